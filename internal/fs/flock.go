@@ -0,0 +1,14 @@
+// +build !js !wasm
+
+package fs
+
+import (
+	"os"
+	"syscall"
+)
+
+// Flock applies or removes an advisory lock on an open file. Locks are
+// released when the file is closed or the program exits.
+func Flock(f *os.File, how int) error {
+	return syscall.Flock(int(f.Fd()), how)
+}
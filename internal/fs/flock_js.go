@@ -0,0 +1,47 @@
+// +build js,wasm
+
+package fs
+
+import (
+	"errors"
+	"os"
+	"syscall"
+	"syscall/js"
+	"time"
+)
+
+var jsFS = js.Global().Get("fs")
+
+// lockRetryInterval is how long a blocking lock waits before trying again.
+// The host only takes locks without blocking so that the event loop is never
+// held up by a lock another process is holding.
+const lockRetryInterval = 50 * time.Millisecond
+
+func flock(fd int, how int) error {
+	c := make(chan error, 1)
+	jsFS.Call("flock", fd, how, js.FuncOf(func(this js.Value, args []js.Value) interface{} {
+		if jsErr := args[0]; jsErr != js.Null() {
+			c <- errors.New(jsErr.Get("code").String())
+			return nil
+		}
+		c <- nil
+		return nil
+	}))
+	err := <-c
+	if err != nil && err.Error() == "EAGAIN" {
+		return syscall.EAGAIN
+	}
+	return err
+}
+
+// Flock applies or removes an advisory lock on an open file. Locks are
+// released when the file is closed or the program exits.
+func Flock(f *os.File, how int) error {
+	for {
+		err := flock(int(f.Fd()), how&^LockNonBlock)
+		if err != syscall.EAGAIN || how&LockNonBlock != 0 {
+			return err
+		}
+		time.Sleep(lockRetryInterval)
+	}
+}
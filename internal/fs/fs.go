@@ -0,0 +1,25 @@
+package fs
+
+// Flock operations, these match the values used by linux
+const (
+	LockShared    = 1
+	LockExclusive = 2
+	LockNonBlock  = 4
+	LockUnlock    = 8
+)
+
+// WatchOp is the kind of change a WatchEvent reports
+type WatchOp int
+
+// Changes reported by a Watcher
+const (
+	Create WatchOp = 1
+	Modify WatchOp = 2
+	Delete WatchOp = 3
+)
+
+// WatchEvent is a single change to a watched path
+type WatchEvent struct {
+	Op   WatchOp
+	Path string
+}
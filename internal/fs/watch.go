@@ -0,0 +1,21 @@
+// +build !js !wasm
+
+package fs
+
+import "errors"
+
+// Watcher delivers changes to a watched path on its Events channel.
+type Watcher struct {
+	Events chan WatchEvent
+}
+
+// Watch starts watching path for created, modified and deleted files. This
+// method only works with the wasabi runtime
+func Watch(path string) (*Watcher, error) {
+	return nil, errors.New("not implemented")
+}
+
+// Close stops watching and closes the Events channel.
+func (w *Watcher) Close() error {
+	return errors.New("not implemented")
+}
@@ -0,0 +1,96 @@
+// +build js,wasm
+
+package fs
+
+import (
+	"errors"
+	"sync"
+	"syscall/js"
+)
+
+var jsWasabi = js.Global().Get("wasabi")
+
+// Watcher delivers changes to a watched path on its Events channel.
+type Watcher struct {
+	Events chan WatchEvent
+
+	id       int
+	onChange js.Func
+
+	mu     sync.Mutex
+	cond   *sync.Cond
+	queue  []WatchEvent
+	closed bool
+}
+
+// Watch starts watching path for created, modified and deleted files. If
+// path is a directory, changes to the files inside of it are reported.
+func Watch(path string) (*Watcher, error) {
+	w := &Watcher{Events: make(chan WatchEvent)}
+	w.cond = sync.NewCond(&w.mu)
+	// callbacks can't block the event loop, so events are queued here and
+	// handed to the Events channel from another goroutine
+	w.onChange = js.FuncOf(func(this js.Value, args []js.Value) interface{} {
+		w.mu.Lock()
+		w.queue = append(w.queue, WatchEvent{
+			Op:   WatchOp(args[0].Int()),
+			Path: args[1].String(),
+		})
+		w.mu.Unlock()
+		w.cond.Signal()
+		return nil
+	})
+
+	type callResult struct {
+		id  int
+		err error
+	}
+	c := make(chan callResult, 1)
+	jsWasabi.Call("watch", path, w.onChange, js.FuncOf(func(this js.Value, args []js.Value) interface{} {
+		if jsErr := args[0]; jsErr != js.Null() {
+			c <- callResult{err: errors.New(jsErr.Get("code").String())}
+			return nil
+		}
+		c <- callResult{id: args[1].Int()}
+		return nil
+	}))
+	res := <-c
+	if res.err != nil {
+		w.onChange.Release()
+		return nil, res.err
+	}
+	w.id = res.id
+	go w.deliver()
+	return w, nil
+}
+
+func (w *Watcher) deliver() {
+	for {
+		w.mu.Lock()
+		for len(w.queue) == 0 && !w.closed {
+			w.cond.Wait()
+		}
+		if w.closed {
+			w.mu.Unlock()
+			close(w.Events)
+			return
+		}
+		event := w.queue[0]
+		w.queue = w.queue[1:]
+		w.mu.Unlock()
+		w.Events <- event
+	}
+}
+
+// Close stops watching and closes the Events channel.
+func (w *Watcher) Close() error {
+	if res := jsWasabi.Call("unwatch", w.id); res != js.Null() {
+		return errors.New(res.String())
+	}
+	w.onChange.Release()
+	w.mu.Lock()
+	w.closed = true
+	w.mu.Unlock()
+	w.cond.Signal()
+	return nil
+}
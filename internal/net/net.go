@@ -20,13 +20,38 @@ func LookupPort(network, service string) (port int, err error) {
 	return net.LookupPort(network, service)
 }
 
+func LookupTXT(name string) ([]string, error) {
+	return net.LookupTXT(name)
+}
+
+func LookupMX(name string) ([]*net.MX, error) {
+	return net.LookupMX(name)
+}
+
+func LookupSRV(service, proto, name string) (string, []*net.SRV, error) {
+	return net.LookupSRV(service, proto, name)
+}
+
+func LookupCNAME(host string) (string, error) {
+	return net.LookupCNAME(host)
+}
+
+func LookupAddr(addr string) ([]string, error) {
+	return net.LookupAddr(addr)
+}
+
 func Dial(network, addr string) (c net.Conn, err error) {
 	c, err = net.Dial(network, addr)
 	if err != nil {
 		return c, err
 	}
-	if network == "tcp" {
+	switch network {
+	case "tcp":
 		return &TCPConn{tc: c.(*net.TCPConn)}, err
+	case "udp":
+		return &UDPConn{uc: c.(*net.UDPConn)}, err
+	case "unix":
+		return c, err
 	}
 	panic("network not supported")
 }
@@ -146,3 +171,72 @@ func ListenTCP(network string, laddr *net.TCPAddr) (*TCPListener, error) {
 func Listen(network, addr string) (net.Listener, error) {
 	return net.Listen(network, addr)
 }
+
+// UDPConn ...
+type UDPConn struct {
+	uc *net.UDPConn
+}
+
+func (c *UDPConn) Read(b []byte) (int, error) {
+	return c.uc.Read(b)
+}
+func (c *UDPConn) Write(b []byte) (int, error) {
+	return c.uc.Write(b)
+}
+func (c *UDPConn) ReadFrom(b []byte) (int, net.Addr, error) {
+	return c.uc.ReadFrom(b)
+}
+func (c *UDPConn) ReadFromUDP(b []byte) (int, *net.UDPAddr, error) {
+	return c.uc.ReadFromUDP(b)
+}
+func (c *UDPConn) WriteTo(b []byte, addr net.Addr) (int, error) {
+	return c.uc.WriteTo(b, addr)
+}
+func (c *UDPConn) WriteToUDP(b []byte, addr *net.UDPAddr) (int, error) {
+	return c.uc.WriteToUDP(b, addr)
+}
+func (c *UDPConn) Close() error {
+	return c.uc.Close()
+}
+func (c *UDPConn) LocalAddr() net.Addr {
+	return c.uc.LocalAddr()
+}
+func (c *UDPConn) RemoteAddr() net.Addr {
+	return c.uc.RemoteAddr()
+}
+func (c *UDPConn) SetDeadline(t time.Time) error {
+	return c.uc.SetDeadline(t)
+}
+func (c *UDPConn) SetReadDeadline(t time.Time) error {
+	return c.uc.SetReadDeadline(t)
+}
+func (c *UDPConn) SetWriteDeadline(t time.Time) error {
+	return c.uc.SetWriteDeadline(t)
+}
+
+func ListenUDP(network string, laddr *net.UDPAddr) (*UDPConn, error) {
+	c, err := net.ListenUDP(network, laddr)
+	if err != nil {
+		return nil, err
+	}
+	return &UDPConn{uc: c}, nil
+}
+
+func DialUDP(network string, laddr, raddr *net.UDPAddr) (*UDPConn, error) {
+	c, err := net.DialUDP(network, laddr, raddr)
+	if err != nil {
+		return nil, err
+	}
+	return &UDPConn{uc: c}, nil
+}
+
+func ListenPacket(network, addr string) (net.PacketConn, error) {
+	c, err := net.ListenPacket(network, addr)
+	if err != nil {
+		return nil, err
+	}
+	if uc, ok := c.(*net.UDPConn); ok {
+		return &UDPConn{uc: uc}, nil
+	}
+	return c, nil
+}
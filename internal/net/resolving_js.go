@@ -3,8 +3,10 @@
 package net
 
 import (
-	"errors"
+	"encoding/binary"
 	"net"
+	"strconv"
+	"strings"
 	"syscall/js"
 
 	"github.com/maxmcd/wasabi/internal/wasm"
@@ -12,23 +14,36 @@ import (
 
 var jsWasabi = js.Global().Get("wasabi")
 
-func lookupIP(host string) (int32, bool)
+// lookupError turns the error passed to a lookup callback into a DNSError
+func lookupError(host string, jsErr js.Value) error {
+	err := &net.DNSError{Name: host}
+	switch jsErr.Get("code").String() {
+	case "ENOENT":
+		err.Err = "no such host"
+	case "ETIMEDOUT":
+		err.Err = "i/o timeout"
+		err.IsTimeout = true
+	default:
+		err.Err = "server misbehaving"
+		err.IsTemporary = true
+	}
+	return err
+}
 
-// LookupIP looks up host using the local resolver. It returns a slice of
-// that host's IPv4 addresses.
-func LookupIP(host string) (addrs []net.IP, err error) {
+// lookup calls fn on the host's resolver with args and waits for the list of
+// byte slices it answers with. The lookup runs asynchronously on the host,
+// only the calling goroutine waits for it.
+func lookup(host, fn string, args ...interface{}) ([][]byte, error) {
 	type callResult struct {
 		val js.Value
 		err error
 	}
 	c := make(chan callResult, 1)
-	args := []interface{}{js.ValueOf(host)}
-	jsWasabi.Call("lookup_ip", append(args, js.FuncOf(func(this js.Value, args []js.Value) interface{} {
+	jsWasabi.Call(fn, append(args, js.FuncOf(func(this js.Value, args []js.Value) interface{} {
 		var res callResult
 
 		if jsErr := args[0]; jsErr != js.Null() {
-			bytes, _ := wasm.GetBytes(int32(jsErr.Int()))
-			res.err = errors.New(string(bytes))
+			res.err = lookupError(host, jsErr)
 		}
 
 		res.val = js.Undefined()
@@ -42,25 +57,164 @@ func LookupIP(host string) (addrs []net.IP, err error) {
 	res := <-c
 
 	if res.err != nil {
-		err = res.err
-		return
+		return nil, res.err
 	}
 	refs, err := wasm.GetArrayOfRefs(int32(res.val.Int()))
 	if err != nil {
-		return
+		return nil, err
 	}
-	addrs = make([]net.IP, len(refs))
+	out := make([][]byte, len(refs))
 	for i, ref := range refs {
-		val, err := wasm.GetBytes(ref)
-		if err != nil {
+		if out[i], err = wasm.GetBytes(ref); err != nil {
 			return nil, err
 		}
-		addrs[i] = val
 	}
-	return
+	return out, nil
 }
 
+// lookupRecords looks up records of kind, which is one of txt, mx, srv,
+// cname or ptr
+func lookupRecords(kind, host string) ([][]byte, error) {
+	return lookup(host, "lookup", js.ValueOf(kind), js.ValueOf(host))
+}
+
+// LookupIP looks up host using the host's resolver. It returns a slice of
+// that host's IPv4 and IPv6 addresses.
+func LookupIP(host string) (addrs []net.IP, err error) {
+	records, err := lookup(host, "lookup_ip", js.ValueOf(host))
+	if err != nil {
+		return nil, err
+	}
+	addrs = make([]net.IP, len(records))
+	for i, record := range records {
+		addrs[i] = record
+	}
+	return addrs, nil
+}
+
+// LookupTXT returns the DNS TXT records for the given domain name
+func LookupTXT(name string) ([]string, error) {
+	records, err := lookupRecords("txt", name)
+	if err != nil {
+		return nil, err
+	}
+	txts := make([]string, len(records))
+	for i, record := range records {
+		txts[i] = string(record)
+	}
+	return txts, nil
+}
+
+// LookupMX returns the DNS MX records for the given domain name sorted by
+// preference
+func LookupMX(name string) ([]*net.MX, error) {
+	records, err := lookupRecords("mx", name)
+	if err != nil {
+		return nil, err
+	}
+	mxs := make([]*net.MX, len(records))
+	for i, record := range records {
+		mxs[i] = &net.MX{
+			Pref: binary.BigEndian.Uint16(record[0:2]),
+			Host: string(record[2:]),
+		}
+	}
+	return mxs, nil
+}
+
+// LookupSRV tries to resolve an SRV query of the given service, protocol and
+// domain name. Like net.LookupSRV, if service and proto are empty name is
+// looked up directly. The records are sorted by priority and then by
+// descending weight.
+func LookupSRV(service, proto, name string) (string, []*net.SRV, error) {
+	target := name
+	if service != "" || proto != "" {
+		target = "_" + service + "._" + proto + "." + name
+	}
+	records, err := lookupRecords("srv", target)
+	if err != nil {
+		return "", nil, err
+	}
+	srvs := make([]*net.SRV, len(records))
+	for i, record := range records {
+		srvs[i] = &net.SRV{
+			Priority: binary.BigEndian.Uint16(record[0:2]),
+			Weight:   binary.BigEndian.Uint16(record[2:4]),
+			Port:     binary.BigEndian.Uint16(record[4:6]),
+			Target:   string(record[6:]),
+		}
+	}
+	return dnsName(target), srvs, nil
+}
+
+// LookupCNAME returns the canonical name for the given host. Unlike
+// net.LookupCNAME it fails when host has no CNAME record.
+func LookupCNAME(host string) (string, error) {
+	records, err := lookupRecords("cname", host)
+	if err != nil {
+		return "", err
+	}
+	if len(records) == 0 {
+		return "", &net.DNSError{Err: "no such host", Name: host}
+	}
+	return string(records[0]), nil
+}
+
+// LookupAddr performs a reverse lookup for the given address, returning a
+// list of names mapping to that address
+func LookupAddr(addr string) ([]string, error) {
+	if net.ParseIP(addr) == nil {
+		return nil, &net.DNSError{Err: "unrecognized address", Name: addr}
+	}
+	records, err := lookupRecords("ptr", addr)
+	if err != nil {
+		return nil, err
+	}
+	names := make([]string, len(records))
+	for i, record := range records {
+		names[i] = string(record)
+	}
+	return names, nil
+}
+
+// dnsName returns name with a trailing dot
+func dnsName(name string) string {
+	if strings.HasSuffix(name, ".") {
+		return name
+	}
+	return name + "."
+}
+
+// services that LookupPort knows by name, as there's no /etc/services to
+// read them from
+var services = map[string]int{
+	"domain":     53,
+	"ftp":        21,
+	"http":       80,
+	"https":      443,
+	"imap":       143,
+	"imaps":      993,
+	"ntp":        123,
+	"pop3":       110,
+	"smtp":       25,
+	"ssh":        22,
+	"submission": 587,
+}
+
+// LookupPort returns the port of service, which is either a port number or
+// the name of a well known service like "http"
 func LookupPort(network, service string) (port int, err error) {
-	panic("unimplemented")
-	return 0, nil
+	if service == "" {
+		return 0, nil
+	}
+	if port, err := strconv.Atoi(service); err == nil {
+		if port < 0 || port > 0xffff {
+			return 0, &net.AddrError{Err: "invalid port", Addr: service}
+		}
+		return port, nil
+	}
+	if port, ok := services[strings.ToLower(service)]; ok {
+		return port, nil
+	}
+	return 0, &net.AddrError{Err: "unknown port", Addr: network + "/" + service}
 }
@@ -1,6 +0,0 @@
-#include "textflag.h"
-
-TEXT ·lookupIP(SB), NOSPLIT, $0
-    CallImport
-    RET
-
@@ -3,7 +3,6 @@ package net
 import (
 	"bufio"
 	"compress/gzip"
-	"crypto/tls"
 	"errors"
 	"io"
 	"net"
@@ -20,7 +19,13 @@ type RoundTripper struct {
 
 func (t *RoundTripper) dial(req *http.Request) (net.Conn, error) {
 	targetAddr := canonicalAddr(req.URL)
-	c, err := Dial("tcp", targetAddr)
+	dial := Dial
+	if req.URL.Scheme == "https" {
+		// the host does the handshake and verifies the certificate, which
+		// is much faster than crypto/tls in wasm
+		dial = DialTLS
+	}
+	c, err := dial("tcp", targetAddr)
 	if err != nil {
 		return c, err
 	}
@@ -35,18 +40,6 @@ func (t *RoundTripper) dial(req *http.Request) (net.Conn, error) {
 		}
 	}
 
-	if req.URL.Scheme == "https" {
-		c = tls.Client(c, &tls.Config{ServerName: req.URL.Host})
-
-		if err = c.(*tls.Conn).Handshake(); err != nil {
-			return nil, err
-		}
-
-		if err = c.(*tls.Conn).VerifyHostname(req.URL.Host); err != nil {
-			return nil, err
-		}
-	}
-
 	return c, nil
 }
 
@@ -48,6 +48,32 @@ func (e *eventState) getError() error {
 	return errors.New("wasabi: Network error")
 }
 
+// timeoutError is returned when a deadline or a host timeout passes, like
+// Go's internal/poll.TimeoutError
+type timeoutError struct{}
+
+func (e timeoutError) Error() string   { return "i/o timeout" }
+func (e timeoutError) Timeout() bool   { return true }
+func (e timeoutError) Temporary() bool { return true }
+
+// netError turns timeouts, reset connections, such as one closed by a peer
+// that set SetLinger(0), and running out of sockets into the *net.OpError
+// that Go code checks for. Other errors are returned as is.
+func netError(op string, err error) error {
+	msg := err.Error()
+	if strings.Contains(msg, "timed out") {
+		return &net.OpError{Op: op, Net: "tcp", Err: timeoutError{}}
+	}
+	if strings.Contains(msg, "Connection reset by peer") {
+		return &net.OpError{Op: op, Net: "tcp", Err: os.NewSyscallError(op, syscall.ECONNRESET)}
+	}
+	if strings.Contains(msg, "Too many open files") {
+		// temporary, so http.Server backs off instead of giving up
+		return &net.OpError{Op: op, Net: "tcp", Err: os.NewSyscallError(op, syscall.EMFILE)}
+	}
+	return err
+}
+
 func (e *eventState) dead() bool {
 	return e.error() || e.hup()
 }
@@ -58,10 +84,10 @@ func (e *eventState) wait() {
 	e.cond.Wait()
 }
 
+// writewait waits for a writable event. The host also sends one when a
+// throttled write may be retried, so a stale writable state is used up
+// rather than trusted.
 func (e *eventState) writewait() error {
-	if e.writeable() {
-		return nil
-	}
 	e.cond.L.Lock()
 	defer e.cond.L.Unlock()
 	for {
@@ -111,6 +137,53 @@ func newEventState(token int32) *eventState {
 
 var connections map[int32]*eventState
 
+// addrLen is the size of the address encoding used by localAddr and
+// remoteAddr: a family byte (4 or 6), 16 bytes of ip of which ipv4 uses the
+// first four, and a little endian port
+const addrLen = 19
+
+func bytesToTCPAddr(b []byte) *net.TCPAddr {
+	var ip net.IP
+	if b[0] == 6 {
+		ip = make(net.IP, net.IPv6len)
+		copy(ip, b[1:17])
+	} else {
+		ip = net.IPv4(b[1], b[2], b[3], b[4])
+	}
+	return &net.TCPAddr{
+		IP:   ip,
+		Port: int(uint16(b[17]) | uint16(b[18])<<8),
+		Zone: "",
+	}
+}
+
+// resolveHost returns the addresses for host, ipv4 addresses first. IP
+// literals are returned as is.
+func resolveHost(host string) ([]net.IP, error) {
+	if ip := net.ParseIP(host); ip != nil {
+		return []net.IP{ip}, nil
+	}
+	addrs, err := LookupIP(host)
+	if err != nil {
+		return nil, err
+	}
+	if len(addrs) == 0 {
+		return nil, errors.New("no such host")
+	}
+	sorted := make([]net.IP, 0, len(addrs))
+	for _, ip := range addrs {
+		if ip.To4() != nil {
+			sorted = append(sorted, ip)
+		}
+	}
+	for _, ip := range addrs {
+		if ip.To4() == nil {
+			sorted = append(sorted, ip)
+		}
+	}
+	return sorted, nil
+}
+
 func init() {
 	connections = make(map[int32]*eventState)
 	callback := js.FuncOf(func(this js.Value, args []js.Value) interface{} {
@@ -150,8 +223,8 @@ func (l TCPListener) accept() (*TCPConn, error) {
 		}
 		bytes, _ := wasm.GetBytes(token)
 		err = errors.New(string(bytes))
-		if !strings.Contains(err.Error(), "Resource temporarily unavailable (os error") {
-			return nil, err
+		if !wouldBlock(err) {
+			return nil, netError("accept", err)
 		}
 		if err = connections[l.token].readwait(); err != nil {
 			return nil, err
@@ -182,13 +255,9 @@ func (l TCPListener) Close() error {
 }
 
 func (l TCPListener) Addr() net.Addr {
-	b := make([]byte, 6)
+	b := make([]byte, addrLen)
 	localAddr(l.token, b)
-	return &net.TCPAddr{
-		IP:   net.IPv4(b[0], b[1], b[2], b[3]),
-		Port: int(uint16(b[4]) | uint16(b[5])<<8),
-		Zone: "",
-	}
+	return bytesToTCPAddr(b)
 }
 
 func (l TCPListener) SetDeadline(t time.Time) error {
@@ -197,14 +266,15 @@ func (l TCPListener) SetDeadline(t time.Time) error {
 }
 
 type TCPConn struct {
-	mu        sync.Mutex
-	token     int32
-	es        *eventState
-	rDeadline time.Time
-	wDeadline time.Time
+	token int32
+	es    *eventState
+	// the host takes the keepalive period when keepalive is turned on, so
+	// both are kept here
+	keepAlive       bool
+	keepAlivePeriod time.Duration
 }
 
-func readConn(id int32, b []byte) (int32, bool)
+func readConn(id int32, b []byte) (int64, bool)
 
 func (c *TCPConn) Read(b []byte) (ln int, err error) {
 	if len(b) == 0 {
@@ -212,7 +282,7 @@ func (c *TCPConn) Read(b []byte) (ln int, err error) {
 	}
 	for {
 		if c.es.error() {
-			return 0, c.es.getError()
+			return 0, netError("read", c.es.getError())
 		}
 		length, ok := readConn(c.token, b)
 		if ok {
@@ -224,7 +294,7 @@ func (c *TCPConn) Read(b []byte) (ln int, err error) {
 		if c.es.hup() {
 			return 0, io.EOF
 		}
-		bytes, _ := wasm.GetBytes(length) // ln is ref if there's an error
+		bytes, _ := wasm.GetBytes(int32(length)) // ln is ref if there's an error
 		err = errors.New(string(bytes))
 
 		// "Network object not found in slab" error then the connection existed
@@ -232,7 +302,7 @@ func (c *TCPConn) Read(b []byte) (ln int, err error) {
 		if strings.Contains(err.Error(), "Network object not found in slab") {
 			return 0, io.EOF
 		} else if !strings.Contains(err.Error(), "Resource temporarily unavailable (os error") {
-			return 0, err
+			return 0, netError("read", err)
 		} else {
 			if c.es.readable() {
 				// remove readable
@@ -240,13 +310,8 @@ func (c *TCPConn) Read(b []byte) (ln int, err error) {
 			}
 		}
 
-		if !c.rDeadline.IsZero() {
-			d := time.Until(c.rDeadline)
-			if d <= 0 {
-				return 0, syscall.EAGAIN
-			}
-			time.AfterFunc(d, c.es.cond.Broadcast)
-		}
+		// deadlines are tracked by the host, which wakes us when one passes
+		// so that readConn returns its timeout
 		if c.es.readable() {
 			continue
 		}
@@ -255,7 +320,7 @@ func (c *TCPConn) Read(b []byte) (ln int, err error) {
 	return
 }
 
-func writeConn(id int32, b []byte) (int32, bool)
+func writeConn(id int32, b []byte) (int64, bool)
 
 func (c *TCPConn) Write(b []byte) (ln int, err error) {
 	for {
@@ -263,15 +328,15 @@ func (c *TCPConn) Write(b []byte) (ln int, err error) {
 		if ok {
 			return int(length), nil
 		}
-		bytes, _ := wasm.GetBytes(length) // ln is ref if there's an error
+		bytes, _ := wasm.GetBytes(int32(length)) // ln is ref if there's an error
 		err = errors.New(string(bytes))
 		if strings.Contains(err.Error(), "Network object not found in slab") {
 			return 0, syscall.EPIPE
-		} else if err.Error() != "Resource temporarily unavailable (os error 35)" {
-			return 0, err
+		} else if !wouldBlock(err) {
+			return 0, netError("write", err)
 		}
 		if err := connections[c.token].writewait(); err != nil {
-			return 0, err
+			return 0, netError("write", err)
 		}
 	}
 }
@@ -290,36 +355,46 @@ func (c *TCPConn) Close() error {
 func localAddr(id int32, b []byte)
 
 func (c *TCPConn) LocalAddr() net.Addr {
-	b := make([]byte, 6)
+	b := make([]byte, addrLen)
 	localAddr(c.token, b)
-	return &net.TCPAddr{
-		IP:   net.IPv4(b[0], b[1], b[2], b[3]),
-		Port: int(uint16(b[4]) | uint16(b[5])<<8),
-		Zone: "",
-	}
+	return bytesToTCPAddr(b)
 }
 
 func remoteAddr(id int32, b []byte)
 
 func (c *TCPConn) RemoteAddr() net.Addr {
-	b := make([]byte, 6)
+	b := make([]byte, addrLen)
 	remoteAddr(c.token, b)
-	return &net.TCPAddr{
-		IP:   net.IPv4(b[0], b[1], b[2], b[3]),
-		Port: int(uint16(b[4]) | uint16(b[5])<<8),
-		Zone: "",
+	return bytesToTCPAddr(b)
+}
+func setDeadline(id int32, kind int32, nanos int64) (int32, bool)
+
+// setDeadline passes t to the host as unix nanoseconds, a zero t clears the
+// deadline. Once it passes the host wakes reads and writes, and fails those
+// in the deadline's direction with a timeout until it's moved.
+func (c *TCPConn) setDeadline(kind int32, t time.Time) error {
+	var nanos int64
+	if !t.IsZero() {
+		nanos = t.UnixNano()
 	}
+	ref, ok := setDeadline(c.token, kind, nanos)
+	if !ok {
+		return refError(ref)
+	}
+	return nil
 }
+
 func (c *TCPConn) SetDeadline(t time.Time) error {
-	return nil
+	if err := c.SetReadDeadline(t); err != nil {
+		return err
+	}
+	return c.SetWriteDeadline(t)
 }
 func (c *TCPConn) SetReadDeadline(t time.Time) error {
-	c.rDeadline = t
-	c.es.cond.Broadcast()
-	return nil
+	return c.setDeadline(1, t)
 }
 func (c *TCPConn) SetWriteDeadline(t time.Time) error {
-	return nil
+	return c.setDeadline(2, t)
 }
 
 func shutdownConn(id int32, how int32) (int32, bool)
@@ -343,33 +418,142 @@ func (c *TCPConn) CloseWrite() error {
 func (c *TCPConn) File() (f *os.File, err error) {
 	return nil, nil
 }
+func sendFile(id int32, fd int32, offset int64, length int64) (int64, bool)
+
+// sendFileChunk is the most one sendFile call moves, so that sending a large
+// file doesn't hold up the host's event loop
+const sendFileChunk = 4 << 20
+
+// writerOnly hides the ReadFrom of a connection from io.Copy
+type writerOnly struct {
+	io.Writer
+}
+
+// ReadFrom copies r to the connection. When r is a file, or an
+// io.LimitedReader of one as io.CopyN and http.ServeContent use for ranges,
+// the host sends it with sendfile from the file's offset, without the bytes
+// passing through the program's memory. Other readers are copied.
 func (c *TCPConn) ReadFrom(r io.Reader) (int64, error) {
-	return 0, nil
+	remain := int64(-1)
+	lr, limited := r.(*io.LimitedReader)
+	src := r
+	if limited {
+		remain, src = lr.N, lr.R
+		if remain <= 0 {
+			return 0, nil
+		}
+	}
+	f, ok := src.(*os.File)
+	if !ok {
+		return io.Copy(writerOnly{c}, r)
+	}
+	offset, err := f.Seek(0, io.SeekCurrent)
+	if err != nil {
+		return io.Copy(writerOnly{c}, r)
+	}
+	var written int64
+	for remain != 0 {
+		n := int64(sendFileChunk)
+		if remain > 0 && remain < n {
+			n = remain
+		}
+		sent, ok := sendFile(c.token, int32(f.Fd()), offset+written, n)
+		if ok {
+			if sent == 0 {
+				// the end of the file
+				break
+			}
+			written += sent
+			if remain > 0 {
+				remain -= sent
+			}
+			continue
+		}
+		err = refError(int32(sent))
+		if strings.Contains(err.Error(), "Network object not found in slab") {
+			err = syscall.EPIPE
+			break
+		} else if !wouldBlock(err) {
+			err = netError("write", err)
+			break
+		}
+		if err = c.es.writewait(); err != nil {
+			err = netError("write", err)
+			break
+		}
+	}
+	// what was sent counts as read, like it would with a copy
+	if _, serr := f.Seek(offset+written, io.SeekStart); err == nil {
+		err = serr
+	}
+	if limited {
+		lr.N -= written
+	}
+	return written, err
+}
+
+// option codes understood by setSockOpt
+const (
+	optNoDelay = iota + 1
+	optKeepAlive
+	optLinger
+	optReadBuffer
+	optWriteBuffer
+)
+
+// defaultKeepAlivePeriod matches the period Go's dialer uses
+const defaultKeepAlivePeriod = 15 * time.Second
+
+func setSockOpt(id int32, opt int32, value int64) (int32, bool)
+
+func (c *TCPConn) setSockOpt(opt int32, value int64) error {
+	ref, ok := setSockOpt(c.token, opt, value)
+	if ok {
+		return nil
+	}
+	return refError(ref)
 }
 
 func (c *TCPConn) SetKeepAlive(keepalive bool) error {
-	return nil
+	c.keepAlive = keepalive
+	if !keepalive {
+		return c.setSockOpt(optKeepAlive, 0)
+	}
+	period := c.keepAlivePeriod
+	if period == 0 {
+		period = defaultKeepAlivePeriod
+	}
+	return c.setSockOpt(optKeepAlive, int64((period+time.Second-1)/time.Second))
 }
 
-// SetKeepAlivePeriod sets period between keep alives.
+// SetKeepAlivePeriod sets period between keep alives. It's rounded up to a
+// whole second and takes effect once keepalive is turned on.
 func (c *TCPConn) SetKeepAlivePeriod(d time.Duration) error {
-	return nil
+	c.keepAlivePeriod = d
+	if !c.keepAlive {
+		return nil
+	}
+	return c.SetKeepAlive(true)
 }
 
 func (c *TCPConn) SetLinger(sec int) error {
-	return nil
+	return c.setSockOpt(optLinger, int64(sec))
 }
 
 func (c *TCPConn) SetNoDelay(noDelay bool) error {
-	return nil
+	var v int64
+	if noDelay {
+		v = 1
+	}
+	return c.setSockOpt(optNoDelay, v)
 }
 
 func (c *TCPConn) SetReadBuffer(bytes int) error {
-	return nil
+	return c.setSockOpt(optReadBuffer, int64(bytes))
 }
 
 func (c *TCPConn) SetWriteBuffer(bytes int) error {
-	return nil
+	return c.setSockOpt(optWriteBuffer, int64(bytes))
 }
 func (c *TCPConn) SyscallConn() (syscall.RawConn, error) {
 	return nil, nil
@@ -378,7 +562,57 @@ func (c *TCPConn) SyscallConn() (syscall.RawConn, error) {
 func listenTCP(addr string) (int32, bool)
 
 func ListenTCP(network string, laddr *net.TCPAddr) (*TCPListener, error) {
-	id, ok := listenTCP(laddr.String())
+	return listenStream(laddr, listenTCP)
+}
+
+func listenTls(addr string) (int32, bool)
+
+// ListenTLS listens on addr for connections that the host does the TLS
+// handshake for, with the certificate and key wasabi was started with.
+// Accepted connections read and write plaintext.
+func ListenTLS(network, addr string) (net.Listener, error) {
+	if network != "tcp" {
+		return nil, errors.New("tcp is the only protocol supported for tls")
+	}
+	laddr, err := resolveListenAddr(addr)
+	if err != nil {
+		return nil, err
+	}
+	l, err := listenStream(laddr, listenTls)
+	if err != nil {
+		return nil, err
+	}
+	return l, nil
+}
+
+func proxyProtocol(id int32) (int32, bool)
+
+// ListenProxy listens on addr for connections from a load balancer or proxy
+// that starts each one with a PROXY protocol v1 or v2 header. The host reads
+// the header before Accept returns the connection, and RemoteAddr is the
+// client it names. Connections with a malformed header, or none, are closed
+// without being returned.
+func ListenProxy(network, addr string) (net.Listener, error) {
+	if network != "tcp" {
+		return nil, errors.New("tcp is the only protocol supported for the proxy protocol")
+	}
+	laddr, err := resolveListenAddr(addr)
+	if err != nil {
+		return nil, err
+	}
+	l, err := listenStream(laddr, listenTCP)
+	if err != nil {
+		return nil, err
+	}
+	if ref, ok := proxyProtocol(l.token); !ok {
+		l.Close()
+		return nil, refError(ref)
+	}
+	return l, nil
+}
+
+func listenStream(laddr *net.TCPAddr, listen func(string) (int32, bool)) (*TCPListener, error) {
+	id, ok := listen(laddr.String())
 	if ok {
 		es := newEventState(id)
 		connections[id] = es
@@ -390,11 +624,36 @@ func ListenTCP(network string, laddr *net.TCPAddr) (*TCPListener, error) {
 }
 
 func Listen(network, addr string) (net.Listener, error) {
+	if network == "unix" {
+		l, err := ListenUnix(network, &net.UnixAddr{Name: addr, Net: network})
+		if err != nil {
+			return nil, err
+		}
+		return l, nil
+	}
+	if network == "virtual" {
+		l, err := ListenVirtual(addr)
+		if err != nil {
+			return nil, err
+		}
+		return l, nil
+	}
+	laddr, err := resolveListenAddr(addr)
+	if err != nil {
+		return nil, err
+	}
+	return ListenTCP(network, laddr)
+}
+
+func resolveListenAddr(addr string) (*net.TCPAddr, error) {
 	host, port, err := net.SplitHostPort(addr)
 	if err != nil {
 		return nil, err
 	}
-	addrs, err := LookupIP(host)
+	if host == "" {
+		host = "0.0.0.0"
+	}
+	addrs, err := resolveHost(host)
 	if err != nil {
 		return nil, err
 	}
@@ -402,7 +661,7 @@ func Listen(network, addr string) (net.Listener, error) {
 	if err != nil {
 		return nil, err
 	}
-	return ListenTCP(network, &net.TCPAddr{IP: addrs[0], Port: iport})
+	return &net.TCPAddr{IP: addrs[0], Port: iport}, nil
 }
 
 // func DialContext(ctx context.Context, network, addr string) (net.TCPConn, error) {
@@ -414,32 +673,100 @@ func Listen(network, addr string) (net.Listener, error) {
 func dialTcp(addr string) (int32, bool)
 
 func Dial(network, addr string) (c net.Conn, err error) {
+	if network == "udp" {
+		raddr, err := resolveUDPAddr(addr)
+		if err != nil {
+			return c, err
+		}
+		uc, err := DialUDP(network, nil, raddr)
+		if err != nil {
+			return c, err
+		}
+		return uc, nil
+	}
+	if network == "unix" {
+		uc, err := DialUnix(network, nil, &net.UnixAddr{Name: addr, Net: network})
+		if err != nil {
+			return c, err
+		}
+		return uc, nil
+	}
+	if network == "virtual" {
+		vc, err := DialVirtual(addr)
+		if err != nil {
+			return c, err
+		}
+		return vc, nil
+	}
+	if network != "tcp" {
+		return c, errors.New("tcp, udp, unix and virtual are the only protocols supported")
+	}
+
+	return dialStream(addr, dialTcp)
+}
+
+func dialTls(addr string, serverName string) (int32, bool)
+
+// DialTLS connects to addr with TLS done by the host, which verifies the
+// server's certificate for the host name in addr. The returned connection
+// reads and writes plaintext.
+func DialTLS(network, addr string) (net.Conn, error) {
 	if network != "tcp" {
-		return c, errors.New("tcp is the only protocal supported")
+		return nil, errors.New("tcp is the only protocol supported for tls")
+	}
+	host, _, err := net.SplitHostPort(addr)
+	if err != nil {
+		return nil, err
 	}
+	return dialStream(addr, func(ip string) (int32, bool) {
+		return dialTls(ip, host)
+	})
+}
 
+// dialStream resolves addr and connects to it with dial
+func dialStream(addr string, dial func(string) (int32, bool)) (c net.Conn, err error) {
 	host, port, err := net.SplitHostPort(addr)
 	if err != nil {
 		return
 	}
-	addrs, err := LookupIP(host)
+	addrs, err := resolveHost(host)
 	if err != nil {
 		return
 	}
 	// this is very simplified. see func (d *Dialer) DialContext in Go src
-	ref, ok := dialTcp(addrs[0].String() + ":" + port)
-	if ok {
-		es := newEventState(ref)
-		connections[ref] = es
-		if err := es.writewait(); err != nil {
-			return c, err
+	ref, err := throttledDial(func() (int32, bool) {
+		return dial(net.JoinHostPort(addrs[0].String(), port))
+	})
+	if err != nil {
+		return c, netError("dial", err)
+	}
+	es := newEventState(ref)
+	connections[ref] = es
+	if err := es.writewait(); err != nil {
+		closeConn(ref)
+		return c, netError("dial", err)
+	}
+	c = &TCPConn{token: ref, es: es}
+	return c, nil
+}
+
+func dialWait() int64
+
+// throttledDial calls dial until the host admits it. Dials over the
+// connection rate limit are refused with EAGAIN, the host then tells how
+// long to wait until another one is allowed.
+func throttledDial(dial func() (int32, bool)) (int32, error) {
+	for {
+		ref, ok := dial()
+		if ok {
+			return ref, nil
 		}
-		var c net.Conn
-		c = &TCPConn{token: ref, es: es}
-		return c, nil
+		err := refError(ref)
+		if !wouldBlock(err) {
+			return ref, err
+		}
+		time.Sleep(time.Duration(dialWait()))
 	}
-	bytes, _ := wasm.GetBytes(ref)
-	return c, errors.New(string(bytes))
 }
 
 type tcpKeepAliveListener struct {
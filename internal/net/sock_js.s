@@ -28,6 +28,10 @@ TEXT ·writeConn(SB), NOSPLIT, $0
     CallImport
     RET
 
+TEXT ·sendFile(SB), NOSPLIT, $0
+    CallImport
+    RET
+
 TEXT ·closeConn(SB), NOSPLIT, $0
     CallImport
     RET
@@ -43,3 +47,27 @@ TEXT ·remoteAddr(SB), NOSPLIT, $0
 TEXT ·dialTcp(SB), NOSPLIT, $0
     CallImport
     RET
+
+TEXT ·dialTls(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·dialWait(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·listenTls(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·proxyProtocol(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·setSockOpt(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·setDeadline(SB), NOSPLIT, $0
+    CallImport
+    RET
@@ -0,0 +1,271 @@
+// +build js,wasm
+
+package net
+
+import (
+	"errors"
+	"net"
+	"strconv"
+	"strings"
+	"time"
+
+	"github.com/maxmcd/wasabi/internal/wasm"
+)
+
+func bytesToUDPAddr(b []byte) *net.UDPAddr {
+	a := bytesToTCPAddr(b)
+	return &net.UDPAddr{IP: a.IP, Port: a.Port}
+}
+
+func udpAddrToBytes(a *net.UDPAddr) []byte {
+	b := make([]byte, addrLen)
+	if ip4 := a.IP.To4(); ip4 != nil {
+		b[0] = 4
+		copy(b[1:5], ip4)
+	} else {
+		b[0] = 6
+		copy(b[1:17], a.IP.To16())
+	}
+	b[17] = byte(a.Port)
+	b[18] = byte(a.Port >> 8)
+	return b
+}
+
+func refError(ref int32) error {
+	bytes, _ := wasm.GetBytes(ref)
+	return errors.New(string(bytes))
+}
+
+func wouldBlock(err error) bool {
+	return strings.Contains(err.Error(), "Resource temporarily unavailable (os error")
+}
+
+// UDPConn is a udp socket. Sockets returned by DialUDP are connected and
+// can use Read and Write, sockets from ListenUDP use ReadFrom and WriteTo.
+type UDPConn struct {
+	token     int32
+	es        *eventState
+	raddr     *net.UDPAddr
+	rDeadline time.Time
+}
+
+func listenUDP(addr string) (int32, bool)
+
+func dialUDP(addr string) (int32, bool)
+
+func newUDPConn(id int32) *UDPConn {
+	es := newEventState(id)
+	connections[id] = es
+	return &UDPConn{token: id, es: es}
+}
+
+func ListenUDP(network string, laddr *net.UDPAddr) (*UDPConn, error) {
+	ip, port := net.IPv4zero, 0
+	if laddr != nil {
+		if laddr.IP != nil {
+			ip = laddr.IP
+		}
+		port = laddr.Port
+	}
+	id, ok := listenUDP(net.JoinHostPort(ip.String(), strconv.Itoa(port)))
+	if !ok {
+		return nil, refError(id) // id is ref if there's an error
+	}
+	return newUDPConn(id), nil
+}
+
+// DialUDP connects to raddr from an ephemeral local port. Picking the local
+// address isn't supported, laddr must be nil.
+func DialUDP(network string, laddr, raddr *net.UDPAddr) (*UDPConn, error) {
+	if laddr != nil {
+		return nil, errors.New("wasabi: DialUDP doesn't support a local address")
+	}
+	id, ok := dialUDP(net.JoinHostPort(raddr.IP.String(), strconv.Itoa(raddr.Port)))
+	if !ok {
+		return nil, refError(id)
+	}
+	c := newUDPConn(id)
+	c.raddr = raddr
+	return c, nil
+}
+
+func resolveUDPAddr(addr string) (*net.UDPAddr, error) {
+	host, port, err := net.SplitHostPort(addr)
+	if err != nil {
+		return nil, err
+	}
+	if host == "" {
+		host = "0.0.0.0"
+	}
+	addrs, err := resolveHost(host)
+	if err != nil {
+		return nil, err
+	}
+	iport, err := LookupPort("udp", port)
+	if err != nil {
+		return nil, err
+	}
+	return &net.UDPAddr{IP: addrs[0], Port: iport}, nil
+}
+
+// ListenPacket listens for udp packets on addr
+func ListenPacket(network, addr string) (net.PacketConn, error) {
+	if network != "udp" && network != "udp4" && network != "udp6" {
+		return nil, errors.New("udp is the only packet network supported")
+	}
+	laddr, err := resolveUDPAddr(addr)
+	if err != nil {
+		return nil, err
+	}
+	uc, err := ListenUDP(network, laddr)
+	if err != nil {
+		return nil, err
+	}
+	return uc, nil
+}
+
+// wait blocks until the socket is ready or the read deadline passes
+func (c *UDPConn) wait() error {
+	if c.es.readable() {
+		// remove readable
+		c.es.state = c.es.state ^ (1 << 0)
+		return nil
+	}
+	if !c.rDeadline.IsZero() {
+		d := time.Until(c.rDeadline)
+		if d <= 0 {
+			return &net.OpError{Op: "read", Net: "udp", Err: timeoutError{}}
+		}
+		t := time.AfterFunc(d, c.es.cond.Broadcast)
+		defer t.Stop()
+	}
+	c.es.wait()
+	return nil
+}
+
+func recvFrom(id int32, b []byte, addr []byte) (int64, bool)
+
+func (c *UDPConn) ReadFromUDP(b []byte) (int, *net.UDPAddr, error) {
+	addr := make([]byte, addrLen)
+	for {
+		if c.es.error() {
+			return 0, nil, c.es.getError()
+		}
+		length, ok := recvFrom(c.token, b, addr)
+		if ok {
+			return int(length), bytesToUDPAddr(addr), nil
+		}
+		err := refError(int32(length))
+		if !wouldBlock(err) {
+			return 0, nil, err
+		}
+		if err := c.wait(); err != nil {
+			return 0, nil, err
+		}
+	}
+}
+
+func (c *UDPConn) ReadFrom(b []byte) (int, net.Addr, error) {
+	n, addr, err := c.ReadFromUDP(b)
+	if addr == nil {
+		return n, nil, err
+	}
+	return n, addr, err
+}
+
+func (c *UDPConn) Read(b []byte) (int, error) {
+	for {
+		if c.es.error() {
+			return 0, c.es.getError()
+		}
+		length, ok := readConn(c.token, b)
+		if ok {
+			return int(length), nil
+		}
+		err := refError(int32(length))
+		if !wouldBlock(err) {
+			return 0, err
+		}
+		if err := c.wait(); err != nil {
+			return 0, err
+		}
+	}
+}
+
+func sendTo(id int32, b []byte, addr []byte) (int64, bool)
+
+func (c *UDPConn) WriteToUDP(b []byte, addr *net.UDPAddr) (int, error) {
+	for {
+		length, ok := sendTo(c.token, b, udpAddrToBytes(addr))
+		if ok {
+			return int(length), nil
+		}
+		err := refError(int32(length))
+		if !wouldBlock(err) {
+			return 0, err
+		}
+		if err := c.es.writewait(); err != nil {
+			return 0, err
+		}
+	}
+}
+
+func (c *UDPConn) WriteTo(b []byte, addr net.Addr) (int, error) {
+	a, ok := addr.(*net.UDPAddr)
+	if !ok {
+		return 0, errors.New("wasabi: WriteTo needs a *net.UDPAddr")
+	}
+	return c.WriteToUDP(b, a)
+}
+
+func (c *UDPConn) Write(b []byte) (int, error) {
+	for {
+		length, ok := writeConn(c.token, b)
+		if ok {
+			return int(length), nil
+		}
+		err := refError(int32(length))
+		if !wouldBlock(err) {
+			return 0, err
+		}
+		if err := c.es.writewait(); err != nil {
+			return 0, err
+		}
+	}
+}
+
+func (c *UDPConn) Close() error {
+	ref, ok := closeConn(c.token)
+	if ok {
+		return nil
+	}
+	return refError(ref)
+}
+
+func (c *UDPConn) LocalAddr() net.Addr {
+	b := make([]byte, addrLen)
+	localAddr(c.token, b)
+	return bytesToUDPAddr(b)
+}
+
+// RemoteAddr returns the address a dialed socket is connected to, or nil
+func (c *UDPConn) RemoteAddr() net.Addr {
+	if c.raddr == nil {
+		return nil
+	}
+	b := make([]byte, addrLen)
+	remoteAddr(c.token, b)
+	return bytesToUDPAddr(b)
+}
+
+func (c *UDPConn) SetDeadline(t time.Time) error {
+	return c.SetReadDeadline(t)
+}
+func (c *UDPConn) SetReadDeadline(t time.Time) error {
+	c.rDeadline = t
+	c.es.cond.Broadcast()
+	return nil
+}
+func (c *UDPConn) SetWriteDeadline(t time.Time) error {
+	return nil
+}
@@ -0,0 +1,17 @@
+#include "textflag.h"
+
+TEXT ·listenUDP(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·dialUDP(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·sendTo(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·recvFrom(SB), NOSPLIT, $0
+    CallImport
+    RET
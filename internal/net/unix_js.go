@@ -0,0 +1,145 @@
+// +build js,wasm
+
+package net
+
+import (
+	"errors"
+	"net"
+	"time"
+)
+
+// Unix socket paths are resolved by the host inside the sandbox, in the
+// same way as filesystem paths.
+
+// UnixListener listens on a unix socket
+type UnixListener struct {
+	token int32
+	es    *eventState
+	addr  *net.UnixAddr
+}
+
+func listenUnix(path string) (int32, bool)
+
+func ListenUnix(network string, laddr *net.UnixAddr) (*UnixListener, error) {
+	if network != "unix" {
+		return nil, errors.New("unix is the only unix network supported")
+	}
+	id, ok := listenUnix(laddr.Name)
+	if !ok {
+		return nil, refError(id) // id is ref if there's an error
+	}
+	es := newEventState(id)
+	connections[id] = es
+	return &UnixListener{token: id, es: es, addr: laddr}, nil
+}
+
+func acceptUnix(id int32) (int32, bool)
+
+func (l *UnixListener) AcceptUnix() (*UnixConn, error) {
+	for {
+		token, ok := acceptUnix(l.token)
+		if ok {
+			es := newEventState(token)
+			connections[token] = es
+			return &UnixConn{
+				c:     &TCPConn{token: token, es: es},
+				laddr: l.addr,
+				raddr: &net.UnixAddr{Net: "unix"},
+			}, nil
+		}
+		err := refError(token)
+		if !wouldBlock(err) {
+			return nil, netError("accept", err)
+		}
+		if err := l.es.readwait(); err != nil {
+			return nil, err
+		}
+	}
+}
+
+func (l *UnixListener) Accept() (net.Conn, error) {
+	c, err := l.AcceptUnix()
+	if err != nil {
+		return nil, err
+	}
+	return c, nil
+}
+
+// Close stops listening and removes the socket file
+func (l *UnixListener) Close() error {
+	ref, ok := closeListener(l.token)
+	if ok {
+		return nil
+	}
+	return refError(ref)
+}
+
+func (l *UnixListener) Addr() net.Addr {
+	return l.addr
+}
+
+func dialUnix(path string) (int32, bool)
+
+func DialUnix(network string, laddr, raddr *net.UnixAddr) (*UnixConn, error) {
+	if network != "unix" {
+		return nil, errors.New("unix is the only unix network supported")
+	}
+	if laddr != nil {
+		return nil, errors.New("wasabi: DialUnix doesn't support a local address")
+	}
+	id, err := throttledDial(func() (int32, bool) {
+		return dialUnix(raddr.Name)
+	})
+	if err != nil {
+		return nil, err
+	}
+	es := newEventState(id)
+	connections[id] = es
+	if err := es.writewait(); err != nil {
+		return nil, err
+	}
+	return &UnixConn{
+		c:     &TCPConn{token: id, es: es},
+		laddr: &net.UnixAddr{Net: "unix"},
+		raddr: raddr,
+	}, nil
+}
+
+// UnixConn is a stream connection over a unix socket. Reads and writes go
+// through the same host calls as tcp connections.
+type UnixConn struct {
+	c     *TCPConn
+	laddr *net.UnixAddr
+	raddr *net.UnixAddr
+}
+
+func (c *UnixConn) Read(b []byte) (int, error) {
+	return c.c.Read(b)
+}
+func (c *UnixConn) Write(b []byte) (int, error) {
+	return c.c.Write(b)
+}
+func (c *UnixConn) Close() error {
+	return c.c.Close()
+}
+func (c *UnixConn) CloseRead() error {
+	return c.c.CloseRead()
+}
+func (c *UnixConn) CloseWrite() error {
+	return c.c.CloseWrite()
+}
+func (c *UnixConn) LocalAddr() net.Addr {
+	return c.laddr
+}
+func (c *UnixConn) RemoteAddr() net.Addr {
+	return c.raddr
+}
+func (c *UnixConn) SetDeadline(t time.Time) error {
+	return c.c.SetDeadline(t)
+}
+func (c *UnixConn) SetReadDeadline(t time.Time) error {
+	return c.c.SetReadDeadline(t)
+}
+func (c *UnixConn) SetWriteDeadline(t time.Time) error {
+	return c.c.SetWriteDeadline(t)
+}
@@ -0,0 +1,13 @@
+#include "textflag.h"
+
+TEXT ·listenUnix(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·acceptUnix(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·dialUnix(SB), NOSPLIT, $0
+    CallImport
+    RET
@@ -0,0 +1,150 @@
+// +build js,wasm
+
+package net
+
+import (
+	"errors"
+	"net"
+	"time"
+)
+
+// Virtual endpoints are names in wasabi's in-memory network. Programs that
+// share a network can listen and dial on them without going through the
+// kernel or using any ports.
+
+// VirtualAddr is the name of a virtual endpoint
+type VirtualAddr struct {
+	Name string
+}
+
+func (a *VirtualAddr) Network() string {
+	return "virtual"
+}
+
+func (a *VirtualAddr) String() string {
+	return a.Name
+}
+
+// VirtualListener listens on a virtual endpoint
+type VirtualListener struct {
+	token int32
+	es    *eventState
+	addr  *VirtualAddr
+}
+
+func listenVirtual(name string) (int32, bool)
+
+func ListenVirtual(name string) (*VirtualListener, error) {
+	id, ok := listenVirtual(name)
+	if !ok {
+		return nil, refError(id) // id is ref if there's an error
+	}
+	es := newEventState(id)
+	connections[id] = es
+	return &VirtualListener{token: id, es: es, addr: &VirtualAddr{Name: name}}, nil
+}
+
+func acceptVirtual(id int32) (int32, bool)
+
+func (l *VirtualListener) AcceptVirtual() (*VirtualConn, error) {
+	for {
+		token, ok := acceptVirtual(l.token)
+		if ok {
+			es := newEventState(token)
+			connections[token] = es
+			return &VirtualConn{
+				c:     &TCPConn{token: token, es: es},
+				laddr: l.addr,
+				raddr: &VirtualAddr{},
+			}, nil
+		}
+		err := refError(token)
+		if !wouldBlock(err) {
+			return nil, netError("accept", err)
+		}
+		if err := l.es.readwait(); err != nil {
+			return nil, err
+		}
+	}
+}
+
+func (l *VirtualListener) Accept() (net.Conn, error) {
+	c, err := l.AcceptVirtual()
+	if err != nil {
+		return nil, err
+	}
+	return c, nil
+}
+
+// Close stops listening and frees the name
+func (l *VirtualListener) Close() error {
+	ref, ok := closeListener(l.token)
+	if ok {
+		return nil
+	}
+	return refError(ref)
+}
+
+func (l *VirtualListener) Addr() net.Addr {
+	return l.addr
+}
+
+func dialVirtual(name string) (int32, bool)
+
+func DialVirtual(name string) (*VirtualConn, error) {
+	if name == "" {
+		return nil, errors.New("wasabi: DialVirtual needs a name")
+	}
+	id, err := throttledDial(func() (int32, bool) {
+		return dialVirtual(name)
+	})
+	if err != nil {
+		return nil, err
+	}
+	es := newEventState(id)
+	connections[id] = es
+	return &VirtualConn{
+		c:     &TCPConn{token: id, es: es},
+		laddr: &VirtualAddr{},
+		raddr: &VirtualAddr{Name: name},
+	}, nil
+}
+
+// VirtualConn is a stream connection over a virtual endpoint. Reads and
+// writes go through the same host calls as tcp connections.
+type VirtualConn struct {
+	c     *TCPConn
+	laddr *VirtualAddr
+	raddr *VirtualAddr
+}
+
+func (c *VirtualConn) Read(b []byte) (int, error) {
+	return c.c.Read(b)
+}
+func (c *VirtualConn) Write(b []byte) (int, error) {
+	return c.c.Write(b)
+}
+func (c *VirtualConn) Close() error {
+	return c.c.Close()
+}
+func (c *VirtualConn) CloseRead() error {
+	return c.c.CloseRead()
+}
+func (c *VirtualConn) CloseWrite() error {
+	return c.c.CloseWrite()
+}
+func (c *VirtualConn) LocalAddr() net.Addr {
+	return c.laddr
+}
+func (c *VirtualConn) RemoteAddr() net.Addr {
+	return c.raddr
+}
+func (c *VirtualConn) SetDeadline(t time.Time) error {
+	return c.c.SetDeadline(t)
+}
+func (c *VirtualConn) SetReadDeadline(t time.Time) error {
+	return c.c.SetReadDeadline(t)
+}
+func (c *VirtualConn) SetWriteDeadline(t time.Time) error {
+	return c.c.SetWriteDeadline(t)
+}
@@ -0,0 +1,13 @@
+#include "textflag.h"
+
+TEXT ·listenVirtual(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·acceptVirtual(SB), NOSPLIT, $0
+    CallImport
+    RET
+
+TEXT ·dialVirtual(SB), NOSPLIT, $0
+    CallImport
+    RET
@@ -9,9 +9,45 @@ import (
 	"syscall"
 	"time"
 
+	wfs "github.com/maxmcd/wasabi/internal/fs"
 	wnet "github.com/maxmcd/wasabi/internal/net"
 )
 
+// Flock operations
+const (
+	LockShared    = wfs.LockShared
+	LockExclusive = wfs.LockExclusive
+	LockNonBlock  = wfs.LockNonBlock
+	LockUnlock    = wfs.LockUnlock
+)
+
+// WatchEvent is a single change to a watched path
+type WatchEvent = wfs.WatchEvent
+
+// Watcher delivers changes to a watched path on its Events channel
+type Watcher = wfs.Watcher
+
+// Changes reported by a Watcher
+const (
+	Create = wfs.Create
+	Modify = wfs.Modify
+	Delete = wfs.Delete
+)
+
+// Watch starts watching path for created, modified and deleted files. If
+// path is a directory, changes to the files inside of it are reported. Call
+// Close on the returned Watcher to stop watching.
+func Watch(path string) (*Watcher, error) {
+	return wfs.Watch(path)
+}
+
+// Flock applies or removes an advisory lock on an open file. how is one of
+// LockShared, LockExclusive or LockUnlock, optionally combined with
+// LockNonBlock. Locks are released when the file is closed.
+func Flock(f *os.File, how int) error {
+	return wfs.Flock(f, how)
+}
+
 // TCPConn ...
 type TCPConn struct {
 	tc *wnet.TCPConn
@@ -128,12 +164,57 @@ func Dial(network, addr string) (c net.Conn, err error) {
 	if err != nil {
 		return c, err
 	}
-	if network == "tcp" {
+	switch network {
+	case "tcp":
 		return &TCPConn{tc: c.(*wnet.TCPConn)}, err
+	case "udp", "unix", "virtual":
+		return c, err
 	}
 	panic("network not supported")
 }
 
+// DialTLS connects to addr over TLS. The handshake, certificate
+// verification and encryption are done by wasabi, the connection reads and
+// writes plaintext.
+func DialTLS(network, addr string) (net.Conn, error) {
+	c, err := wnet.DialTLS(network, addr)
+	if err != nil {
+		return nil, err
+	}
+	return &TCPConn{tc: c.(*wnet.TCPConn)}, nil
+}
+
+// ListenTLS listens on addr for TLS connections, using the certificate and
+// key wasabi was started with
+func ListenTLS(network, addr string) (net.Listener, error) {
+	return wnet.ListenTLS(network, addr)
+}
+
+// ListenProxy listens on addr for connections that start with a PROXY
+// protocol header, like those forwarded by a TCP load balancer. RemoteAddr
+// of accepted connections is the client address from the header.
+func ListenProxy(network, addr string) (net.Listener, error) {
+	return wnet.ListenProxy(network, addr)
+}
+
+// UDPConn is a udp socket
+type UDPConn = wnet.UDPConn
+
+// ListenUDP ...
+func ListenUDP(network string, laddr *net.UDPAddr) (*UDPConn, error) {
+	return wnet.ListenUDP(network, laddr)
+}
+
+// DialUDP ...
+func DialUDP(network string, laddr, raddr *net.UDPAddr) (*UDPConn, error) {
+	return wnet.DialUDP(network, laddr, raddr)
+}
+
+// ListenPacket ...
+func ListenPacket(network, addr string) (net.PacketConn, error) {
+	return wnet.ListenPacket(network, addr)
+}
+
 // ListenAndServe ...
 func ListenAndServe(addr string, handler http.Handler) error {
 	return wnet.ListenAndServe(addr, handler)
@@ -149,6 +230,31 @@ func LookupPort(network, service string) (int, error) {
 	return wnet.LookupPort(network, service)
 }
 
+// LookupTXT ...
+func LookupTXT(name string) ([]string, error) {
+	return wnet.LookupTXT(name)
+}
+
+// LookupMX ...
+func LookupMX(name string) ([]*net.MX, error) {
+	return wnet.LookupMX(name)
+}
+
+// LookupSRV ...
+func LookupSRV(service, proto, name string) (string, []*net.SRV, error) {
+	return wnet.LookupSRV(service, proto, name)
+}
+
+// LookupCNAME ...
+func LookupCNAME(host string) (string, error) {
+	return wnet.LookupCNAME(host)
+}
+
+// LookupAddr ...
+func LookupAddr(addr string) ([]string, error) {
+	return wnet.LookupAddr(addr)
+}
+
 //RoundTripper ...
 type RoundTripper struct {
 	ReadTimeout    time.Duration
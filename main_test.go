@@ -139,6 +139,256 @@ func TestLookupIP(t *testing.T) {
 	println("finished TestLookupIP")
 }
 
+func TestLookupPort(t *testing.T) {
+	println("TestLookupPort")
+	for service, want := range map[string]int{"8080": 8080, "http": 80, "HTTPS": 443} {
+		port, err := LookupPort("tcp", service)
+		if err != nil {
+			t.Error(err)
+		}
+		if port != want {
+			t.Errorf("LookupPort(%q) = %d; want %d", service, port, want)
+		}
+	}
+	for _, service := range []string{"nope", "70000"} {
+		if _, err := LookupPort("tcp", service); err == nil {
+			t.Errorf("LookupPort(%q) succeeded", service)
+		}
+	}
+	println("finished TestLookupPort")
+}
+
+func TestIPv6Loopback(t *testing.T) {
+	println("TestIPv6Loopback")
+	l, err := Listen("tcp", "[::1]:0")
+	if err != nil {
+		t.Skip("ipv6 isn't available: ", err)
+	}
+	defer l.Close()
+	if ip := l.Addr().(*net.TCPAddr).IP; !ip.Equal(net.IPv6loopback) {
+		t.Fatalf("got %v; want %v", ip, net.IPv6loopback)
+	}
+	c, err := Dial("tcp", l.Addr().String())
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer c.Close()
+	lc, err := l.Accept()
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer lc.Close()
+	if c.RemoteAddr().String() != l.Addr().String() {
+		t.Errorf("got %v; want %v", c.RemoteAddr(), l.Addr())
+	}
+	if lc.RemoteAddr().String() != c.LocalAddr().String() {
+		t.Errorf("got %v; want %v", lc.RemoteAddr(), c.LocalAddr())
+	}
+	println("finished TestIPv6Loopback")
+}
+
+func TestUDP(t *testing.T) {
+	println("TestUDP")
+	server, err := ListenUDP("udp", &net.UDPAddr{IP: net.IPv4(127, 0, 0, 1)})
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer server.Close()
+	c, err := Dial("udp", server.LocalAddr().String())
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer c.Close()
+	if c.RemoteAddr().String() != server.LocalAddr().String() {
+		t.Errorf("got %v; want %v", c.RemoteAddr(), server.LocalAddr())
+	}
+	if _, err := c.Write([]byte("ping")); err != nil {
+		t.Fatal(err)
+	}
+	b := make([]byte, 16)
+	n, from, err := server.ReadFromUDP(b)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if string(b[:n]) != "ping" {
+		t.Errorf("got %q; want %q", b[:n], "ping")
+	}
+	if from.String() != c.LocalAddr().String() {
+		t.Errorf("got %v; want %v", from, c.LocalAddr())
+	}
+	if _, err := server.WriteToUDP([]byte("pong"), from); err != nil {
+		t.Fatal(err)
+	}
+	n, err = c.Read(b)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if string(b[:n]) != "pong" {
+		t.Errorf("got %q; want %q", b[:n], "pong")
+	}
+	server.SetReadDeadline(time.Now().Add(10 * time.Millisecond))
+	_, _, err = server.ReadFromUDP(b)
+	if nerr, ok := err.(net.Error); !ok || !nerr.Timeout() {
+		t.Errorf("got %v; want a timeout", err)
+	}
+	println("finished TestUDP")
+}
+
+func TestUnixSocket(t *testing.T) {
+	println("TestUnixSocket")
+	// relative so that the socket ends up inside the sandbox under wasabi
+	path := fmt.Sprintf("wasabi-%d.sock", time.Now().UnixNano())
+	l, err := Listen("unix", path)
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer l.Close()
+	accepted := make(chan net.Conn)
+	go func() {
+		c, err := l.Accept()
+		if err != nil {
+			t.Error(err)
+		}
+		accepted <- c
+	}()
+	c, err := Dial("unix", path)
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer c.Close()
+	if _, err := c.Write([]byte("hello")); err != nil {
+		t.Fatal(err)
+	}
+	lc := <-accepted
+	if lc == nil {
+		t.FailNow()
+	}
+	defer lc.Close()
+	b := make([]byte, 16)
+	n, err := lc.Read(b)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if string(b[:n]) != "hello" {
+		t.Errorf("got %q; want %q", b[:n], "hello")
+	}
+	println("finished TestUnixSocket")
+}
+
+func TestVirtualNetwork(t *testing.T) {
+	println("TestVirtualNetwork")
+	if runtime.GOOS != "js" {
+		t.Skip("virtual endpoints are only supported under wasabi")
+	}
+	name := fmt.Sprintf("wasabi-%d", time.Now().UnixNano())
+	l, err := Listen("virtual", name)
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer l.Close()
+	if _, err := Listen("virtual", name); err == nil {
+		t.Error("listened on a name that's in use")
+	}
+	accepted := make(chan net.Conn)
+	go func() {
+		c, err := l.Accept()
+		if err != nil {
+			t.Error(err)
+		}
+		accepted <- c
+	}()
+	c, err := Dial("virtual", name)
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer c.Close()
+	if c.RemoteAddr().String() != name {
+		t.Errorf("got remote address %q; want %q", c.RemoteAddr(), name)
+	}
+	if _, err := c.Write([]byte("hello")); err != nil {
+		t.Fatal(err)
+	}
+	lc := <-accepted
+	if lc == nil {
+		t.FailNow()
+	}
+	b := make([]byte, 16)
+	n, err := lc.Read(b)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if string(b[:n]) != "hello" {
+		t.Errorf("got %q; want %q", b[:n], "hello")
+	}
+	lc.Close()
+	if _, err := c.Read(b); err != io.EOF {
+		t.Errorf("got %v; want EOF", err)
+	}
+	if _, err := Dial("virtual", name+"-missing"); err == nil {
+		t.Error("dialed a name nothing listens on")
+	}
+	println("finished TestVirtualNetwork")
+}
+
+func TestFlock(t *testing.T) {
+	println("TestFlock")
+	f, err := ioutil.TempFile("", "wasabi-flock")
+	if err != nil {
+		t.Fatal(err)
+	}
+	if err := Flock(f, LockExclusive); err != nil {
+		t.Fatal(err)
+	}
+
+	other, err := os.Open(f.Name())
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer other.Close()
+	if err := Flock(other, LockShared|LockNonBlock); err == nil {
+		t.Fatal("lock should be held by the first file")
+	}
+
+	// closing the file releases its lock
+	if err := f.Close(); err != nil {
+		t.Fatal(err)
+	}
+	if err := Flock(other, LockShared|LockNonBlock); err != nil {
+		t.Fatal(err)
+	}
+	println("finished TestFlock")
+}
+
+func TestWatch(t *testing.T) {
+	println("TestWatch")
+	if runtime.GOOS != "js" {
+		t.Skip("Watch is only supported under wasabi")
+	}
+	if err := os.Mkdir("/wasabi-watch", 0755); err != nil && !os.IsExist(err) {
+		t.Fatal(err)
+	}
+	w, err := Watch("/wasabi-watch")
+	if err != nil {
+		t.Fatal(err)
+	}
+	name := fmt.Sprintf("/wasabi-watch/%d", time.Now().UnixNano())
+	if err := os.Mkdir(name, 0755); err != nil {
+		t.Fatal(err)
+	}
+	select {
+	case event := <-w.Events:
+		if event.Op != Create || event.Path != name {
+			t.Errorf("got %v; want a create of %s", event, name)
+		}
+	case <-time.After(someTimeout):
+		t.Error("timed out waiting for a create event")
+	}
+	if err := w.Close(); err != nil {
+		t.Error(err)
+	}
+	println("finished TestWatch")
+}
+
 func TestCloseRead(t *testing.T) {
 	println("TestCloseRead")
 	network := "tcp"
@@ -423,6 +673,96 @@ func TestZeroByteRead(t *testing.T) {
 	println("finished TestZeroByteRead")
 }
 
+func TestReadFromFile(t *testing.T) {
+	println("TestReadFromFile")
+	f, err := ioutil.TempFile("", "wasabi-sendfile")
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer os.Remove(f.Name())
+	defer f.Close()
+	if _, err := f.WriteString("Hello, world!"); err != nil {
+		t.Fatal(err)
+	}
+	withTCPConnPair(t, func(c *TCPConn) error {
+		// a range, like http.ServeContent sends
+		if _, err := f.Seek(7, io.SeekStart); err != nil {
+			return err
+		}
+		n, err := io.CopyN(c, f, 5)
+		if err != nil {
+			return err
+		}
+		if n != 5 {
+			return fmt.Errorf("sent %d bytes; want 5", n)
+		}
+		offset, err := f.Seek(0, io.SeekCurrent)
+		if err != nil {
+			return err
+		}
+		if offset != 12 {
+			return fmt.Errorf("file offset is %d; want 12", offset)
+		}
+		return nil
+	}, func(c *TCPConn) error {
+		b, err := ioutil.ReadAll(c)
+		if err != nil {
+			return err
+		}
+		if string(b) != "world" {
+			return fmt.Errorf("got %q; want %q", b, "world")
+		}
+		return nil
+	})
+	println("finished TestReadFromFile")
+}
+
+func TestListenProxy(t *testing.T) {
+	println("TestListenProxy")
+	ln, err := ListenProxy("tcp", "127.0.0.1:0")
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer ln.Close()
+	malformed, err := Dial("tcp", ln.Addr().String())
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer malformed.Close()
+	if _, err := malformed.Write([]byte("GET / HTTP/1.1\r\n\r\n")); err != nil {
+		t.Fatal(err)
+	}
+	c, err := Dial("tcp", ln.Addr().String())
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer c.Close()
+	if _, err := c.Write([]byte("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello")); err != nil {
+		t.Fatal(err)
+	}
+
+	accepted, err := ln.Accept()
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer accepted.Close()
+	if addr := accepted.RemoteAddr().String(); addr != "192.0.2.1:56324" {
+		t.Errorf("remote address is %s; want 192.0.2.1:56324", addr)
+	}
+	b := make([]byte, 5)
+	if _, err := io.ReadFull(accepted, b); err != nil {
+		t.Fatal(err)
+	}
+	if string(b) != "hello" {
+		t.Errorf("got %q; want %q", b, "hello")
+	}
+	// closed by the host instead of being accepted
+	if _, err := malformed.Read(b); err == nil {
+		t.Error("read from a connection with a malformed header succeeded")
+	}
+	println("finished TestListenProxy")
+}
+
 // withTCPConnPair sets up a TCP connection between two peers, then
 // runs peer1 and peer2 concurrently. withTCPConnPair returns when
 // both have completed.
@@ -0,0 +1,106 @@
+//! Compares instances that each have a reactor of their own with instances
+//! sharing one reactor per core. Every instance runs on its own thread, like
+//! wasabi runs programs, and bounces a message between two of its own TCP
+//! sockets.
+//!
+//!     cargo bench --bench reactors -- <instances> <round trips>
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use wasabi_io::{event_to_ints, IOLoop, Reactors, Response};
+
+const MESSAGE: &[u8] = &[7; 64];
+
+fn ping_pong(mut nl: IOLoop, round_trips: usize) {
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = nl.tcp_listen(&addr).unwrap();
+    let addr = nl.local_addr(listener).unwrap();
+    let client = nl.tcp_connect(&addr).unwrap();
+    let mut server = None;
+    let mut sent = false;
+    let mut received = 0;
+    let mut done = 0;
+    let mut buf = [0u8; 1024];
+    while done < round_trips {
+        if let Response::Event(event) = nl.recv().unwrap() {
+            let (token, _) = event_to_ints(&event);
+            if token as usize == listener && server.is_none() {
+                server = nl.tcp_accept(listener).ok();
+            }
+        }
+        if !sent {
+            // fails until the client is connected
+            sent = nl.write_stream(client, MESSAGE).is_ok();
+        }
+        if let Some(server) = server {
+            while let Ok(n) = nl.read_stream(server, &mut buf) {
+                if n == 0 {
+                    break;
+                }
+                nl.write_stream(server, &buf[..n]).unwrap();
+            }
+        }
+        while let Ok(n) = nl.read_stream(client, &mut buf) {
+            if n == 0 {
+                break;
+            }
+            received += n;
+            if received == MESSAGE.len() {
+                received = 0;
+                done += 1;
+                sent = done < round_trips && nl.write_stream(client, MESSAGE).is_ok();
+            }
+        }
+    }
+}
+
+fn run(instances: usize, round_trips: usize, reactors: Option<Arc<Reactors>>) -> Duration {
+    let start = Instant::now();
+    let threads: Vec<_> = (0..instances)
+        .map(|_| {
+            let reactors = reactors.clone();
+            thread::spawn(move || {
+                let nl = match reactors {
+                    Some(reactors) => IOLoop::with_reactors(&reactors),
+                    None => IOLoop::new(),
+                };
+                ping_pong(nl, round_trips)
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, total: usize, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    println!(
+        "{:<24} {:>10.0} round trips/s ({:?})",
+        name,
+        total as f64 / secs,
+        elapsed
+    );
+}
+
+fn main() {
+    let mut args = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .map(|arg| arg.parse::<usize>().unwrap());
+    let instances = args.next().unwrap_or(64);
+    let round_trips = args.next().unwrap_or(2000);
+    let total = instances * round_trips;
+
+    let elapsed = run(instances, round_trips, None);
+    report("reactor per instance", total, elapsed);
+
+    let reactors = Arc::new(Reactors::per_core().unwrap());
+    let name = format!("{} shared reactors", reactors.len());
+    let elapsed = run(instances, round_trips, Some(reactors));
+    report(&name, total, elapsed);
+}
@@ -0,0 +1,274 @@
+//! Filesystem audit log
+//!
+//! When enabled, every filesystem operation requested through the IOLoop is
+//! recorded as a single JSON object per line. Operations are recorded when
+//! their result comes back so that the outcome and byte counts are known.
+//! Network operations that are denied by the instance's Policy are recorded
+//! as well.
+
+use crate::Response;
+use failure::{err_msg, Error};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An operation that has been requested but hasn't completed yet
+#[derive(Debug)]
+struct Entry {
+    op: &'static str,
+    guest_path: String,
+    host_path: PathBuf,
+    mode: Option<i64>,
+    fd: Option<usize>,
+}
+
+pub struct AuditLog {
+    sink: Box<dyn Write + Send>,
+    pending: HashMap<i64, Entry>,
+    // guest and host paths of open files so that fd based operations can be
+    // attributed to a path
+    files: HashMap<usize, (String, PathBuf)>,
+    // why the sink couldn't be written to. Nothing more is logged after
+    // that, as the log would have a hole in it.
+    failed: Option<String>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("pending", &self.pending)
+            .field("files", &self.files)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl AuditLog {
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+        Self {
+            sink,
+            pending: HashMap::new(),
+            files: HashMap::new(),
+            failed: None,
+        }
+    }
+    /// Errs once the sink couldn't be written to
+    pub fn check(&self) -> Result<(), Error> {
+        match &self.failed {
+            Some(err) => Err(err_msg(format!("failed to write audit log: {}", err))),
+            None => Ok(()),
+        }
+    }
+    /// record a path based operation that will complete with callback id
+    pub fn path_op(
+        &mut self,
+        id: i64,
+        op: &'static str,
+        guest_path: &str,
+        host_path: PathBuf,
+        mode: Option<i64>,
+    ) {
+        self.pending.insert(
+            id,
+            Entry {
+                op,
+                guest_path: guest_path.to_string(),
+                host_path,
+                mode,
+                fd: None,
+            },
+        );
+    }
+    /// record an operation on an already open file descriptor
+    pub fn fd_op(&mut self, id: i64, op: &'static str, fd: usize) {
+        let entry = self.fd_entry(op, fd);
+        self.pending.insert(id, entry);
+    }
+    /// record an operation on an open file descriptor that completed without
+    /// a callback
+    pub fn fd_done(&mut self, op: &'static str, fd: usize, result: Result<usize, String>) {
+        let entry = self.fd_entry(op, fd);
+        match result {
+            Ok(bytes) => self.write_line(&entry, "ok", Some(bytes), None),
+            Err(error) => self.write_line(&entry, "error", None, Some(&error)),
+        }
+    }
+    fn fd_entry(&self, op: &'static str, fd: usize) -> Entry {
+        let (guest_path, host_path) = self
+            .files
+            .get(&fd)
+            .cloned()
+            .unwrap_or_else(|| (String::new(), PathBuf::new()));
+        Entry {
+            op,
+            guest_path,
+            host_path,
+            mode: None,
+            fd: Some(fd),
+        }
+    }
+    pub fn close(&mut self, fd: usize) {
+        if let Some((guest_path, host_path)) = self.files.remove(&fd) {
+            let entry = Entry {
+                op: "close",
+                guest_path,
+                host_path,
+                mode: None,
+                fd: Some(fd),
+            };
+            self.write_line(&entry, "ok", None, None);
+        }
+    }
+    /// finish a pending operation with the response it produced
+    pub fn complete(&mut self, resp: &Response) {
+        let id = match resp.id() {
+            Some(id) => id,
+            None => return,
+        };
+        let mut entry = match self.pending.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        match resp {
+            Response::Error { msg, .. } => self.write_line(&entry, "error", None, Some(msg)),
+            Response::FileRef { fd, .. } => {
+                self.files
+                    .insert(*fd, (entry.guest_path.clone(), entry.host_path.clone()));
+                entry.fd = Some(*fd);
+                self.write_line(&entry, "ok", None, None)
+            }
+            Response::Written { len, .. } | Response::Read { len, .. } => {
+                self.write_line(&entry, "ok", Some(*len), None)
+            }
+            _ => self.write_line(&entry, "ok", None, None),
+        }
+    }
+    /// record a network operation that was denied by policy
+    pub fn denied(&mut self, op: &'static str, target: &str, error: &str) {
+        let line = format!(
+            "{{\"ts\":{},\"op\":\"{}\",\"target\":{},\"result\":\"denied\",\"error\":{}}}\n",
+            timestamp(),
+            op,
+            json_string(target),
+            json_string(error),
+        );
+        self.write(&line);
+    }
+    fn write_line(
+        &mut self,
+        entry: &Entry,
+        result: &str,
+        bytes: Option<usize>,
+        error: Option<&str>,
+    ) {
+        let mut line = format!(
+            "{{\"ts\":{},\"op\":\"{}\",\"guest_path\":{},\"host_path\":{}",
+            timestamp(),
+            entry.op,
+            json_string(&entry.guest_path),
+            json_string(&entry.host_path.to_string_lossy()),
+        );
+        if let Some(mode) = entry.mode {
+            line.push_str(&format!(",\"mode\":{}", mode));
+        }
+        if let Some(fd) = entry.fd {
+            line.push_str(&format!(",\"fd\":{}", fd));
+        }
+        line.push_str(&format!(",\"result\":\"{}\"", result));
+        if let Some(bytes) = bytes {
+            line.push_str(&format!(",\"bytes\":{}", bytes));
+        }
+        if let Some(error) = error {
+            line.push_str(&format!(",\"error\":{}", json_string(error)));
+        }
+        line.push_str("}\n");
+        self.write(&line);
+    }
+    fn write(&mut self, line: &str) {
+        // an audit sink that can't be written to shouldn't take the guest
+        // down with it, the embedder finds out through check
+        if self.failed.is_some() {
+            return;
+        }
+        if let Err(err) = self.sink.write_all(line.as_bytes()) {
+            self.failed = Some(err.to_string());
+        }
+    }
+}
+
+fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    // fails its first write and keeps the ones after it
+    struct Flaky {
+        failed: bool,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stops_once_the_sink_fails() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let mut log = AuditLog::new(Box::new(Flaky {
+            failed: false,
+            written: written.clone(),
+        }));
+        assert!(log.check().is_ok());
+        log.write("{}\n");
+        let err = log.check().unwrap_err();
+        assert!(err.to_string().starts_with("failed to write audit log"));
+        // later lines would leave a hole in the log
+        log.write("{}\n");
+        assert!(written.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+    }
+}
@@ -0,0 +1,205 @@
+//! Socket deadlines
+//!
+//! Deadlines are tracked on the host so that a peer that never answers
+//! can't pin a guest's sockets. Connect and idle timeouts are set per
+//! instance and fail the socket for good when they expire. Read and write
+//! deadlines are set by the guest and, like Go's, only fail reads and writes
+//! until they are moved. Each has at most one pending timer, which is only
+//! replaced when the deadline is moved earlier.
+
+use std::cell::Cell;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Per instance timeouts for tcp streams. None never times out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// how long a dial may take to connect
+    pub connect: Option<Duration>,
+    /// how long a connection may go without reading or writing any bytes
+    pub idle: Option<Duration>,
+}
+
+/// The deadlines a guest can set on a socket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Deadline {
+    Read,
+    Write,
+}
+
+/// The timers the IOLoop schedules for a socket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timer {
+    Connect,
+    Idle,
+    /// the PROXY protocol header of a held stream
+    Header,
+    Deadline(Deadline),
+}
+
+#[derive(Debug)]
+pub struct Deadlines {
+    pub connect: Option<Instant>,
+    pub read: Option<Instant>,
+    pub write: Option<Instant>,
+    // when the pending timers of the read and write deadlines fire
+    read_timer: Option<Instant>,
+    write_timer: Option<Instant>,
+    idle: Option<Duration>,
+    // reads and writes only have a shared reference to the IOLoop
+    last_active: Cell<Instant>,
+    // the reason the socket was failed by a connect or idle timeout
+    failed: Option<&'static str>,
+}
+
+impl Deadlines {
+    pub fn new(idle: Option<Duration>) -> Self {
+        Self {
+            connect: None,
+            read: None,
+            write: None,
+            read_timer: None,
+            write_timer: None,
+            idle,
+            last_active: Cell::new(Instant::now()),
+            failed: None,
+        }
+    }
+    /// record that bytes were read or written
+    pub fn touch(&self) {
+        self.last_active.set(Instant::now());
+    }
+    pub fn idle_deadline(&self) -> Option<Instant> {
+        self.idle.map(|idle| self.last_active.get() + idle)
+    }
+    /// the deadline of kind, if it has passed
+    pub fn check(&self, kind: Deadline) -> Result<(), io::Error> {
+        let (deadline, msg) = match kind {
+            Deadline::Read => (self.read, "read timed out"),
+            Deadline::Write => (self.write, "write timed out"),
+        };
+        match deadline {
+            Some(at) if at <= Instant::now() => Err(timed_out(msg)),
+            _ => Ok(()),
+        }
+    }
+    /// moves the deadline of kind and returns when to schedule a timer for
+    /// it, which is only needed when it's earlier than the pending one
+    pub fn set(&mut self, kind: Deadline, at: Option<Instant>) -> Option<Instant> {
+        let (deadline, timer) = self.slots(kind);
+        *deadline = at;
+        let at = at?;
+        if timer.map_or(true, |pending| at < pending) {
+            *timer = Some(at);
+            Some(at)
+        } else {
+            None
+        }
+    }
+    /// called when a timer of kind fires. Returns whether the deadline has
+    /// passed, and when to schedule the next timer if it was moved later.
+    pub fn fire(&mut self, kind: Deadline) -> (bool, Option<Instant>) {
+        let now = Instant::now();
+        let (deadline, timer) = self.slots(kind);
+        // a pending timer that's still ahead isn't the one that fired
+        if timer.map_or(false, |pending| pending <= now) {
+            *timer = None;
+        }
+        match *deadline {
+            Some(at) if at <= now => (true, None),
+            Some(at) if timer.is_none() => {
+                *timer = Some(at);
+                (false, Some(at))
+            }
+            _ => (false, None),
+        }
+    }
+    fn slots(&mut self, kind: Deadline) -> (&mut Option<Instant>, &mut Option<Instant>) {
+        match kind {
+            Deadline::Read => (&mut self.read, &mut self.read_timer),
+            Deadline::Write => (&mut self.write, &mut self.write_timer),
+        }
+    }
+    /// fails the socket if its connect or idle timeout passed and returns
+    /// whether it did
+    pub fn expire(&mut self) -> bool {
+        let now = Instant::now();
+        if self.failed.is_some() {
+            return false;
+        }
+        if self.connect.map_or(false, |at| at <= now) {
+            self.failed = Some("connect timed out");
+        } else if self.idle_deadline().map_or(false, |at| at <= now) {
+            self.failed = Some("connection timed out while idle");
+        }
+        self.failed.is_some()
+    }
+    pub fn failed(&self) -> bool {
+        self.failed.is_some()
+    }
+    /// the error the guest gets for a socket that a connect or idle timeout
+    /// failed. Passed read and write deadlines are only returned by check.
+    pub fn error(&self) -> Option<io::Error> {
+        self.failed.map(timed_out)
+    }
+}
+
+fn timed_out(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn expires_deadlines() {
+        let mut deadlines = Deadlines::new(Some(Duration::from_secs(60)));
+        assert!(!deadlines.expire());
+        assert!(deadlines.error().is_none());
+
+        deadlines.read = Some(Instant::now());
+        let err = deadlines.check(Deadline::Read).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(deadlines.check(Deadline::Write).is_ok());
+        // only fails reads, not the socket
+        assert!(deadlines.error().is_none());
+        // moving the deadline clears the error
+        deadlines.read = Some(Instant::now() + Duration::from_secs(60));
+        assert!(deadlines.check(Deadline::Read).is_ok());
+
+        deadlines.connect = Some(Instant::now());
+        assert!(deadlines.expire());
+        assert!(deadlines.failed());
+        assert_eq!(deadlines.error().unwrap().to_string(), "connect timed out");
+        // a socket only fails once
+        assert!(!deadlines.expire());
+    }
+
+    #[test]
+    fn keeps_one_timer_per_deadline() {
+        let mut deadlines = Deadlines::new(None);
+        let soon = Instant::now() + Duration::from_millis(10);
+        assert_eq!(deadlines.set(Deadline::Read, Some(soon)), Some(soon));
+        // later deadlines wait for the pending timer
+        let later = soon + Duration::from_secs(60);
+        assert_eq!(deadlines.set(Deadline::Read, Some(later)), None);
+        assert_eq!(deadlines.set(Deadline::Read, Some(later)), None);
+        // the write deadline has a timer of its own
+        assert_eq!(deadlines.set(Deadline::Write, Some(later)), Some(later));
+        // earlier ones replace it
+        let sooner = soon - Duration::from_millis(5);
+        assert_eq!(deadlines.set(Deadline::Read, Some(sooner)), Some(sooner));
+
+        thread::sleep(Duration::from_millis(20));
+        // the deadline moved, so the timer is scheduled again for it
+        assert_eq!(deadlines.fire(Deadline::Read), (false, Some(later)));
+        // the replaced timer fires too but isn't the pending one
+        assert_eq!(deadlines.fire(Deadline::Read), (false, None));
+
+        deadlines.set(Deadline::Read, Some(Instant::now()));
+        assert_eq!(deadlines.fire(Deadline::Read), (true, None));
+        assert!(deadlines.check(Deadline::Write).is_ok());
+    }
+}
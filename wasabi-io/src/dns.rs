@@ -0,0 +1,541 @@
+//! Per instance DNS
+//!
+//! A Resolver answers guest lookups from a static hosts map, then from its
+//! cache, and finally from the configured upstream servers through
+//! trust-dns. With DNS disabled only the static hosts map is used.
+//!
+//! Besides addresses, TXT, MX, SRV, CNAME and PTR records can be looked up.
+//! Those aren't cached, and only PTR lookups consult the static hosts map.
+
+use failure::{err_msg, Error};
+use futures::{future, Future};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::proto::rr::{RData, RecordType};
+use trust_dns_resolver::system_conf::read_system_conf;
+use trust_dns_resolver::{AsyncResolver, Name};
+
+pub type LookupFuture = Box<dyn Future<Item = Vec<IpAddr>, Error = io::Error> + Send>;
+pub type RecordsFuture = Box<dyn Future<Item = Records, Error = io::Error> + Send>;
+
+/// The record types that can be looked up besides addresses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    Txt,
+    Mx,
+    Srv,
+    Cname,
+    Ptr,
+}
+
+impl FromStr for RecordKind {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s {
+            "txt" => RecordKind::Txt,
+            "mx" => RecordKind::Mx,
+            "srv" => RecordKind::Srv,
+            "cname" => RecordKind::Cname,
+            "ptr" => RecordKind::Ptr,
+            _ => return Err(err_msg(format!("unknown record type {}", s))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MxRecord {
+    pub host: String,
+    pub pref: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrvRecord {
+    pub target: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+/// The answer to a RecordKind lookup. TXT records are returned as names,
+/// with the strings of each record joined together.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Records {
+    Names(Vec<String>),
+    Mx(Vec<MxRecord>),
+    Srv(Vec<SrvRecord>),
+}
+
+// lookups cached at once, expired ones are dropped to make room
+const MAX_CACHED: usize = 1024;
+
+#[derive(Debug, Clone, Default)]
+pub struct DnsConfig {
+    upstreams: Vec<SocketAddr>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    search: Vec<String>,
+    cache_ttl: Option<Duration>,
+    negative_ttl: Option<Duration>,
+    disabled: bool,
+}
+
+impl DnsConfig {
+    /// add an upstream server as `ip` or `ip:port`, the port defaults to 53.
+    /// Without any upstreams the host's resolver configuration is used.
+    pub fn add_upstream(&mut self, server: &str) -> Result<(), Error> {
+        let addr = match server.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => SocketAddr::new(server.trim_matches(|c| c == '[' || c == ']').parse()?, 53),
+        };
+        self.upstreams.push(addr);
+        Ok(())
+    }
+    /// add a static entry in the form `name=ip[,ip]`
+    pub fn add_host(&mut self, entry: &str) -> Result<(), Error> {
+        let mut parts = entry.splitn(2, '=');
+        let name = normalize(parts.next().unwrap_or(""));
+        let ips = parts
+            .next()
+            .ok_or_else(|| err_msg(format!("expected name=ip, got {}", entry)))?;
+        if name.is_empty() {
+            return Err(err_msg(format!("expected name=ip, got {}", entry)));
+        }
+        for ip in ips.split(',') {
+            let ip = ip.parse()?;
+            self.hosts
+                .entry(name.clone())
+                .or_insert_with(Vec::new)
+                .push(ip);
+        }
+        Ok(())
+    }
+    /// add a domain that is appended to names that aren't fully qualified
+    pub fn add_search(&mut self, domain: &str) -> Result<(), Error> {
+        Name::from_ascii(domain).map_err(|e| err_msg(e.to_string()))?;
+        self.search.push(domain.to_string());
+        Ok(())
+    }
+    /// cache successful lookups for ttl
+    pub fn set_cache_ttl(&mut self, ttl: Duration) {
+        self.cache_ttl = Some(ttl);
+    }
+    /// cache failed lookups for ttl
+    pub fn set_negative_ttl(&mut self, ttl: Duration) {
+        self.negative_ttl = Some(ttl);
+    }
+    /// answer only from the static hosts map
+    pub fn disable(&mut self) {
+        self.disabled = true;
+    }
+    fn resolver_config(&self) -> Result<(ResolverConfig, ResolverOpts), Error> {
+        let (mut config, opts) = if self.upstreams.is_empty() {
+            // a host without a resolver configuration has no servers to ask,
+            // rather than sending lookups to a public resolver
+            read_system_conf().unwrap_or_else(|_| (ResolverConfig::new(), ResolverOpts::default()))
+        } else {
+            (ResolverConfig::new(), ResolverOpts::default())
+        };
+        for addr in &self.upstreams {
+            for protocol in &[Protocol::Udp, Protocol::Tcp] {
+                config.add_name_server(NameServerConfig {
+                    socket_addr: *addr,
+                    protocol: *protocol,
+                    tls_dns_name: None,
+                });
+            }
+        }
+        for domain in &self.search {
+            config.add_search(Name::from_ascii(domain).map_err(|e| err_msg(e.to_string()))?);
+        }
+        Ok((config, opts))
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    expires: Instant,
+    // failures keep their message
+    result: Result<Vec<IpAddr>, String>,
+}
+
+// clones share the cache and the upstream connections
+#[derive(Clone)]
+pub struct Resolver {
+    config: DnsConfig,
+    resolver: AsyncResolver,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Resolver")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl Resolver {
+    /// returns the resolver and a future that drives its connections, which
+    /// has to be spawned
+    pub fn new(
+        config: DnsConfig,
+    ) -> Result<(Self, impl Future<Item = (), Error = ()> + Send), Error> {
+        let (resolver_config, opts) = config.resolver_config()?;
+        let (resolver, background) = AsyncResolver::new(resolver_config, opts);
+        Ok((
+            Self {
+                config,
+                resolver,
+                cache: Arc::new(Mutex::new(HashMap::new())),
+            },
+            background,
+        ))
+    }
+    pub fn lookup_ip(&self, host: &str) -> LookupFuture {
+        let host = normalize(host);
+        if let Some(ips) = self.config.hosts.get(&host) {
+            return Box::new(future::ok(ips.clone()));
+        }
+        if let Some(entry) = self.cache.lock().unwrap().get(&host) {
+            if entry.expires > Instant::now() {
+                return Box::new(future::result(entry.result.clone().map_err(not_found)));
+            }
+        }
+        if self.config.disabled {
+            return Box::new(future::err(not_found(format!(
+                "dns is disabled, {} isn't a static host",
+                host
+            ))));
+        }
+        let cache = self.cache.clone();
+        let cache_ttl = self.config.cache_ttl;
+        let negative_ttl = self.config.negative_ttl;
+        Box::new(self.resolver.lookup_ip(host.as_str()).then(move |result| {
+            let result = result
+                .map(|ips| ips.iter().collect::<Vec<IpAddr>>())
+                .map_err(|err| err.to_string());
+            let ttl = if result.is_ok() {
+                cache_ttl
+            } else {
+                negative_ttl
+            };
+            if let Some(ttl) = ttl {
+                let entry = CacheEntry {
+                    expires: Instant::now() + ttl,
+                    result: result.clone(),
+                };
+                cache_insert(&mut cache.lock().unwrap(), host, entry);
+            }
+            result.map_err(not_found)
+        }))
+    }
+    /// look up records of kind for host. For PTR records host is an ip.
+    /// MX records are sorted by preference and SRV records by priority and
+    /// then by descending weight.
+    pub fn lookup(&self, host: &str, kind: RecordKind) -> RecordsFuture {
+        if kind == RecordKind::Ptr {
+            return self.lookup_ptr(host);
+        }
+        let host = normalize(host);
+        if self.config.disabled {
+            return Box::new(future::err(not_found(format!(
+                "dns is disabled, can't look up {}",
+                host
+            ))));
+        }
+        let host = host.as_str();
+        match kind {
+            RecordKind::Txt => Box::new(self.resolver.txt_lookup(host).map_err(resolve_error).map(
+                |txt| {
+                    Records::Names(
+                        txt.iter()
+                            .map(|r| {
+                                r.txt_data()
+                                    .iter()
+                                    .map(|s| String::from_utf8_lossy(s))
+                                    .collect()
+                            })
+                            .collect(),
+                    )
+                },
+            )),
+            RecordKind::Mx => Box::new(self.resolver.mx_lookup(host).map_err(resolve_error).map(
+                |mx| {
+                    let mut records: Vec<MxRecord> = mx
+                        .iter()
+                        .map(|r| MxRecord {
+                            host: r.exchange().to_string(),
+                            pref: r.preference(),
+                        })
+                        .collect();
+                    records.sort_by_key(|r| r.pref);
+                    Records::Mx(records)
+                },
+            )),
+            RecordKind::Srv => Box::new(self.resolver.srv_lookup(host).map_err(resolve_error).map(
+                |srv| {
+                    let mut records: Vec<SrvRecord> = srv
+                        .iter()
+                        .map(|r| SrvRecord {
+                            target: r.target().to_string(),
+                            port: r.port(),
+                            priority: r.priority(),
+                            weight: r.weight(),
+                        })
+                        .collect();
+                    records.sort_by_key(|r| (r.priority, Reverse(r.weight)));
+                    Records::Srv(records)
+                },
+            )),
+            RecordKind::Cname => Box::new(
+                self.resolver
+                    .lookup(host, RecordType::CNAME)
+                    .map_err(resolve_error)
+                    .map(|lookup| {
+                        Records::Names(
+                            lookup
+                                .iter()
+                                .filter_map(|rdata| match rdata {
+                                    RData::CNAME(name) => Some(name.to_string()),
+                                    _ => None,
+                                })
+                                .collect(),
+                        )
+                    }),
+            ),
+            RecordKind::Ptr => unreachable!(),
+        }
+    }
+    fn lookup_ptr(&self, addr: &str) -> RecordsFuture {
+        let ip: IpAddr = match addr.parse() {
+            Ok(ip) => ip,
+            Err(_) => {
+                return Box::new(future::err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} isn't an ip address", addr),
+                )))
+            }
+        };
+        let mut names: Vec<String> = self
+            .config
+            .hosts
+            .iter()
+            .filter(|(_, ips)| ips.contains(&ip))
+            .map(|(name, _)| format!("{}.", name))
+            .collect();
+        if !names.is_empty() {
+            names.sort();
+            return Box::new(future::ok(Records::Names(names)));
+        }
+        if self.config.disabled {
+            return Box::new(future::err(not_found(format!(
+                "dns is disabled, {} isn't a static host",
+                addr
+            ))));
+        }
+        Box::new(
+            self.resolver
+                .reverse_lookup(ip)
+                .map_err(resolve_error)
+                .map(|names| Records::Names(names.iter().map(|n| n.to_string()).collect())),
+        )
+    }
+}
+
+fn resolve_error(err: ResolveError) -> io::Error {
+    not_found(err.to_string())
+}
+
+fn cache_insert(cache: &mut HashMap<String, CacheEntry>, host: String, entry: CacheEntry) {
+    if cache.len() >= MAX_CACHED && !cache.contains_key(&host) {
+        let now = Instant::now();
+        cache.retain(|_, entry| entry.expires > now);
+        // still full, so the entry that expires first goes
+        if cache.len() >= MAX_CACHED {
+            let first = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(host, _)| host.clone());
+            if let Some(first) = first {
+                cache.remove(&first);
+            }
+        }
+    }
+    cache.insert(host, entry);
+}
+
+fn not_found(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, msg)
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::thread;
+    use tokio::runtime::Runtime;
+
+    // answers a single query with one record of rtype and returns the
+    // server's address
+    fn stub_resolver(rtype: u8, rdata: Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (_, from) = socket.recv_from(&mut buf).unwrap();
+            // skip the labels of the question's name, then its type and class
+            let mut end = 12;
+            while buf[end] != 0 {
+                end += buf[end] as usize + 1;
+            }
+            end += 5;
+            let mut resp = buf[..2].to_vec();
+            resp.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+            resp.extend_from_slice(&buf[12..end]);
+            resp.extend_from_slice(&[0xc0, 0x0c, 0, rtype, 0, 1, 0, 0, 0, 60, 0]);
+            resp.push(rdata.len() as u8);
+            resp.extend_from_slice(&rdata);
+            socket.send_to(&resp, from).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn parses_config() {
+        let mut config = DnsConfig::default();
+        config.add_upstream("127.0.0.1:5353").unwrap();
+        config.add_upstream("::1").unwrap();
+        assert_eq!(
+            config.upstreams,
+            vec![
+                "127.0.0.1:5353".parse().unwrap(),
+                "[::1]:53".parse().unwrap()
+            ]
+        );
+        config.add_host("Db.Internal.=10.0.0.5,10.0.0.6").unwrap();
+        assert_eq!(config.hosts["db.internal"].len(), 2);
+        assert!(config.add_host("db.internal").is_err());
+        assert!(config.add_host("db=nope").is_err());
+        assert!(config.add_upstream("nope").is_err());
+    }
+
+    #[test]
+    fn resolves_through_upstream_and_caches() {
+        let ip = Ipv4Addr::new(10, 1, 2, 3);
+        let mut config = DnsConfig::default();
+        config
+            .add_upstream(&stub_resolver(1, ip.octets().to_vec()).to_string())
+            .unwrap();
+        config.set_cache_ttl(Duration::from_secs(60));
+        let (resolver, background) = Resolver::new(config).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(background);
+
+        let ips = runtime.block_on(resolver.lookup_ip("stub.test")).unwrap();
+        assert_eq!(ips, vec![IpAddr::V4(ip)]);
+        assert!(resolver.cache.lock().unwrap().contains_key("stub.test"));
+        // the stub only answers once
+        let ips = runtime.block_on(resolver.lookup_ip("Stub.Test.")).unwrap();
+        assert_eq!(ips, vec![IpAddr::V4(ip)]);
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let mut cache = HashMap::new();
+        let now = Instant::now();
+        for i in 0..MAX_CACHED {
+            let entry = CacheEntry {
+                expires: now + Duration::from_secs(60 + i as u64),
+                result: Ok(vec![]),
+            };
+            cache_insert(&mut cache, i.to_string(), entry);
+        }
+        let entry = CacheEntry {
+            expires: now + Duration::from_secs(3600),
+            result: Ok(vec![]),
+        };
+        cache_insert(&mut cache, "new".to_string(), entry);
+        assert_eq!(cache.len(), MAX_CACHED);
+        assert!(cache.contains_key("new"));
+        assert!(!cache.contains_key("0"));
+
+        // expired entries are dropped first
+        cache.get_mut("5").unwrap().expires = now;
+        cache.get_mut("6").unwrap().expires = now;
+        let entry = CacheEntry {
+            expires: now + Duration::from_secs(3600),
+            result: Ok(vec![]),
+        };
+        cache_insert(&mut cache, "newer".to_string(), entry);
+        assert_eq!(cache.len(), MAX_CACHED - 1);
+        assert!(cache.contains_key("1"));
+    }
+
+    #[test]
+    fn disabled_uses_static_hosts() {
+        let mut config = DnsConfig::default();
+        config.add_host("db.internal=10.0.0.5").unwrap();
+        config.disable();
+        let (resolver, _) = Resolver::new(config).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        let ips = runtime.block_on(resolver.lookup_ip("db.internal")).unwrap();
+        assert_eq!(ips, vec!["10.0.0.5".parse::<IpAddr>().unwrap()]);
+        let err = runtime
+            .block_on(resolver.lookup_ip("localhost"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn looks_up_mx_records() {
+        let mut config = DnsConfig::default();
+        let rdata = b"\x00\x0a\x04mail\x04test\x00".to_vec();
+        config
+            .add_upstream(&stub_resolver(15, rdata).to_string())
+            .unwrap();
+        let (resolver, background) = Resolver::new(config).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(background);
+
+        let records = runtime
+            .block_on(resolver.lookup("test", RecordKind::Mx))
+            .unwrap();
+        assert_eq!(
+            records,
+            Records::Mx(vec![MxRecord {
+                host: "mail.test.".to_string(),
+                pref: 10,
+            }])
+        );
+    }
+
+    #[test]
+    fn ptr_uses_static_hosts() {
+        let mut config = DnsConfig::default();
+        config.add_host("db.internal=10.0.0.5").unwrap();
+        config.disable();
+        let (resolver, _) = Resolver::new(config).unwrap();
+        let mut runtime = Runtime::new().unwrap();
+        let records = runtime
+            .block_on(resolver.lookup("10.0.0.5", RecordKind::Ptr))
+            .unwrap();
+        assert_eq!(records, Records::Names(vec!["db.internal.".to_string()]));
+        let err = runtime
+            .block_on(resolver.lookup("db.internal", RecordKind::Ptr))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(runtime
+            .block_on(resolver.lookup("db.internal", RecordKind::Txt))
+            .is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! Host HTTP client
+//!
+//! Go's net/http uses the browser's fetch API on js/wasm. The guest's fetch
+//! calls are served by a hyper client running on the IOLoop's reactor, so the
+//! default transport works without any changes to the guest.
+//!
+//! Connections are made the same way a guest's dial would be: names are
+//! resolved with the instance's resolver, addresses are checked against its
+//! egress policy, dials wait for the connection rate and time out after the
+//! connect timeout, and https is verified with its TLS config. Denied dials
+//! are sent back to the IOLoop for its audit log.
+
+use crate::deadline::Timeouts;
+use crate::dns::{LookupFuture, Resolver};
+use crate::policy::Policy;
+use crate::ratelimit::{Budget, RateLimiter};
+use crate::tls::{Tls, TlsConfig};
+use crate::Message;
+use futures::future::{self, Either, Loop};
+use futures::{Future, Poll};
+use hyper::client::connect::{Connect, Connected, Destination};
+use hyper::{Body, Client, Request};
+use rustls::ClientConfig;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::timer::{Delay, Timeout};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+pub type HttpClient = Client<Connector, Body>;
+
+/// A request made with the guest's fetch
+#[derive(Debug, Clone, Default)]
+pub struct FetchRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl FetchRequest {
+    pub(crate) fn build(self) -> Result<Request<Body>, String> {
+        let mut builder = Request::builder();
+        builder.method(self.method.as_str()).uri(self.url.as_str());
+        for (name, value) in &self.headers {
+            builder.header(name.as_str(), value.as_str());
+        }
+        builder
+            .body(Body::from(self.body))
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchResponse {
+    /// the status and headers arrived. The body is read with fetch_read.
+    Head {
+        id: i64,
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: usize,
+    },
+    /// the next part of a body, None once all of it has been read
+    Chunk {
+        id: i64,
+        chunk: Option<Vec<u8>>,
+    },
+    Failed {
+        id: i64,
+        msg: String,
+    },
+    /// the request was aborted before the head arrived
+    Aborted {
+        id: i64,
+    },
+}
+
+impl FetchResponse {
+    pub fn id(&self) -> i64 {
+        match self {
+            FetchResponse::Head { id, .. }
+            | FetchResponse::Chunk { id, .. }
+            | FetchResponse::Failed { id, .. }
+            | FetchResponse::Aborted { id } => *id,
+        }
+    }
+}
+
+/// The parts of an IOLoop that its fetch connections are subject to
+pub(crate) struct Controls {
+    pub resolver: Resolver,
+    pub policy: Policy,
+    pub limiter: RateLimiter,
+    pub timeouts: Timeouts,
+    /// the instance's TLS config, None until it needs one
+    pub tls: Option<Arc<ClientConfig>>,
+    pub events: mpsc::Sender<Message>,
+}
+
+pub(crate) fn client(controls: Controls) -> HttpClient {
+    let mut builder = Client::builder();
+    // pooled connections are closed once they have been idle for as long
+    // as a guest's connection could be
+    if let Some(idle) = controls.timeouts.idle {
+        builder.keep_alive_timeout(idle);
+    }
+    builder.build(Connector {
+        resolver: controls.resolver,
+        policy: controls.policy,
+        limiter: controls.limiter,
+        connect_timeout: controls.timeouts.connect,
+        tls: Arc::new(Mutex::new(controls.tls)),
+        events: Arc::new(Mutex::new(controls.events)),
+    })
+}
+
+/// Connects to the origin of a request
+#[derive(Clone)]
+pub struct Connector {
+    resolver: Resolver,
+    // snapshots, as the IOLoop drops its client when they are replaced
+    policy: Policy,
+    connect_timeout: Option<Duration>,
+    limiter: RateLimiter,
+    tls: Arc<Mutex<Option<Arc<ClientConfig>>>>,
+    events: Arc<Mutex<mpsc::Sender<Message>>>,
+}
+
+impl Connector {
+    /// the config for https, which is made the first time it's needed when
+    /// the instance doesn't have one
+    fn tls_config(&self) -> io::Result<Arc<ClientConfig>> {
+        let mut tls = self.tls.lock().unwrap();
+        if tls.is_none() {
+            let config = Tls::new(&TlsConfig::default())
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            *tls = Some(config.client_config());
+        }
+        Ok(tls.clone().unwrap())
+    }
+}
+
+/// resolves once the connection rate allows another dial
+fn admit(limiter: RateLimiter) -> impl Future<Item = (), Error = io::Error> {
+    future::loop_fn((), move |()| {
+        match limiter.reserve(Budget::Connections, 1) {
+            Ok(_) => Either::A(future::ok(Loop::Break(()))),
+            Err(wait) => Either::B(
+                Delay::new(Instant::now() + wait)
+                    .map(|()| Loop::Continue(()))
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+            ),
+        }
+    })
+}
+
+fn connect(
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    let stream = TcpStream::connect(&addr);
+    match timeout {
+        Some(timeout) => Either::A(Timeout::new(stream, timeout).map_err(|err| {
+            err.into_inner()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))
+        })),
+        None => Either::B(stream),
+    }
+}
+
+impl Connect for Connector {
+    type Transport = HttpStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (HttpStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let https = dst.scheme() == "https";
+        let tls = if https {
+            match self.tls_config() {
+                Ok(tls) => Some(tls),
+                Err(err) => return Box::new(future::err(err)),
+            }
+        } else {
+            None
+        };
+        let port = dst.port().unwrap_or(if https { 443 } else { 80 });
+        let host = dst
+            .host()
+            .trim_matches(|c| c == '[' || c == ']')
+            .to_string();
+        let ips: LookupFuture = match host.parse::<IpAddr>() {
+            Ok(ip) => Box::new(future::ok(vec![ip])),
+            Err(_) => self.resolver.lookup_ip(&host),
+        };
+        let mut policy = self.policy.clone();
+        let events = self.events.clone();
+        let limiter = self.limiter.clone();
+        let charge = self.limiter.clone();
+        let connect_timeout = self.connect_timeout;
+        let name = host.clone();
+        Box::new(
+            ips.and_then(move |ips| {
+                policy.record_resolution(&host, ips.iter().cloned());
+                // the first address the guest would be allowed to dial
+                let mut denied = None;
+                for ip in ips {
+                    let addr = SocketAddr::new(ip, port);
+                    match policy.check_connect(&addr) {
+                        Ok(()) => return Ok(addr),
+                        Err(err) => denied = Some((addr, err)),
+                    }
+                }
+                Err(match denied {
+                    Some((addr, err)) => {
+                        let _ = events.lock().unwrap().send(Message::Denied {
+                            op: "connect",
+                            target: addr.to_string(),
+                            error: err.to_string(),
+                        });
+                        err
+                    }
+                    None => io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no addresses for {}", host),
+                    ),
+                })
+            })
+            .and_then(move |addr| admit(limiter).map(move |()| addr))
+            .and_then(move |addr| connect(addr, connect_timeout))
+            .and_then(move |stream| {
+                charge.charge(Budget::Connections, 1);
+                let tls = match tls {
+                    Some(tls) => tls,
+                    None => return Either::A(future::ok(HttpStream::Plain(stream))),
+                };
+                match webpki::DNSNameRef::try_from_ascii_str(&name) {
+                    Ok(dns_name) => Either::B(Either::A(
+                        TlsConnector::from(tls)
+                            .connect(dns_name, stream)
+                            .map(HttpStream::Tls),
+                    )),
+                    Err(_) => Either::B(Either::B(future::err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid tls server name {}", name),
+                    )))),
+                }
+            })
+            .map(|stream| (stream, Connected::new())),
+        )
+    }
+}
+
+pub enum HttpStream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(stream) => stream.read(buf),
+            HttpStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for HttpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            HttpStream::Plain(stream) => stream.write(buf),
+            HttpStream::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            HttpStream::Plain(stream) => stream.flush(),
+            HttpStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for HttpStream {}
+
+impl AsyncWrite for HttpStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            HttpStream::Plain(stream) => AsyncWrite::shutdown(stream),
+            HttpStream::Tls(stream) => stream.shutdown(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_requests() {
+        let request = FetchRequest {
+            method: "POST".to_string(),
+            url: "http://example.com/form".to_string(),
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"hi".to_vec(),
+        }
+        .build()
+        .unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.uri().host(), Some("example.com"));
+        assert_eq!(request.headers()["content-type"], "text/plain");
+
+        let invalid = FetchRequest {
+            method: "GET".to_string(),
+            url: "not a url".to_string(),
+            ..FetchRequest::default()
+        };
+        assert!(invalid.build().is_err());
+    }
+}
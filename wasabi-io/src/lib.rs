@@ -1,9 +1,12 @@
 //! Wasabi io
 //!
-//! This module contains a struct IOLoop that handles all kinds of IO. Networking
-//! is implemented using mio and a single event thread. Filesystem io and dns is
-//! handled with tokio and trust-dns. A simple chroot is implemented for the
-//! filesystem. More resoure limiting and access rules will be added in the future.
+//! This module contains a struct IOLoop that handles all kinds of IO. Every
+//! IOLoop runs on a reactor, which is either its own or a shard of a Reactors
+//! pool shared with other instances. A reactor is one thread that drives the
+//! sockets, dns lookups, timers and fetches of all its instances, with a small
+//! pool next to it for blocking filesystem io. Lookups use trust-dns. A simple
+//! chroot is implemented for the filesystem, and policy and rate limits
+//! restrict the network.
 
 #![deny(
     // missing_docs,
@@ -31,26 +34,70 @@
     )
 )]
 
+mod audit;
+mod deadline;
+mod dns;
+mod http;
+mod listenfd;
+mod policy;
+mod pool;
+mod portmap;
+mod proxy;
+mod ratelimit;
+mod reactor;
+mod record;
+mod sockopt;
+mod tls;
+mod vnet;
+mod watch;
+
+use audit::AuditLog;
+pub use deadline::{Deadline, Timeouts};
+use deadline::{Deadlines, Timer};
+pub use dns::{DnsConfig, MxRecord, RecordKind, SrvRecord};
+use dns::{Records, Resolver};
 use failure::{err_msg, Error};
-use futures::future;
-use futures::Future;
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use http::HttpClient;
+pub use http::{FetchRequest, FetchResponse};
+use listenfd::Inherited;
+pub use listenfd::{ListenFds, ListenerSet};
 use mio;
-use mio::net::{TcpListener, TcpStream};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use mio_uds::{UnixListener, UnixStream};
 use path_dedot::ParseDot;
+pub use policy::Policy;
+use pool::BufferPool;
+pub use portmap::{PortMap, PortMapping};
+use proxy::{Parsed, Proxied};
+use ratelimit::Budget;
+pub use ratelimit::{RateLimiter, RateLimits};
+pub use reactor::{Reactor, Reactors};
+use record::{Capture, Replay};
 use slab::Slab;
+pub use sockopt::{SockOpt, SockOptLimits};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::env::current_dir;
 use std::fs;
 use std::io::{Read, SeekFrom, Write};
-use std::net::{Shutdown, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::sync::{mpsc, Arc};
-use std::{thread, time};
+use std::time;
+pub use tls::TlsConfig;
+use tls::{Tls, TlsSession};
 use tokio;
 use tokio::fs::{metadata, OpenOptions};
-use tokio::runtime::Runtime;
-use trust_dns_resolver;
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
-use trust_dns_resolver::AsyncResolver;
+use tokio::timer::{Delay, Timeout};
+pub use vnet::VirtualNetwork;
+use vnet::{VirtualListener, VirtualStream};
+use watch::Watcher;
+pub use watch::{WatchEvent, WatchKind};
 
 /// O_WRONLY openmode bitmask
 pub const O_WRONLY: i64 = 1;
@@ -65,6 +112,24 @@ pub const O_APPEND: i64 = 1024;
 /// O_EXCL openmode bitmask
 pub const O_EXCL: i64 = 128;
 
+/// LOCK_SH flock operation, a shared lock
+pub const LOCK_SH: i64 = 1;
+/// LOCK_EX flock operation, an exclusive lock
+pub const LOCK_EX: i64 = 2;
+/// LOCK_UN flock operation, removes a held lock
+pub const LOCK_UN: i64 = 8;
+
+// admit keeps slab ids below this
+const WATCH_TOKEN: usize = reactor::MAX_TOKEN;
+
+// guest fds of opened files start here, as 0 to 2 are stdin, stdout and
+// stderr, which the embedder writes to the host's own
+const FIRST_FILE_FD: usize = 3;
+
+// the most send_file copies at once to streams that it can't use sendfile(2)
+// for
+const SEND_FILE_BUFFER: usize = 64 * 1024;
+
 /// converts a mio event to a token id and event bitarray
 /// the first four bits correlate to: readable, writeable, is_hup, is_error
 pub fn event_to_ints(event: &mio::Event) -> ((i64, i64)) {
@@ -90,16 +155,53 @@ fn u16_as_u8_le(x: u16) -> [u8; 2] {
     [(x & 0xff) as u8, ((x >> 8) & 0xff) as u8]
 }
 
-/// writes an ipv4 socket address as bytes to a u8 array
+/// Number of bytes written by addr_to_bytes
+pub const ADDR_LEN: usize = 19;
+/// addr_to_bytes family tag for ipv4 addresses
+pub const ADDR_V4: u8 = 4;
+/// addr_to_bytes family tag for ipv6 addresses
+pub const ADDR_V6: u8 = 6;
+
+/// writes a socket address as bytes to a u8 array. The first byte is the
+/// address family, followed by 16 bytes of ip (ipv4 only uses the first 4)
+/// and the port as a little endian u16
 pub fn addr_to_bytes(addr: SocketAddr, b: &mut [u8]) -> Result<(), Error> {
+    if b.len() < ADDR_LEN {
+        return Err(err_msg("address buffer too small"));
+    }
+    for byte in b[..ADDR_LEN].iter_mut() {
+        *byte = 0;
+    }
     match addr {
         SocketAddr::V4(a) => {
-            b[0..4].copy_from_slice(&a.ip().octets());
-            b[4..6].copy_from_slice(&u16_as_u8_le(a.port()));
-            Ok(())
+            b[0] = ADDR_V4;
+            b[1..5].copy_from_slice(&a.ip().octets());
         }
-        SocketAddr::V6(_) => Err(err_msg("IPV6 not supported")),
+        SocketAddr::V6(a) => {
+            b[0] = ADDR_V6;
+            b[1..17].copy_from_slice(&a.ip().octets());
+        }
+    }
+    b[17..19].copy_from_slice(&u16_as_u8_le(addr.port()));
+    Ok(())
+}
+
+/// reads a socket address written in the addr_to_bytes format
+pub fn bytes_to_addr(b: &[u8]) -> Result<SocketAddr, Error> {
+    if b.len() < ADDR_LEN {
+        return Err(err_msg("address buffer too small"));
     }
+    let port = u16::from(b[17]) | (u16::from(b[18]) << 8);
+    let ip = match b[0] {
+        ADDR_V4 => IpAddr::V4(Ipv4Addr::new(b[1], b[2], b[3], b[4])),
+        ADDR_V6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&b[1..17]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(err_msg("unknown address family")),
+    };
+    Ok(SocketAddr::new(ip, port))
 }
 
 #[derive(Debug)]
@@ -111,7 +213,20 @@ pub enum Response {
     },
     Ips {
         id: i64,
-        ips: trust_dns_resolver::lookup_ip::LookupIp,
+        host: String,
+        ips: Vec<IpAddr>,
+    },
+    Names {
+        id: i64,
+        names: Vec<String>,
+    },
+    Mx {
+        id: i64,
+        mx: Vec<MxRecord>,
+    },
+    Srv {
+        id: i64,
+        srv: Vec<SrvRecord>,
     },
     Metadata {
         id: i64,
@@ -125,6 +240,8 @@ pub enum Response {
         id: i64,
         len: usize,
     },
+    /// The first len bytes of buf were read for address. buf can be handed
+    /// back with IOLoop::recycle once they've been copied.
     Read {
         id: i64,
         len: usize,
@@ -134,10 +251,15 @@ pub enum Response {
     Success {
         id: i64,
     },
+    Watch {
+        id: i64,
+        watch: usize,
+    },
     File {
         id: i64,
         file: tokio::fs::File,
     },
+    Fetch(FetchResponse),
     Event(mio::event::Event),
 }
 
@@ -150,13 +272,61 @@ impl Response {
             Response::Written { id, .. } => Some(*id),
             Response::Error { id, .. } => Some(*id),
             Response::Ips { id, .. } => Some(*id),
+            Response::Names { id, .. } => Some(*id),
+            Response::Mx { id, .. } => Some(*id),
+            Response::Srv { id, .. } => Some(*id),
             Response::Metadata { id, .. } => Some(*id),
             Response::FileRef { id, .. } => Some(*id),
+            Response::Watch { id, .. } => Some(*id),
+            Response::Fetch(fetch) => Some(fetch.id()),
             Response::Event(_) => None,
         }
     }
 }
 
+/// What the reactor and the IOLoop's tasks send it. Everything but
+/// Message::Response is handled by the IOLoop and never returned from recv.
+#[derive(Debug)]
+pub(crate) enum Message {
+    Response(Response),
+    /// The events of one poll of the reactor. The IOLoop returns them one at
+    /// a time as Response::Event.
+    Events(Vec<mio::event::Event>),
+    /// A timer of a socket fired, so one of its deadlines may have passed
+    Deadline {
+        token: usize,
+        timer: Timer,
+    },
+    /// The head of a fetch arrived. The IOLoop keeps the body and returns
+    /// FetchResponse::Head.
+    FetchHead {
+        id: i64,
+        abort: usize,
+        response: hyper::Response<hyper::Body>,
+    },
+    /// A read of a fetch body finished. The IOLoop keeps the body and
+    /// returns FetchResponse::Chunk.
+    FetchChunk {
+        id: i64,
+        body: usize,
+        stream: hyper::Body,
+        chunk: Result<Option<Vec<u8>>, String>,
+    },
+    /// A fetch dialed an address the policy denies. The IOLoop records it
+    /// in the audit log.
+    Denied {
+        op: &'static str,
+        target: String,
+        error: String,
+    },
+}
+
+impl From<Response> for Message {
+    fn from(resp: Response) -> Self {
+        Message::Response(resp)
+    }
+}
+
 trait ToResponse {
     fn to_response(self, id: i64) -> Response;
 }
@@ -212,42 +382,134 @@ impl ToResponse for (tokio::fs::File, fs::Metadata) {
     }
 }
 
+/// Applies a non-blocking advisory lock to file. Blocking locks are left to
+/// the guest so that the event loop is never held up waiting on a lock.
+fn flock(file: &fs::File, how: i64) -> Result<(), std::io::Error> {
+    let operation = match how & (LOCK_SH | LOCK_EX | LOCK_UN) {
+        LOCK_SH => libc::LOCK_SH,
+        LOCK_EX => libc::LOCK_EX,
+        LOCK_UN => libc::LOCK_UN,
+        _ => return Err(std::io::Error::from_raw_os_error(libc::EINVAL)),
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 fn send_result(
     id: i64,
-    es: mpsc::Sender<Response>,
+    es: mpsc::Sender<Message>,
     result: Result<impl ToResponse, std::io::Error>,
 ) -> impl Future<Item = (), Error = ()> {
-    match result {
-        Err(err) => es
-            .send(Response::Error {
-                msg: err.to_string(),
-                id,
-                kind: err.kind(),
-            })
-            .unwrap(),
-        Ok(tr) => es.send(tr.to_response(id)).unwrap(),
+    let resp = match result {
+        Err(err) => Response::Error {
+            msg: err.to_string(),
+            id,
+            kind: err.kind(),
+        },
+        Ok(tr) => tr.to_response(id),
     };
+    // the receiver is gone if the instance already ended, which mustn't take
+    // the reactor's other instances down with it
+    let _ = es.send(resp.into());
     future::ok(())
 }
 
+/// Turns a failed or timed out dns lookup into an error response
+fn lookup_error(
+    id: i64,
+    host: &str,
+    err: tokio::timer::timeout::Error<std::io::Error>,
+) -> Response {
+    let msg = format!("lookup {}: {}", host, err);
+    let kind = if err.is_elapsed() {
+        std::io::ErrorKind::TimedOut
+    } else {
+        err.into_inner()
+            .map_or(std::io::ErrorKind::NotFound, |err| err.kind())
+    };
+    Response::Error { msg, kind, id }
+}
+
 #[derive(Debug)]
-enum Tcp {
+enum Socket {
     Listener(TcpListener),
     Stream(TcpStream),
+    // mio doesn't keep track of the address a udp socket is connected to
+    Udp(UdpSocket, Option<SocketAddr>),
+    // the host path is kept so the socket file can be removed on close
+    UnixListener(UnixListener, PathBuf),
+    UnixStream(UnixStream),
+    VirtualListener(VirtualListener),
+    VirtualStream(VirtualStream),
 }
 
 #[derive(Debug)]
 pub struct IOLoop {
-    event_receiver: mpsc::Receiver<Response>,
-    event_sender: mpsc::Sender<Response>,
+    event_receiver: mpsc::Receiver<Message>,
+    event_sender: mpsc::Sender<Message>,
     path: PathBuf,
     call_count: usize,
     runtime_cwd: PathBuf,
+    reactor: Arc<Reactor>,
+    // this instance's id on the reactor, which its sockets' tokens carry
+    instance: usize,
     poll: Arc<mio::Poll>,
-    resolver: AsyncResolver,
-    runtime: Runtime,
-    slab: Slab<Tcp>,
+    // events of the last batch from the reactor that weren't returned yet
+    events: VecDeque<mio::Event>,
+    resolver: Resolver,
+    executor: tokio_current_thread::Handle,
+    blocking: tokio_threadpool::Sender,
+    slab: Slab<Socket>,
     files: Slab<std::fs::File>,
+    buffers: BufferPool,
+    audit: Option<AuditLog>,
+    watcher: Option<Watcher>,
+    policy: Policy,
+    port_map: PortMap,
+    // listeners bound before the instance started, until the guest listens
+    inherited: Inherited,
+    listeners: ListenerSet,
+    // listeners bound through the port map
+    mappings: HashMap<usize, PortMapping>,
+    // guest ports of streams accepted from mapped listeners
+    guest_ports: HashMap<usize, u16>,
+    port_mapped: Option<mpsc::Sender<PortMapping>>,
+    dns_timeout: time::Duration,
+    sockopt_limits: SockOptLimits,
+    timeouts: Timeouts,
+    deadlines: HashMap<usize, Deadlines>,
+    limiter: RateLimiter,
+    // built from the default TlsConfig when first needed, as that reads the
+    // system's CA bundle
+    tls: Option<Tls>,
+    tls_streams: HashMap<usize, TlsSession>,
+    tls_listeners: HashSet<usize>,
+    proxied: Proxied,
+    // built when first needed and dropped when the policy, resolver or TLS
+    // config it uses is replaced
+    http_client: Option<HttpClient>,
+    // fetches waiting for their head, by abort handle
+    fetch_aborts: HashMap<usize, oneshot::Sender<()>>,
+    // response bodies, None while a read is in flight
+    fetch_bodies: HashMap<usize, Option<hyper::Body>>,
+    next_fetch: usize,
+    network: VirtualNetwork,
+    capture: Option<Capture>,
+    // answers connects, accepts and lookups instead of the network
+    replay: Option<Replay>,
+}
+
+impl Drop for IOLoop {
+    fn drop(&mut self) {
+        self.listeners.clear();
+        // the instance's id is handed out again once it leaves, so its
+        // sockets are closed first
+        self.slab.clear();
+        self.watcher = None;
+        self.reactor.leave(self.instance);
+    }
 }
 
 impl Default for IOLoop {
@@ -257,76 +519,566 @@ impl Default for IOLoop {
 }
 
 impl IOLoop {
+    /// An IOLoop with a reactor of its own
     pub fn new() -> Self {
-        let poll = Arc::new(mio::Poll::new().unwrap());
+        Self::on_reactor(Reactor::new().unwrap())
+    }
+    /// An IOLoop on the least busy reactor of a pool shared with other
+    /// instances
+    pub fn with_reactors(reactors: &Reactors) -> Self {
+        Self::on_reactor(reactors.pick())
+    }
+    fn on_reactor(reactor: Arc<Reactor>) -> Self {
         let (event_sender, event_receiver) = mpsc::channel();
-        let es = event_sender.clone();
-        let t_poll = poll.clone();
-
-        // Spawn a thread for mio events
-        thread::spawn(move || {
-            let mut events = mio::Events::with_capacity(1024);
-            loop {
-                t_poll.poll(&mut events, None).unwrap();
-                for event in events.iter() {
-                    if es.send(Response::Event(event)).is_err() {
-                        // the receiver has been dropped, so we can
-                        // proceed with quitting
-                        break;
-                    }
-                }
-            }
-        });
+        let instance = reactor.join(event_sender.clone()).unwrap();
+        let (resolver, background) = Resolver::new(DnsConfig::default()).unwrap();
 
-        // let's guarantee order for now, we can make performance changes later
-        let mut runtime = tokio::runtime::Builder::new()
-            .core_threads(1)
-            .build()
-            .unwrap();
-        let (resolver, background) =
-            AsyncResolver::new(ResolverConfig::default(), ResolverOpts::default());
-        runtime.spawn(background);
-
-        Self {
+        let nl = Self {
             event_receiver,
             event_sender,
             call_count: 0,
             path: PathBuf::from("/"),
-            poll,
+            poll: reactor.poll().clone(),
+            events: VecDeque::new(),
+            executor: reactor.executor(),
+            blocking: reactor.blocking(),
+            reactor,
+            instance,
             resolver,
             runtime_cwd: current_dir().unwrap(),
-            runtime,
             slab: Slab::new(),
             files: Slab::new(),
+            buffers: BufferPool::default(),
+            audit: None,
+            watcher: None,
+            policy: Policy::default(),
+            port_map: PortMap::default(),
+            inherited: Inherited::default(),
+            listeners: ListenerSet::default(),
+            mappings: HashMap::new(),
+            guest_ports: HashMap::new(),
+            port_mapped: None,
+            dns_timeout: time::Duration::from_secs(5),
+            sockopt_limits: SockOptLimits::default(),
+            timeouts: Timeouts::default(),
+            deadlines: HashMap::new(),
+            limiter: RateLimiter::default(),
+            tls: None,
+            tls_streams: HashMap::new(),
+            tls_listeners: HashSet::new(),
+            proxied: Proxied::default(),
+            http_client: None,
+            fetch_aborts: HashMap::new(),
+            fetch_bodies: HashMap::new(),
+            next_fetch: 0,
+            network: VirtualNetwork::default(),
+            capture: None,
+            replay: None,
+        };
+        nl.spawn(background);
+        nl
+    }
+    /// Runs a task on the reactor's thread
+    fn spawn<F>(&self, f: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        // only fails once the reactor has stopped, which it doesn't while
+        // this instance is on it
+        let _ = self.executor.spawn(f);
+    }
+    /// Runs a task that blocks, like file operations, off the reactor's
+    /// thread
+    fn spawn_blocking<F>(&self, f: F)
+    where
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let _ = self.blocking.spawn(f);
+    }
+    /// Log every filesystem operation as a line of JSON to sink. Auditing is
+    /// disabled by default.
+    pub fn enable_audit_log(&mut self, sink: Box<dyn Write + Send>) {
+        self.audit = Some(AuditLog::new(sink));
+    }
+    /// Errs once the audit log couldn't be written to. Nothing is logged
+    /// after that, so the embedder should treat the log as incomplete.
+    pub fn check_audit_log(&self) -> Result<(), Error> {
+        match &self.audit {
+            Some(audit) => audit.check(),
+            None => Ok(()),
+        }
+    }
+    /// Log the guest's TCP connections, the bytes read and written on them
+    /// and its DNS answers to sink, in the format Replay reads
+    pub fn record_network(&mut self, sink: Box<dyn Write + Send>) {
+        self.capture = Some(Capture::new(sink));
+    }
+    /// Serve connections and DNS answers from a capture made with
+    /// record_network instead of the network. Connects that weren't recorded
+    /// are refused and fetch isn't available.
+    pub fn replay_network(&mut self, capture: &str) -> Result<(), Error> {
+        self.replay = Some(capture.parse()?);
+        Ok(())
+    }
+    /// Restrict the addresses the guest may connect to and the ports it may
+    /// listen on. Denied operations are recorded in the audit log.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.http_client = None;
+    }
+    /// Translate the ports the guest listens on. The guest keeps seeing the
+    /// address it asked for in local_addr.
+    pub fn set_port_map(&mut self, port_map: PortMap) {
+        self.port_map = port_map;
+    }
+    /// Hand listeners bound outside of the instance to the guest when it
+    /// listens on the address they're bound to. Each fd must be a listening
+    /// tcp socket, the IOLoop takes ownership of it.
+    pub fn inherit_listeners(&mut self, fds: &ListenFds) -> Result<(), Error> {
+        self.inherited.adopt(fds)?;
+        for (name, fd) in fds.iter() {
+            self.listeners.inherit(name.clone(), *fd);
+        }
+        Ok(())
+    }
+    /// A handle to the tcp listeners the guest holds that can be used from
+    /// other threads, to pass them on to a new process
+    pub fn listener_set(&self) -> ListenerSet {
+        self.listeners.clone()
+    }
+    /// Send every new port mapping to sender as listeners are bound
+    pub fn notify_port_mappings(&mut self, sender: mpsc::Sender<PortMapping>) {
+        self.port_mapped = Some(sender);
+    }
+    /// The mappings of all listeners that are currently open
+    pub fn port_mappings(&self) -> Vec<PortMapping> {
+        self.mappings.values().cloned().collect()
+    }
+    fn record_mapping(&mut self, id: usize, guest: SocketAddr, host: SocketAddr) {
+        let mapping = PortMapping { id, guest, host };
+        self.mappings.insert(id, mapping);
+        if let Some(sender) = &self.port_mapped {
+            // the embedder may not care about mappings anymore
+            let _ = sender.send(mapping);
+        }
+    }
+    /// Replace the upstream servers, static hosts, search domains and
+    /// caching used by lookup_ip
+    pub fn set_dns_config(&mut self, config: DnsConfig) -> Result<(), Error> {
+        let (resolver, background) = Resolver::new(config)?;
+        self.spawn(background);
+        self.resolver = resolver;
+        self.http_client = None;
+        Ok(())
+    }
+    /// How long lookup_ip waits for an answer before failing with
+    /// ErrorKind::TimedOut
+    pub fn set_dns_timeout(&mut self, timeout: time::Duration) {
+        self.dns_timeout = timeout;
+    }
+    /// Cap the socket options the guest can set
+    pub fn set_sockopt_limits(&mut self, limits: SockOptLimits) {
+        self.sockopt_limits = limits;
+    }
+    /// Fail tcp streams that take too long to connect or that go idle. The
+    /// guest gets an error event and a timeout from get_error.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+        self.http_client = None;
+    }
+    /// Set or clear the read or write deadline of a socket. Reads or writes
+    /// fail with ErrorKind::TimedOut once it passes, until it's moved.
+    pub fn set_deadline(
+        &mut self,
+        i: usize,
+        kind: Deadline,
+        at: Option<time::Instant>,
+    ) -> Result<(), Error> {
+        self.slab_get(i)?;
+        let schedule = self
+            .deadlines
+            .entry(i)
+            .or_insert_with(|| Deadlines::new(None))
+            .set(kind, at);
+        if let Some(at) = schedule {
+            self.schedule_deadline(i, at, Timer::Deadline(kind));
+        }
+        Ok(())
+    }
+    fn schedule_deadline(&mut self, token: usize, at: time::Instant, timer: Timer) {
+        let es = self.event_sender.clone();
+        self.spawn(Delay::new(at).then(move |_| {
+            // the receiver is gone if the instance already ended
+            let _ = es.send(Message::Deadline { token, timer });
+            future::ok(())
+        }));
+    }
+    /// Fails the socket if its connect or idle timeout passed, and wakes the
+    /// guest's reads and writes if a read or write deadline did
+    fn expire_deadline(&mut self, token: usize, timer: Timer) -> Option<Response> {
+        if let Timer::Deadline(kind) = timer {
+            let (passed, next) = self.deadlines.get_mut(&token)?.fire(kind);
+            if let Some(at) = next {
+                self.schedule_deadline(token, at, timer);
+            }
+            if !passed {
+                return None;
+            }
+            // the retried read or write gets the timeout from
+            // check_deadline. both are woken as each event replaces the
+            // guest's event state, and the error bit stays for real errors
+            return Some(Response::Event(mio::Event::new(
+                mio::Ready::readable() | mio::Ready::writable(),
+                mio::Token(token),
+            )));
+        }
+        let (failed, idle_deadline) = {
+            let deadlines = self.deadlines.get_mut(&token)?;
+            let failed = deadlines.expire();
+            let idle_deadline = if deadlines.failed() {
+                None
+            } else {
+                deadlines.idle_deadline()
+            };
+            (failed, idle_deadline)
+        };
+        if failed {
+            if let Ok(stream) = self.get_stream_ref(token) {
+                // no events but our own error should reach the guest
+                let _ = self.poll.deregister(stream);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        // there was activity since the idle timer was scheduled
+        if let (Timer::Idle, Some(at)) = (timer, idle_deadline) {
+            self.schedule_deadline(token, at, Timer::Idle);
+        }
+        if failed {
+            Some(Response::Event(mio::Event::new(
+                mio::unix::UnixReady::error().into(),
+                mio::Token(token),
+            )))
+        } else {
+            None
+        }
+    }
+    fn check_deadline(&self, i: usize, kind: Deadline) -> Result<(), Error> {
+        match self.deadlines.get(&i) {
+            Some(deadlines) => deadlines.check(kind).map_err(|e| e.into()),
+            None => Ok(()),
+        }
+    }
+    fn touch(&self, i: usize) {
+        if let Some(deadlines) = self.deadlines.get(&i) {
+            deadlines.touch();
+        }
+    }
+    /// Limit the bytes read and written each second, the connections made
+    /// each second and the number of open sockets. Limits can be changed at
+    /// any time, also through the handle returned by rate_limiter.
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.limiter.set_limits(limits);
+    }
+    /// A handle to this instance's limits that can be used from other threads
+    pub fn rate_limiter(&self) -> RateLimiter {
+        self.limiter.clone()
+    }
+    /// Returns a WouldBlock error and wakes the guest when the budget for
+    /// socket i has refilled, so that the operation is retried rather than
+    /// dropped
+    fn throttle(&self, i: usize, wait: time::Duration) -> Error {
+        let es = self.event_sender.clone();
+        self.spawn(Delay::new(time::Instant::now() + wait).then(move |_| {
+            let ready = mio::Ready::readable() | mio::Ready::writable();
+            let _ = es.send(Response::Event(mio::Event::new(ready, mio::Token(i))).into());
+            future::ok(())
+        }));
+        std::io::Error::from_raw_os_error(libc::EAGAIN).into()
+    }
+    /// How long until the connection rate allows another dial, zero when it
+    /// already does
+    pub fn dial_wait(&self) -> time::Duration {
+        match self.limiter.reserve(Budget::Connections, 1) {
+            Ok(_) => time::Duration::from_secs(0),
+            Err(wait) => wait,
+        }
+    }
+    /// Checks the socket limit, and the connection rate for dialed and
+    /// accepted connections. A listener is woken once the rate allows
+    /// accepting again.
+    fn admit(&self, connection: bool, listener: Option<usize>) -> Result<(), Error> {
+        // ids past MAX_TOKEN would run into the next instance's tokens
+        let max = self
+            .limiter
+            .limits()
+            .max_sockets
+            .map_or(reactor::MAX_TOKEN, |max| max.min(reactor::MAX_TOKEN));
+        if self.slab.len() >= max {
+            return Err(std::io::Error::from_raw_os_error(libc::EMFILE).into());
+        }
+        if connection {
+            if let Err(wait) = self.limiter.reserve(Budget::Connections, 1) {
+                return Err(match listener {
+                    Some(i) => self.throttle(i, wait),
+                    None => std::io::Error::from_raw_os_error(libc::EAGAIN).into(),
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Set the CA bundles that dialed servers are verified against and the
+    /// certificate and key that TLS listeners present. The files are read
+    /// right away.
+    pub fn set_tls_config(&mut self, config: TlsConfig) -> Result<(), Error> {
+        self.tls = Some(Tls::new(&config)?);
+        self.http_client = None;
+        Ok(())
+    }
+    fn tls(&mut self) -> Result<&Tls, Error> {
+        if self.tls.is_none() {
+            self.tls = Some(Tls::new(&TlsConfig::default())?);
+        }
+        Ok(self.tls.as_ref().unwrap())
+    }
+    fn check_policy(
+        &mut self,
+        op: &'static str,
+        addr: &SocketAddr,
+        result: std::io::Result<()>,
+    ) -> Result<(), Error> {
+        if let Err(err) = result {
+            if let Some(audit) = &mut self.audit {
+                audit.denied(op, &addr.to_string(), &err.to_string());
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+    fn check_connect(&mut self, addr: &SocketAddr) -> Result<(), Error> {
+        let result = self.policy.check_connect(addr);
+        self.check_policy("connect", addr, result)
+    }
+    /// Refuses what a capture can't serve while replaying, so that it
+    /// doesn't reach the network instead
+    fn check_not_replaying(&self, what: &str) -> Result<(), Error> {
+        if self.replay.is_some() {
+            return Err(err_msg(format!(
+                "{} isn't available when replaying the network",
+                what
+            )));
+        }
+        Ok(())
+    }
+    /// Fails network operations once the capture couldn't be written to
+    fn check_capture(&self) -> Result<(), Error> {
+        match &self.capture {
+            Some(capture) => capture.check(),
+            None => Ok(()),
         }
     }
+    fn check_listen(&mut self, addr: &SocketAddr) -> Result<(), Error> {
+        let result = self.policy.check_listen(addr);
+        self.check_policy("listen", addr, result)
+    }
     pub fn is_active(&self) -> bool {
-        self.call_count > 0 || !self.slab.is_empty()
+        self.call_count > 0
+            || !self.slab.is_empty()
+            || self.watcher.as_ref().map_or(false, |w| !w.is_empty())
         // TODO: add networking activity
     }
     pub fn metadata_by_name(&mut self, id: i64, name: String) {
         self.call_count += 1;
+        let host_path = self.opened_path(&name);
+        if let Some(audit) = &mut self.audit {
+            audit.path_op(id, "stat", &name, host_path, None);
+        }
         let es = self.event_sender.clone();
-        self.runtime
-            .spawn(metadata(name).then(move |result| send_result(id, es, result)));
+        self.spawn_blocking(metadata(name).then(move |result| send_result(id, es, result)));
     }
     pub fn lookup_ip(&mut self, id: i64, addr: &str) {
         self.call_count += 1;
+        if let Some(replay) = &mut self.replay {
+            self.event_sender
+                .send(replay.lookup(id, None, addr).into())
+                .unwrap();
+            return;
+        }
+        if let Some(capture) = &mut self.capture {
+            if let Err(err) = capture.check() {
+                let msg = err.to_string();
+                let kind = std::io::ErrorKind::Other;
+                let _ = self
+                    .event_sender
+                    .send(Response::Error { msg, kind, id }.into());
+                return;
+            }
+            capture.lookup(id, None, addr);
+        }
+        let es = self.event_sender.clone();
+        let host = addr.to_string();
+        let lookup = Timeout::new(self.resolver.lookup_ip(addr), self.dns_timeout);
+        self.spawn(lookup.then(move |result| {
+            let _ = match result {
+                Err(err) => es.send(lookup_error(id, &host, err).into()),
+                Ok(ips) => es.send(Response::Ips { ips, host, id }.into()),
+            };
+            future::ok(())
+        }));
+    }
+    /// Look up TXT, MX, SRV, CNAME or PTR records. The answer is sent as
+    /// Response::Names, Response::Mx or Response::Srv.
+    pub fn lookup(&mut self, id: i64, host: &str, kind: RecordKind) {
+        self.call_count += 1;
+        if let Some(replay) = &mut self.replay {
+            self.event_sender
+                .send(replay.lookup(id, Some(kind), host).into())
+                .unwrap();
+            return;
+        }
+        if let Some(capture) = &mut self.capture {
+            if let Err(err) = capture.check() {
+                let msg = err.to_string();
+                let kind = std::io::ErrorKind::Other;
+                let _ = self
+                    .event_sender
+                    .send(Response::Error { msg, kind, id }.into());
+                return;
+            }
+            capture.lookup(id, Some(kind), host);
+        }
         let es = self.event_sender.clone();
-        self.runtime
-            .spawn(self.resolver.lookup_ip(addr).then(move |result| {
-                match result {
-                    Err(err) => es
-                        .send(Response::Error {
-                            msg: err.to_string(),
-                            kind: std::io::ErrorKind::NotFound,
-                            id,
-                        })
-                        .unwrap(),
-                    Ok(ips) => es.send(Response::Ips { ips, id }).unwrap(),
-                };
-                future::ok(())
-            }));
+        let host = host.to_string();
+        let lookup = Timeout::new(self.resolver.lookup(&host, kind), self.dns_timeout);
+        self.spawn(lookup.then(move |result| {
+            let resp = match result {
+                Err(err) => lookup_error(id, &host, err),
+                Ok(Records::Names(names)) => Response::Names { id, names },
+                Ok(Records::Mx(mx)) => Response::Mx { id, mx },
+                Ok(Records::Srv(srv)) => Response::Srv { id, srv },
+            };
+            let _ = es.send(resp.into());
+            future::ok(())
+        }));
+    }
+    fn http_client(&mut self) -> Result<HttpClient, Error> {
+        self.check_not_replaying("fetch")?;
+        if self.http_client.is_none() {
+            let client = http::client(http::Controls {
+                resolver: self.resolver.clone(),
+                policy: self.policy.clone(),
+                limiter: self.limiter.clone(),
+                timeouts: self.timeouts,
+                tls: self.tls.as_ref().map(Tls::client_config),
+                events: self.event_sender.clone(),
+            });
+            self.http_client = Some(client);
+        }
+        Ok(self.http_client.clone().unwrap())
+    }
+    /// Start an http request for the guest's fetch. FetchResponse::Head is
+    /// returned with id once the status and headers arrive, then the body is
+    /// read with fetch_read. The returned handle aborts the request with
+    /// abort_fetch until the head arrives.
+    pub fn fetch(&mut self, id: i64, request: FetchRequest) -> usize {
+        self.call_count += 1;
+        let es = self.event_sender.clone();
+        let abort = self.next_fetch;
+        self.next_fetch += 1;
+        let (client, request) = match (self.http_client(), request.build()) {
+            (Ok(client), Ok(request)) => (client, request),
+            (Err(err), _) => {
+                let msg = err.to_string();
+                let _ = es.send(Response::Fetch(FetchResponse::Failed { id, msg }).into());
+                return abort;
+            }
+            (_, Err(msg)) => {
+                let _ = es.send(Response::Fetch(FetchResponse::Failed { id, msg }).into());
+                return abort;
+            }
+        };
+        let (abort_sender, aborted) = oneshot::channel();
+        self.fetch_aborts.insert(abort, abort_sender);
+        // the client spawns its connections, so requests are made on the
+        // runtime
+        let response = future::lazy(move || client.request(request));
+        self.spawn(response.select2(aborted).then(move |result| {
+            let resp = match result {
+                Ok(Either::A((response, _))) => Message::FetchHead {
+                    id,
+                    abort,
+                    response,
+                },
+                Err(Either::A((err, _))) => Response::Fetch(FetchResponse::Failed {
+                    id,
+                    msg: err.to_string(),
+                })
+                .into(),
+                Ok(Either::B(_)) | Err(Either::B(_)) => {
+                    Response::Fetch(FetchResponse::Aborted { id }).into()
+                }
+            };
+            let _ = es.send(resp);
+            future::ok(())
+        }));
+        abort
+    }
+    fn fetch_head(&mut self, id: i64, response: hyper::Response<hyper::Body>) -> FetchResponse {
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.as_str().to_string(), value)
+            })
+            .collect();
+        let body = self.next_fetch;
+        self.next_fetch += 1;
+        self.fetch_bodies.insert(body, Some(response.into_body()));
+        FetchResponse::Head {
+            id,
+            status,
+            headers,
+            body,
+        }
+    }
+    /// Read the next part of a fetch body, returned as FetchResponse::Chunk
+    /// with id
+    pub fn fetch_read(&mut self, id: i64, body: usize) {
+        self.call_count += 1;
+        let es = self.event_sender.clone();
+        let stream = match self.fetch_bodies.get_mut(&body).and_then(Option::take) {
+            Some(stream) => stream,
+            None => {
+                let msg = "body is closed or already being read".to_string();
+                let _ = es.send(Response::Fetch(FetchResponse::Failed { id, msg }).into());
+                return;
+            }
+        };
+        self.spawn(stream.into_future().then(move |result| {
+            let (chunk, stream) = match result {
+                Ok((chunk, stream)) => (Ok(chunk.map(|chunk| chunk.to_vec())), stream),
+                Err((err, stream)) => (Err(err.to_string()), stream),
+            };
+            let _ = es.send(Message::FetchChunk {
+                id,
+                body,
+                stream,
+                chunk,
+            });
+            future::ok(())
+        }));
+    }
+    /// Drop the rest of a fetch body
+    pub fn fetch_cancel(&mut self, body: usize) {
+        self.fetch_bodies.remove(&body);
+    }
+    /// Abort a fetch that is waiting for its head. It returns
+    /// FetchResponse::Aborted.
+    pub fn abort_fetch(&mut self, abort: usize) {
+        if let Some(sender) = self.fetch_aborts.remove(&abort) {
+            let _ = sender.send(());
+        }
     }
     pub fn cwd(&self) -> &str {
         self.path.to_str().unwrap()
@@ -346,21 +1098,57 @@ impl IOLoop {
                 .unwrap(),
         )
     }
+    /// the host path that opening path as given resolves to, for the audit log
+    fn opened_path(&self, path: &str) -> PathBuf {
+        self.runtime_cwd.join(path).parse_dot().unwrap()
+    }
     pub fn fs_mkdir(&mut self, id: i64, path: String, _perms: i64) {
         let es = self.event_sender.clone();
+        let real_path = self.real_path(&path);
         self.call_count += 1;
-        self.runtime.spawn(
-            tokio::fs::create_dir(self.real_path(&path))
-                .then(move |result| send_result(id, es, result)),
+        if let Some(audit) = &mut self.audit {
+            audit.path_op(id, "mkdir", &path, real_path.clone(), None);
+        }
+        self.spawn_blocking(
+            tokio::fs::create_dir(real_path).then(move |result| send_result(id, es, result)),
         );
     }
-    pub fn fs_close(&mut self, fd: usize) {
-        self.files.remove(fd);
+    /// The file behind a guest fd, or EBADF if it isn't open
+    fn file(&self, fd: usize) -> Result<&std::fs::File, std::io::Error> {
+        fd.checked_sub(FIRST_FILE_FD)
+            .and_then(|i| self.files.get(i))
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::EBADF))
+    }
+    pub fn fs_close(&mut self, id: i64, fd: usize) {
+        let es = self.event_sender.clone();
+        self.call_count += 1;
+        // dropping the file closes it, which also releases any flock held
+        // through it
+        let result = match fd.checked_sub(FIRST_FILE_FD) {
+            Some(i) if self.files.contains(i) => {
+                self.files.remove(i);
+                Ok(())
+            }
+            _ => Err(std::io::Error::from_raw_os_error(libc::EBADF)),
+        };
+        if let Some(audit) = &mut self.audit {
+            audit.close(fd);
+        }
+        self.spawn(future::lazy(move || send_result(id, es, result)));
+    }
+    pub fn fs_flock(&mut self, id: i64, fd: usize, how: i64) {
+        let es = self.event_sender.clone();
+        self.call_count += 1;
+        if let Some(audit) = &mut self.audit {
+            audit.fd_op(id, "flock", fd);
+        }
+        let result = self.file(fd).and_then(|file| flock(file, how));
+        self.spawn(future::lazy(move || send_result(id, es, result)));
     }
     pub fn stderr(&mut self, id: i64, buf: Vec<u8>) {
         let es = self.event_sender.clone();
         self.call_count += 1;
-        self.runtime.spawn(
+        self.spawn_blocking(
             tokio::io::write_all(tokio::io::stderr(), buf)
                 .then(move |result| send_result(id, es, result)),
         );
@@ -368,33 +1156,52 @@ impl IOLoop {
     pub fn stdout(&mut self, id: i64, buf: Vec<u8>) {
         let es = self.event_sender.clone();
         self.call_count += 1;
-        self.runtime.spawn(
+        self.spawn_blocking(
             tokio::io::write_all(tokio::io::stdout(), buf)
                 .then(move |result| send_result(id, es, result)),
         );
     }
     pub fn fs_metadata(&mut self, id: i64, fd: usize) {
-        let f = self.files.get(fd).unwrap().try_clone().unwrap();
-        let tf = tokio::fs::File::from_std(f);
         let es = self.event_sender.clone();
         self.call_count += 1;
-        self.runtime.spawn(
+        if let Some(audit) = &mut self.audit {
+            audit.fd_op(id, "fstat", fd);
+        }
+        let tf = match self.file(fd).and_then(|f| f.try_clone()) {
+            Ok(f) => tokio::fs::File::from_std(f),
+            Err(err) => {
+                self.spawn(future::lazy(move || send_result(id, es, Err::<(), _>(err))));
+                return;
+            }
+        };
+        self.spawn_blocking(
             tf.metadata() // TODO: pass this value to the function. also race conditions?
                 .then(move |result| send_result(id, es, result)),
         );
     }
 
-    pub fn fs_write(&mut self, id: i64, fd: usize, buf: Vec<u8>) {
-        let f = self.files.get(fd).unwrap().try_clone().unwrap();
-        let tf = tokio::fs::File::from_std(f);
+    pub fn fs_write(&mut self, id: i64, fd: usize, buf: Vec<u8>, seek_from: SeekFrom) {
         let es = self.event_sender.clone();
         self.call_count += 1;
-        self.runtime.spawn(
-            tf.seek(SeekFrom::Start(0))
+        if let Some(audit) = &mut self.audit {
+            audit.fd_op(id, "write", fd);
+        }
+        let tf = match self.file(fd).and_then(|f| f.try_clone()) {
+            Ok(f) => tokio::fs::File::from_std(f),
+            Err(err) => {
+                self.spawn(future::lazy(move || send_result(id, es, Err::<(), _>(err))));
+                return;
+            }
+        };
+        self.spawn_blocking(
+            tf.seek(seek_from)
                 .and_then(|(tf, _)| tokio::io::write_all(tf, buf))
                 .then(move |result| send_result(id, es, result)),
         );
     }
+    /// Reads up to len bytes into a pooled buffer. The bytes are copied to
+    /// address by the embedder when it handles Response::Read, so the read
+    /// is never written into guest memory that may have moved since.
     pub fn fs_read(
         &mut self,
         id: i64,
@@ -403,35 +1210,103 @@ impl IOLoop {
         len: usize,
         seek_from: std::io::SeekFrom,
     ) {
-        let f = self.files.get(fd).unwrap().try_clone().unwrap();
-        let tf = tokio::fs::File::from_std(f);
         let es = self.event_sender.clone();
         self.call_count += 1;
-        self.runtime.spawn(
+        if let Some(audit) = &mut self.audit {
+            audit.fd_op(id, "read", fd);
+        }
+        let tf = match self.file(fd).and_then(|f| f.try_clone()) {
+            Ok(f) => tokio::fs::File::from_std(f),
+            Err(err) => {
+                self.spawn(future::lazy(move || send_result(id, es, Err::<(), _>(err))));
+                return;
+            }
+        };
+        let buf = self.buffers.take(len);
+        self.spawn_blocking(
             tf.seek(seek_from)
-                .and_then(move |(tf, _)| tokio::io::read(tf, vec![0; len]))
+                .and_then(move |(tf, _)| tokio::io::read(tf, buf))
                 .then(move |result| {
                     match result {
                         Err(err) => es
-                            .send(Response::Error {
-                                msg: err.to_string(),
-                                id,
-                                kind: err.kind(),
-                            })
+                            .send(
+                                Response::Error {
+                                    msg: err.to_string(),
+                                    id,
+                                    kind: err.kind(),
+                                }
+                                .into(),
+                            )
                             .unwrap(),
                         Ok((_file, buf, len)) => es
-                            .send(Response::Read {
-                                address,
-                                buf,
-                                id,
-                                len,
-                            })
+                            .send(
+                                Response::Read {
+                                    address,
+                                    buf,
+                                    id,
+                                    len,
+                                }
+                                .into(),
+                            )
                             .unwrap(),
                     };
                     future::ok(())
                 }),
         );
     }
+    /// Keep the buffer of a Response::Read for later reads
+    pub fn recycle(&self, buf: Vec<u8>) {
+        self.buffers.put(buf);
+    }
+    /// Watches path for created, modified and deleted files. The watch id is
+    /// returned to id and every change is delivered to callback.
+    pub fn watch(&mut self, id: i64, callback: i64, path: &str) {
+        self.call_count += 1;
+        let real_path = self.real_path(path);
+        let guest_path = self.resolve_path(path).to_string_lossy().into_owned();
+        let result = self
+            .watcher()
+            .and_then(|watcher| watcher.add(callback, guest_path, &real_path));
+        let resp = match result {
+            Ok(watch) => Response::Watch { id, watch },
+            Err(err) => Response::Error {
+                msg: err.to_string(),
+                id,
+                kind: err.kind(),
+            },
+        };
+        self.event_sender.send(resp.into()).unwrap();
+    }
+    pub fn unwatch(&mut self, watch: usize) -> Result<(), Error> {
+        match &mut self.watcher {
+            Some(watcher) => watcher.remove(watch).map_err(|e| e.into()),
+            None => Err(err_msg("watch not found")),
+        }
+    }
+    fn watcher(&mut self) -> Result<&mut Watcher, std::io::Error> {
+        if self.watcher.is_none() {
+            let watcher = Watcher::new()?;
+            self.poll.register(
+                &mio::unix::EventedFd(&watcher.fd()),
+                self.token(WATCH_TOKEN),
+                mio::Ready::readable(),
+                mio::PollOpt::edge(),
+            )?;
+            self.watcher = Some(watcher);
+        }
+        Ok(self.watcher.as_mut().unwrap())
+    }
+    /// true if a mio event is for filesystem watches rather than a socket,
+    /// read the changes with watch_events
+    pub fn is_watch_event(&self, event: &mio::Event) -> bool {
+        event.token().0 == WATCH_TOKEN
+    }
+    pub fn watch_events(&mut self) -> Result<Vec<WatchEvent>, Error> {
+        match &mut self.watcher {
+            Some(watcher) => watcher.read_events().map_err(|e| e.into()),
+            None => Ok(Vec::new()),
+        }
+    }
     fn open_options(openmode: i64) -> OpenOptions {
         let (read, write) = if openmode & O_RDWR > 0 {
             (true, true)
@@ -452,111 +1327,820 @@ impl IOLoop {
     pub fn fs_open(&mut self, id: i64, path: String, openmode: i64, _perm: i64) {
         let es = self.event_sender.clone();
         self.call_count += 1;
+        let host_path = self.opened_path(&path);
+        if let Some(audit) = &mut self.audit {
+            audit.path_op(id, "open", &path, host_path, Some(openmode));
+        }
 
         // TODO: set perms on returned file if we create
-        self.runtime.spawn(
+        self.spawn_blocking(
             Self::open_options(openmode)
                 .open(path)
                 .then(move |result| send_result(id, es, result)),
         );
     }
-    fn recv_wrapper(&mut self, r: Result<Response, Error>) -> Result<Response, Error> {
-        if let Ok(resp) = r {
+    /// Handles bookkeeping for a received response. Responses that are only
+    /// meant for the IOLoop return None.
+    fn recv_wrapper(&mut self, r: Result<Message, Error>) -> Result<Option<Response>, Error> {
+        if let Ok(message) = r {
+            let resp = match message {
+                Message::Events(events) => {
+                    self.events.extend(events);
+                    return Ok(None);
+                }
+                // the guest doesn't know about streams that are still
+                // waiting for their PROXY protocol header, so they're closed
+                // rather than failed once their header or idle timeout passes
+                Message::Deadline { token, timer } if self.proxied.is_held(token) => {
+                    let overdue = self.proxied.is_overdue(token, time::Instant::now());
+                    if overdue || self.expire_deadline(token, timer).is_some() {
+                        let _ = self.close_conn(token);
+                    }
+                    return Ok(None);
+                }
+                Message::Deadline { token, timer } => {
+                    return Ok(self.expire_deadline(token, timer));
+                }
+                Message::Response(Response::Event(event))
+                    if self.proxied.is_held(event.token().0) =>
+                {
+                    return Ok(self.read_proxy_header(event.token().0).map(|listener| {
+                        Response::Event(mio::Event::new(
+                            mio::Ready::readable(),
+                            mio::Token(listener),
+                        ))
+                    }));
+                }
+                Message::Denied { op, target, error } => {
+                    if let Some(audit) = &mut self.audit {
+                        audit.denied(op, &target, &error);
+                    }
+                    return Ok(None);
+                }
+                Message::FetchHead {
+                    id,
+                    abort,
+                    response,
+                } => {
+                    self.fetch_aborts.remove(&abort);
+                    Response::Fetch(self.fetch_head(id, response))
+                }
+                Message::FetchChunk {
+                    id,
+                    body,
+                    stream,
+                    chunk,
+                } => {
+                    let done = match &chunk {
+                        Ok(Some(_)) => false,
+                        _ => true,
+                    };
+                    if done {
+                        self.fetch_bodies.remove(&body);
+                    } else if let Some(slot) = self.fetch_bodies.get_mut(&body) {
+                        // unless the guest canceled the body during the read
+                        *slot = Some(stream);
+                    }
+                    Response::Fetch(match chunk {
+                        Ok(chunk) => FetchResponse::Chunk { id, chunk },
+                        Err(msg) => FetchResponse::Failed { id, msg },
+                    })
+                }
+                Message::Response(Response::Event(event)) => {
+                    let token = event.token().0;
+                    if let Some(tls) = self.tls_streams.get(&token) {
+                        // records that didn't fit the socket earlier. errors
+                        // are left for the guest's next read or write
+                        if let Ok(stream) = self.get_stream_ref(token) {
+                            let _ = tls.flush(stream);
+                        }
+                    }
+                    if event.readiness().is_writable() {
+                        // connected
+                        if let Some(deadlines) = self.deadlines.get_mut(&event.token().0) {
+                            deadlines.connect = None;
+                        }
+                    }
+                    Response::Event(event)
+                }
+                Message::Response(resp) => resp,
+            };
             if resp.id().is_some() {
                 self.call_count -= 1;
             }
             // TODO: this is likely excessive if fs_open is the only thing
             // opening or creating files. remove and put in fs_open if that's
             // the case
-            if let Response::File { id, file } = resp {
+            let resp = if let Response::File { id, file } = resp {
                 // keep the file and return a virtual fd and the id
-                Ok(Response::FileRef {
-                    fd: self.files.insert(file.into_std()),
+                Response::FileRef {
+                    fd: self.files.insert(file.into_std()) + FIRST_FILE_FD,
                     id,
-                })
+                }
             } else {
-                Ok(resp)
+                resp
+            };
+            if let Some(audit) = &mut self.audit {
+                audit.complete(&resp);
+            }
+            if let Some(capture) = &mut self.capture {
+                capture.complete(&resp);
             }
+            if let Response::Ips { host, ips, .. } = &resp {
+                self.policy.record_resolution(host, ips.iter().cloned());
+            }
+            Ok(Some(resp))
         } else {
-            r
+            r.map(|_| None)
         }
     }
     pub fn try_recv(&mut self) -> Result<Response, Error> {
-        self.recv_wrapper(self.event_receiver.try_recv().map_err(|e| e.into()))
+        loop {
+            let r = match self.events.pop_front() {
+                Some(event) => Ok(Response::Event(event).into()),
+                None => self.event_receiver.try_recv().map_err(|e| e.into()),
+            };
+            if let Some(resp) = self.recv_wrapper(r)? {
+                return Ok(resp);
+            }
+        }
     }
     pub fn recv(&mut self) -> Result<Response, Error> {
-        self.recv_wrapper(self.event_receiver.recv().map_err(|e| e.into()))
+        loop {
+            let r = match self.events.pop_front() {
+                Some(event) => Ok(Response::Event(event).into()),
+                None => self.event_receiver.recv().map_err(|e| e.into()),
+            };
+            if let Some(resp) = self.recv_wrapper(r)? {
+                return Ok(resp);
+            }
+        }
     }
     pub fn recv_timeout(&mut self, timeout: time::Duration) -> Result<Response, Error> {
-        self.recv_wrapper(
-            self.event_receiver
-                .recv_timeout(timeout)
-                .map_err(|e| e.into()),
-        )
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let now = time::Instant::now();
+            let timeout = if deadline > now {
+                deadline - now
+            } else {
+                time::Duration::from_secs(0)
+            };
+            let r = match self.events.pop_front() {
+                Some(event) => Ok(Response::Event(event).into()),
+                None => self
+                    .event_receiver
+                    .recv_timeout(timeout)
+                    .map_err(|e| e.into()),
+            };
+            if let Some(resp) = self.recv_wrapper(r)? {
+                return Ok(resp);
+            }
+        }
     }
     pub fn tcp_listen(&mut self, addr: &SocketAddr) -> Result<usize, Error> {
-        let listener = TcpListener::bind(addr)?;
-        let id = self.slab.insert(Tcp::Listener(listener));
+        self.check_listen(addr)?;
+        self.check_capture()?;
+        self.admit(false, None)?;
+        if let Some(replay) = &mut self.replay {
+            let listener = replay.listen(addr)?;
+            let id = self.register_virtual_listener(listener)?;
+            if let Some(replay) = &mut self.replay {
+                replay.listening(id, *addr);
+            }
+            return Ok(id);
+        }
+        let host_addr = self.port_map.host_addr(addr);
+        let inherited = match self.inherited.take(addr) {
+            Some(inherited) => Some(inherited),
+            None => self.inherited.take(&host_addr),
+        };
+        let (name, listener) = match inherited {
+            Some((name, listener)) => (name, TcpListener::from_std(listener)?),
+            None => (addr.to_string(), TcpListener::bind(&host_addr)?),
+        };
+        let fd = listener.as_raw_fd();
+        let id = self.slab.insert(Socket::Listener(listener));
+        self.listeners.insert(id, name, fd);
         self.poll.register(
             self.get_listener_ref(id)?,
-            mio::Token(id),
+            self.token(id),
             mio::Ready::readable() | mio::Ready::writable(),
             // https://carllerche.github.io/mio/mio/struct.Poll.html#edge-triggered-and-level-triggered
             mio::PollOpt::edge(),
         )?;
+        if host_addr != *addr {
+            let host_addr = self.get_listener_ref(id)?.local_addr()?;
+            self.record_mapping(id, *addr, host_addr);
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.listen(id, *addr);
+        }
 
         Ok(id)
     }
     pub fn tcp_connect(&mut self, addr: &SocketAddr) -> Result<usize, Error> {
+        self.check_connect(addr)?;
+        self.check_capture()?;
+        self.admit(true, None)?;
+        if let Some(replay) = &mut self.replay {
+            let (stream, playback) = replay.connect(addr)?;
+            self.limiter.charge(Budget::Connections, 1);
+            let id = self.register_virtual_stream(stream)?;
+            if let Some(replay) = &mut self.replay {
+                replay.play(id, playback);
+            }
+            return Ok(id);
+        }
         let stream = TcpStream::connect(addr)?;
+        self.limiter.charge(Budget::Connections, 1);
 
-        self.register_stream(stream)
-    }
-    fn register_stream(&mut self, stream: TcpStream) -> Result<usize, Error> {
-        let id = self.slab.insert(Tcp::Stream(stream));
-        self.poll.register(
-            self.get_stream_ref(id)?,
-            mio::Token(id),
-            mio::Ready::readable() | mio::Ready::writable(),
-            mio::PollOpt::edge(),
-        )?;
+        let id = self.register_stream(stream)?;
+        if self.capture.is_some() {
+            let local = self.local_addr(id)?;
+            if let Some(capture) = &mut self.capture {
+                capture.connect(id, local, *addr);
+            }
+        }
+        if let Some(timeout) = self.timeouts.connect {
+            let at = time::Instant::now() + timeout;
+            self.deadlines
+                .entry(id)
+                .or_insert_with(|| Deadlines::new(None))
+                .connect = Some(at);
+            self.schedule_deadline(id, at, Timer::Connect);
+        }
+        Ok(id)
+    }
+    /// connects to addr and does a TLS handshake with server_name, which is
+    /// also the name the server's certificate is verified for. Reads and
+    /// writes on the stream are plaintext.
+    pub fn tls_connect(&mut self, addr: &SocketAddr, server_name: &str) -> Result<usize, Error> {
+        if self.replay.is_some() {
+            // captures hold the plaintext
+            return self.tcp_connect(addr);
+        }
+        let session = self.tls()?.client(server_name)?;
+        let id = self.tcp_connect(addr)?;
+        self.tls_streams.insert(id, session);
+        Ok(id)
+    }
+    /// listens on addr for TLS connections, which needs the certificate and
+    /// key from set_tls_config
+    pub fn tls_listen(&mut self, addr: &SocketAddr) -> Result<usize, Error> {
+        if self.replay.is_some() {
+            return self.tcp_listen(addr);
+        }
+        if !self.tls()?.can_serve() {
+            return Err(err_msg("no tls certificate configured"));
+        }
+        let id = self.tcp_listen(addr)?;
+        self.tls_listeners.insert(id);
+        Ok(id)
+    }
+    fn register_stream(&mut self, stream: TcpStream) -> Result<usize, Error> {
+        let id = self.slab.insert(Socket::Stream(stream));
+        self.poll.register(
+            self.get_stream_ref(id)?,
+            self.token(id),
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+        if self.timeouts.idle.is_some() {
+            let deadlines = Deadlines::new(self.timeouts.idle);
+            let at = deadlines.idle_deadline().unwrap();
+            self.deadlines.insert(id, deadlines);
+            self.schedule_deadline(id, at, Timer::Idle);
+        }
         Ok(id)
     }
     pub fn tcp_accept(&mut self, id: usize) -> Result<usize, Error> {
-        let (stream, _) = self.get_listener_ref(id)?.accept()?;
-        self.register_stream(stream)
+        self.check_capture()?;
+        self.admit(true, Some(id))?;
+        if let Some(replay) = &mut self.replay {
+            let listener = match self.slab.get(id) {
+                Some(Socket::VirtualListener(listener)) => listener,
+                _ => return Err(err_msg("Network object not found in slab")),
+            };
+            let (stream, playback) = replay.accept(listener)?;
+            self.limiter.charge(Budget::Connections, 1);
+            let stream_id = self.register_virtual_stream(stream)?;
+            if let Some(replay) = &mut self.replay {
+                replay.play(stream_id, playback);
+            }
+            return Ok(stream_id);
+        }
+        if self.proxied.expects(id) {
+            return self.accept_proxied(id);
+        }
+        let (stream, peer) = self.get_listener_ref(id)?.accept()?;
+        self.limiter.charge(Budget::Connections, 1);
+        let session = if self.tls_listeners.contains(&id) {
+            Some(self.tls()?.server()?)
+        } else {
+            None
+        };
+        let stream_id = self.register_stream(stream)?;
+        self.accepted(id, stream_id, peer, session)
+    }
+    /// sets up a stream accepted from listener id for the guest
+    fn accepted(
+        &mut self,
+        id: usize,
+        stream_id: usize,
+        peer: SocketAddr,
+        session: Option<TlsSession>,
+    ) -> Result<usize, Error> {
+        let guest_port = self.mappings.get(&id).map(|m| m.guest.port());
+        if let Some(session) = session {
+            self.tls_streams.insert(stream_id, session);
+        }
+        if let Some(port) = guest_port {
+            self.guest_ports.insert(stream_id, port);
+        }
+        if self.capture.is_some() {
+            let local = self.local_addr(stream_id)?;
+            if let Some(capture) = &mut self.capture {
+                capture.accept(stream_id, id, local, peer);
+            }
+        }
+        Ok(stream_id)
+    }
+    /// Read a PROXY protocol v1 or v2 header from every stream that listener
+    /// accepts, before the guest gets the stream. The client address in the
+    /// header is then the stream's peer address. Streams with a malformed
+    /// header, or none, are closed.
+    pub fn set_proxy_protocol(&mut self, listener: usize, enabled: bool) -> Result<(), Error> {
+        // replayed streams were recorded after their header was read
+        if self.replay.is_some() {
+            return Ok(());
+        }
+        self.get_listener_ref(listener)?;
+        self.proxied.set(listener, enabled);
+        Ok(())
+    }
+    /// accepts streams from listener id until one has its PROXY protocol
+    /// header. The others are held until theirs arrives.
+    fn accept_proxied(&mut self, id: usize) -> Result<usize, Error> {
+        loop {
+            if let Some((stream_id, peer)) = self.proxied.accept(id) {
+                let session = if self.tls_listeners.contains(&id) {
+                    match self.tls().and_then(|tls| tls.server()) {
+                        Ok(session) => Some(session),
+                        Err(err) => {
+                            self.close_conn(stream_id)?;
+                            return Err(err);
+                        }
+                    }
+                } else {
+                    None
+                };
+                return self.accepted(id, stream_id, peer, session);
+            }
+            self.admit(true, Some(id))?;
+            let (stream, peer) = self.get_listener_ref(id)?.accept()?;
+            self.limiter.charge(Budget::Connections, 1);
+            let stream_id = self.register_stream(stream)?;
+            // closed if the header doesn't arrive in time, even when there
+            // are no idle timeouts
+            let deadline = time::Instant::now() + proxy::HEADER_TIMEOUT;
+            self.proxied.hold(stream_id, id, peer, deadline);
+            self.schedule_deadline(stream_id, deadline, Timer::Header);
+            self.read_proxy_header(stream_id);
+        }
+    }
+    /// reads the PROXY protocol header of held stream i if all of it has
+    /// arrived, and returns the listener that can now hand the stream to the
+    /// guest. Streams with a malformed header are closed.
+    fn read_proxy_header(&mut self, i: usize) -> Option<usize> {
+        if !self.proxied.is_pending(i) {
+            return None;
+        }
+        let result = self.get_stream_ref(i).and_then(proxy::read_header);
+        match result {
+            Ok(Parsed::Header { source, .. }) => self.proxied.ready(i, source),
+            Ok(Parsed::Incomplete(_)) => None,
+            Err(_) => {
+                let _ = self.close_conn(i);
+                None
+            }
+        }
+    }
+    pub fn udp_bind(&mut self, addr: &SocketAddr) -> Result<usize, Error> {
+        self.check_not_replaying("udp")?;
+        self.check_listen(addr)?;
+        self.admit(false, None)?;
+        let host_addr = self.port_map.host_addr(addr);
+        let socket = UdpSocket::bind(&host_addr)?;
+        let id = self.register_udp(socket, None)?;
+        if host_addr != *addr {
+            let host_addr = self.get_udp_ref(id)?.local_addr()?;
+            self.record_mapping(id, *addr, host_addr);
+        }
+        Ok(id)
+    }
+    /// binds a udp socket to an ephemeral port and connects it to addr
+    pub fn udp_connect(&mut self, addr: &SocketAddr) -> Result<usize, Error> {
+        self.check_not_replaying("udp")?;
+        self.check_connect(addr)?;
+        self.admit(false, None)?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(&local)?;
+        socket.connect(*addr)?;
+        self.register_udp(socket, Some(*addr))
+    }
+    fn register_udp(
+        &mut self,
+        socket: UdpSocket,
+        peer: Option<SocketAddr>,
+    ) -> Result<usize, Error> {
+        let id = self.slab.insert(Socket::Udp(socket, peer));
+        self.poll.register(
+            self.get_udp_ref(id)?,
+            self.token(id),
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+        Ok(id)
+    }
+    pub fn udp_send_to(&mut self, i: usize, b: &[u8], addr: &SocketAddr) -> Result<usize, Error> {
+        self.check_connect(addr)?;
+        // datagrams are sent whole, even if that overdraws the budget
+        if let Err(wait) = self.limiter.reserve(Budget::Write, b.len()) {
+            return Err(self.throttle(i, wait));
+        }
+        let n = self.get_udp_ref(i)?.send_to(b, addr)?;
+        self.limiter.charge(Budget::Write, n);
+        Ok(n)
+    }
+    pub fn udp_recv_from(&self, i: usize, b: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        if let Err(wait) = self.limiter.reserve(Budget::Read, b.len()) {
+            return Err(self.throttle(i, wait));
+        }
+        let (n, addr) = self.get_udp_ref(i)?.recv_from(b)?;
+        self.limiter.charge(Budget::Read, n);
+        Ok((n, addr))
+    }
+    /// listens on a unix socket at path, which is resolved inside the sandbox
+    pub fn unix_listen(&mut self, path: &str) -> Result<usize, Error> {
+        self.check_not_replaying("unix sockets")?;
+        self.admit(false, None)?;
+        let real_path = self.real_path(path);
+        let listener = UnixListener::bind(&real_path)?;
+        let id = self.slab.insert(Socket::UnixListener(listener, real_path));
+        self.poll.register(
+            self.get_unix_listener_ref(id)?,
+            self.token(id),
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+        Ok(id)
+    }
+    /// connects to a unix socket at path, which is resolved inside the
+    /// sandbox
+    pub fn unix_connect(&mut self, path: &str) -> Result<usize, Error> {
+        self.check_not_replaying("unix sockets")?;
+        self.admit(true, None)?;
+        let stream = UnixStream::connect(self.real_path(path))?;
+        self.limiter.charge(Budget::Connections, 1);
+        self.register_unix_stream(stream)
+    }
+    fn register_unix_stream(&mut self, stream: UnixStream) -> Result<usize, Error> {
+        let id = self.slab.insert(Socket::UnixStream(stream));
+        self.poll.register(
+            self.get_unix_stream_ref(id)?,
+            self.token(id),
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+        Ok(id)
+    }
+    pub fn unix_accept(&mut self, id: usize) -> Result<usize, Error> {
+        self.admit(true, Some(id))?;
+        match self.get_unix_listener_ref(id)?.accept()? {
+            Some((stream, _)) => {
+                self.limiter.charge(Budget::Connections, 1);
+                self.register_unix_stream(stream)
+            }
+            // mio_uds reports WouldBlock as None, turn it back into the
+            // error that tcp_accept returns
+            None => Err(std::io::Error::from_raw_os_error(libc::EAGAIN).into()),
+        }
+    }
+    /// Join a virtual network shared with other IOLoops. Each IOLoop starts
+    /// on a network of its own.
+    pub fn set_virtual_network(&mut self, network: VirtualNetwork) {
+        self.network = network;
+    }
+    /// listens on name in the virtual network
+    pub fn virtual_listen(&mut self, name: &str) -> Result<usize, Error> {
+        self.check_not_replaying("virtual endpoints")?;
+        self.admit(false, None)?;
+        let listener = self.network.listen(name)?;
+        self.register_virtual_listener(listener)
+    }
+    fn register_virtual_listener(&mut self, listener: VirtualListener) -> Result<usize, Error> {
+        let id = self.slab.insert(Socket::VirtualListener(listener));
+        self.poll.register(
+            self.get_virtual_listener_ref(id)?,
+            self.token(id),
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+        Ok(id)
+    }
+    /// connects to name in the virtual network. Fails with ECONNREFUSED when
+    /// nothing is listening on it.
+    pub fn virtual_connect(&mut self, name: &str) -> Result<usize, Error> {
+        self.check_not_replaying("virtual endpoints")?;
+        self.admit(true, None)?;
+        let stream = self.network.connect(name)?;
+        self.limiter.charge(Budget::Connections, 1);
+        self.register_virtual_stream(stream)
+    }
+    fn register_virtual_stream(&mut self, stream: VirtualStream) -> Result<usize, Error> {
+        let id = self.slab.insert(Socket::VirtualStream(stream));
+        self.poll.register(
+            self.get_virtual_stream_ref(id)?,
+            self.token(id),
+            mio::Ready::readable() | mio::Ready::writable(),
+            mio::PollOpt::edge(),
+        )?;
+        Ok(id)
+    }
+    pub fn virtual_accept(&mut self, id: usize) -> Result<usize, Error> {
+        self.admit(true, Some(id))?;
+        let stream = self.get_virtual_listener_ref(id)?.accept()?;
+        self.limiter.charge(Budget::Connections, 1);
+        self.register_virtual_stream(stream)
     }
     pub fn get_error(&mut self, id: usize) -> Result<Option<std::io::Error>, Error> {
+        if let Some(err) = self.deadlines.get(&id).and_then(|d| d.error()) {
+            return Ok(Some(err));
+        }
         match self.slab_get(id)? {
-            Tcp::Listener(listener) => listener.take_error().map_err(|e| e.into()),
-            Tcp::Stream(stream) => stream.take_error().map_err(|e| e.into()),
+            Socket::Listener(listener) => listener.take_error().map_err(|e| e.into()),
+            Socket::Stream(stream) => stream.take_error().map_err(|e| e.into()),
+            Socket::Udp(socket, _) => socket.take_error().map_err(|e| e.into()),
+            Socket::UnixListener(listener, _) => listener.take_error().map_err(|e| e.into()),
+            Socket::UnixStream(stream) => stream.take_error().map_err(|e| e.into()),
+            Socket::VirtualListener(_) | Socket::VirtualStream(_) => Ok(None),
         }
     }
     pub fn local_addr(&self, i: usize) -> Result<SocketAddr, Error> {
+        if let Some(addr) = self.replay.as_ref().and_then(|r| r.local_addr(i)) {
+            return Ok(addr);
+        }
+        if let Some(mapping) = self.mappings.get(&i) {
+            return Ok(mapping.guest);
+        }
+        let mut addr = self.host_local_addr(i)?;
+        if let Some(port) = self.guest_ports.get(&i) {
+            addr.set_port(*port);
+        }
+        Ok(addr)
+    }
+    fn host_local_addr(&self, i: usize) -> Result<SocketAddr, Error> {
         match self.slab_get(i)? {
-            Tcp::Listener(listener) => listener.local_addr().map_err(|e| e.into()),
-            Tcp::Stream(stream) => stream.local_addr().map_err(|e| e.into()),
+            Socket::Listener(listener) => listener.local_addr().map_err(|e| e.into()),
+            Socket::Stream(stream) => stream.local_addr().map_err(|e| e.into()),
+            Socket::Udp(socket, _) => socket.local_addr().map_err(|e| e.into()),
+            Socket::UnixListener(..) | Socket::UnixStream(_) => {
+                Err(err_msg("Unix sockets don't have an inet address"))
+            }
+            Socket::VirtualListener(_) | Socket::VirtualStream(_) => {
+                Err(err_msg("Virtual sockets don't have an inet address"))
+            }
         }
     }
     pub fn peer_addr(&self, i: usize) -> Result<SocketAddr, Error> {
-        self.get_stream_ref(i)?.peer_addr().map_err(|e| e.into())
+        if let Some(addr) = self.replay.as_ref().and_then(|r| r.peer_addr(i)) {
+            return Ok(addr);
+        }
+        if let Some(addr) = self.proxied.peer_addr(i) {
+            return Ok(addr);
+        }
+        match self.slab_get(i)? {
+            Socket::Udp(_, Some(peer)) => Ok(*peer),
+            Socket::Udp(_, None) => Err(err_msg("Udp socket is not connected")),
+            _ => self.get_stream_ref(i)?.peer_addr().map_err(|e| e.into()),
+        }
     }
+    /// reads from a tcp or unix stream, or receives a datagram on a
+    /// connected udp socket
     pub fn read_stream(&self, i: usize, b: &mut [u8]) -> Result<usize, Error> {
-        self.get_stream_ref(i)?.read(b).map_err(|e| e.into())
+        self.check_deadline(i, Deadline::Read)?;
+        self.check_capture()?;
+        let len = match self.limiter.reserve(Budget::Read, b.len()) {
+            Ok(len) => len,
+            Err(wait) => return Err(self.throttle(i, wait)),
+        };
+        let n = match self.slab_get(i)? {
+            // a datagram that doesn't fit the buffer is truncated, so udp
+            // reads get the whole buffer
+            Socket::Udp(socket, _) => socket.recv(b)?,
+            Socket::UnixStream(_) => self.get_unix_stream_ref(i)?.read(&mut b[..len])?,
+            Socket::VirtualStream(stream) => {
+                let result = stream.read(&mut b[..len]);
+                self.pump(i);
+                result?
+            }
+            _ => {
+                let mut stream = self.get_stream_ref(i)?;
+                match self.tls_streams.get(&i) {
+                    Some(tls) => tls.read(stream, &mut b[..len])?,
+                    None => stream.read(&mut b[..len])?,
+                }
+            }
+        };
+        self.limiter.charge(Budget::Read, n);
+        if n > 0 {
+            self.touch(i);
+        }
+        if let Some(capture) = &self.capture {
+            if !b.is_empty() {
+                capture.read(i, &b[..n]);
+            }
+        }
+        Ok(n)
     }
     pub fn shutdown(&mut self, i: usize, how: Shutdown) -> Result<(), Error> {
-        self.get_stream_ref(i)?.shutdown(how).map_err(|e| e.into())
+        match self.slab_get(i)? {
+            Socket::UnixStream(stream) => stream.shutdown(how).map_err(|e| e.into()),
+            Socket::VirtualStream(stream) => stream.shutdown(how).map_err(|e| e.into()),
+            _ => {
+                let stream = self.get_stream_ref(i)?;
+                match self.tls_streams.get(&i) {
+                    Some(tls) if how != Shutdown::Read => tls.close_notify(stream)?,
+                    _ => {}
+                }
+                stream.shutdown(how).map_err(|e| e.into())
+            }
+        }
     }
+    /// writes to a tcp or unix stream, or sends a datagram on a connected udp
+    /// socket
     pub fn write_stream(&self, i: usize, b: &[u8]) -> Result<usize, Error> {
-        self.get_stream_ref(i)?.write(b).map_err(|e| e.into())
+        self.check_deadline(i, Deadline::Write)?;
+        self.check_capture()?;
+        let len = match self.limiter.reserve(Budget::Write, b.len()) {
+            Ok(len) => len,
+            Err(wait) => return Err(self.throttle(i, wait)),
+        };
+        let n = match self.slab_get(i)? {
+            Socket::Udp(socket, _) => socket.send(b)?,
+            Socket::UnixStream(_) => self.get_unix_stream_ref(i)?.write(&b[..len])?,
+            Socket::VirtualStream(stream) => {
+                let result = stream.write(&b[..len]);
+                self.pump(i);
+                result?
+            }
+            _ => {
+                let mut stream = self.get_stream_ref(i)?;
+                match self.tls_streams.get(&i) {
+                    Some(tls) => tls.write(stream, &b[..len])?,
+                    None => stream.write(&b[..len])?,
+                }
+            }
+        };
+        self.limiter.charge(Budget::Write, n);
+        if n > 0 {
+            self.touch(i);
+        }
+        if let Some(capture) = &self.capture {
+            if n > 0 {
+                capture.write(i, &b[..n]);
+            }
+        }
+        Ok(n)
+    }
+    /// Send up to len bytes of file fd, starting at offset, to stream i
+    /// without passing them through the guest. Plain tcp streams are written
+    /// with sendfile(2), other streams through a host buffer so that TLS and
+    /// captures see the bytes. Like write_stream, fewer bytes than len may be
+    /// sent, and WouldBlock is returned until the stream is writable. 0 is
+    /// returned at the end of the file.
+    pub fn send_file(
+        &mut self,
+        i: usize,
+        fd: usize,
+        offset: u64,
+        len: usize,
+    ) -> Result<usize, Error> {
+        let result = self.transfer_file(i, fd, offset, len);
+        if let Some(audit) = &mut self.audit {
+            match &result {
+                Ok(0) => {}
+                Ok(n) => audit.fd_done("sendfile", fd, Ok(*n)),
+                Err(err) => {
+                    let blocked = err
+                        .downcast_ref::<std::io::Error>()
+                        .map_or(false, |err| err.kind() == std::io::ErrorKind::WouldBlock);
+                    if !blocked {
+                        audit.fd_done("sendfile", fd, Err(err.to_string()));
+                    }
+                }
+            }
+        }
+        result
+    }
+    fn transfer_file(&self, i: usize, fd: usize, offset: u64, len: usize) -> Result<usize, Error> {
+        let file = self.file(fd)?;
+        let plain = match self.slab_get(i)? {
+            Socket::Stream(_) => !self.tls_streams.contains_key(&i) && self.capture.is_none(),
+            _ => false,
+        };
+        if !plain {
+            let mut buf = self.buffers.take(len.min(SEND_FILE_BUFFER));
+            // bytes that don't fit the stream are read again by the next call
+            let result = match file.read_at(&mut buf, offset) {
+                Ok(0) => Ok(0),
+                Ok(n) => self.write_stream(i, &buf[..n]),
+                Err(err) => Err(err.into()),
+            };
+            self.buffers.put(buf);
+            return result;
+        }
+        self.check_deadline(i, Deadline::Write)?;
+        let len = match self.limiter.reserve(Budget::Write, len) {
+            Ok(len) => len,
+            Err(wait) => return Err(self.throttle(i, wait)),
+        };
+        let stream = self.get_stream_ref(i)?;
+        let mut offset = match libc::off_t::try_from(offset) {
+            Ok(offset) => offset,
+            Err(_) => return Err(std::io::Error::from_raw_os_error(libc::EINVAL).into()),
+        };
+        let n = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, len) };
+        if n == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let n = n as usize;
+        self.limiter.charge(Budget::Write, n);
+        if n > 0 {
+            self.touch(i);
+        }
+        Ok(n)
+    }
+    /// sets an option on a tcp stream, capped by the instance's limits
+    pub fn set_sockopt(&self, i: usize, opt: SockOpt) -> Result<(), Error> {
+        if self.replay.as_ref().map_or(false, |r| r.is_replayed(i)) {
+            // there's no socket to set it on
+            return Ok(());
+        }
+        let stream = self.get_stream_ref(i)?;
+        match opt.limit(&self.sockopt_limits) {
+            SockOpt::NoDelay(nodelay) => stream.set_nodelay(nodelay)?,
+            SockOpt::KeepAlive(keepalive) => stream.set_keepalive(keepalive)?,
+            SockOpt::Linger(linger) => stream.set_linger(linger)?,
+            SockOpt::RecvBuffer(size) => stream.set_recv_buffer_size(size)?,
+            SockOpt::SendBuffer(size) => stream.set_send_buffer_size(size)?,
+        };
+        Ok(())
     }
     pub fn close_conn(&mut self, i: usize) -> Result<(), Error> {
+        self.listeners.remove(i);
+        self.mappings.remove(&i);
+        self.guest_ports.remove(&i);
+        self.deadlines.remove(&i);
+        self.tls_listeners.remove(&i);
+        for stream in self.proxied.close(i) {
+            self.close_conn(stream)?;
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.close(i);
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.close(i);
+        }
+        if let Some(tls) = self.tls_streams.remove(&i) {
+            if let Ok(stream) = self.get_stream_ref(i) {
+                let _ = tls.close_notify(stream);
+            }
+        }
         if self.slab.contains(i) {
-            self.slab.remove(i); // value is dropped and connection is closed
+            // value is dropped and connection is closed
+            if let Socket::UnixListener(_, path) = self.slab.remove(i) {
+                fs::remove_file(path)?;
+            }
         };
         Ok(())
     }
-    fn slab_get(&self, i: usize) -> Result<&Tcp, Error> {
+    /// plays the recorded side of a replayed stream after the guest's end
+    /// was read from or written to
+    fn pump(&self, i: usize) {
+        if let Some(replay) = &self.replay {
+            replay.pump(i);
+        }
+    }
+    /// the token socket i is registered with on the reactor
+    fn token(&self, i: usize) -> mio::Token {
+        reactor::token(self.instance, i)
+    }
+    fn slab_get(&self, i: usize) -> Result<&Socket, Error> {
         match self.slab.get(i) {
             Some(ntcp) => Ok(ntcp),
             None => Err(err_msg("Network object not found in slab")),
@@ -564,13 +2148,43 @@ impl IOLoop {
     }
     fn get_listener_ref(&self, i: usize) -> Result<&TcpListener, Error> {
         match self.slab_get(i)? {
-            Tcp::Listener(listener) => Ok(listener),
+            Socket::Listener(listener) => Ok(listener),
             _ => Err(err_msg("Network object not found in slab")),
         }
     }
     fn get_stream_ref(&self, i: usize) -> Result<&TcpStream, Error> {
         match self.slab_get(i)? {
-            Tcp::Stream(s) => Ok(s),
+            Socket::Stream(s) => Ok(s),
+            _ => Err(err_msg("Network object not found in slab")),
+        }
+    }
+    fn get_udp_ref(&self, i: usize) -> Result<&UdpSocket, Error> {
+        match self.slab_get(i)? {
+            Socket::Udp(s, _) => Ok(s),
+            _ => Err(err_msg("Network object not found in slab")),
+        }
+    }
+    fn get_unix_listener_ref(&self, i: usize) -> Result<&UnixListener, Error> {
+        match self.slab_get(i)? {
+            Socket::UnixListener(listener, _) => Ok(listener),
+            _ => Err(err_msg("Network object not found in slab")),
+        }
+    }
+    fn get_unix_stream_ref(&self, i: usize) -> Result<&UnixStream, Error> {
+        match self.slab_get(i)? {
+            Socket::UnixStream(s) => Ok(s),
+            _ => Err(err_msg("Network object not found in slab")),
+        }
+    }
+    fn get_virtual_listener_ref(&self, i: usize) -> Result<&VirtualListener, Error> {
+        match self.slab_get(i)? {
+            Socket::VirtualListener(listener) => Ok(listener),
+            _ => Err(err_msg("Network object not found in slab")),
+        }
+    }
+    fn get_virtual_stream_ref(&self, i: usize) -> Result<&VirtualStream, Error> {
+        match self.slab_get(i)? {
+            Socket::VirtualStream(s) => Ok(s),
             _ => Err(err_msg("Network object not found in slab")),
         }
     }
@@ -580,6 +2194,7 @@ impl IOLoop {
 mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
     use tempfile::Builder;
 
     pub fn as_u16_le(array: &[u8]) -> u16 {
@@ -608,7 +2223,7 @@ mod tests {
             panic!("Wrong type returned");
         };
 
-        nl.fs_write(0, fd, "Hello".as_bytes().to_vec());
+        nl.fs_write(0, fd, "Hello".as_bytes().to_vec(), SeekFrom::Start(0));
         nl.recv().unwrap();
 
         let cb_id = 20;
@@ -617,89 +2232,773 @@ mod tests {
             assert_eq!(len, "Hello".len());
             assert_eq!(&buf[..len], "Hello".as_bytes());
             assert_eq!(cb_id, id);
+            let address = buf.as_ptr();
+            nl.recycle(buf);
+
+            // the next read gets the same buffer back
+            nl.fs_read(cb_id, fd, 0, 100, std::io::SeekFrom::Start(1));
+            if let Response::Read { buf, len, .. } = nl.recv().unwrap() {
+                assert_eq!(&buf[..len], "ello".as_bytes());
+                assert_eq!(buf.as_ptr(), address);
+            } else {
+                panic!("Wrong type returned");
+            };
         } else {
             panic!("Wrong type returned");
         };
     }
 
     #[test]
-    fn realpath() {
+    fn file_fds_skip_stdio() {
         let mut nl = IOLoop::new();
-        nl.chdir("/foo");
-        let mut cd = current_dir().unwrap();
-        cd.push("foo");
-        assert_eq!(nl.real_path("."), cd);
+        let files: Vec<_> = (0..3)
+            .map(|_| Builder::new().tempfile_in(nl.real_path(".")).unwrap())
+            .collect();
+        let mut fds = Vec::new();
+        for file in &files {
+            let path = file.path().file_name().unwrap().to_str().unwrap();
+            nl.fs_open(1, path.to_string(), O_RDWR, 0);
+            if let Response::FileRef { fd, .. } = nl.recv().unwrap() {
+                assert!(fd >= FIRST_FILE_FD);
+                fds.push(fd);
+            } else {
+                panic!("Wrong type returned");
+            };
+        }
+
+        // the third file would have had fd 2, stderr
+        nl.fs_write(2, fds[2], "Hello".as_bytes().to_vec(), SeekFrom::Start(0));
+        if let Response::Written { len, .. } = nl.recv().unwrap() {
+            assert_eq!(len, 5);
+        } else {
+            panic!("Wrong type returned");
+        };
+        assert_eq!(fs::read(files[2].path()).unwrap(), "Hello".as_bytes());
+
+        let unopened = fds[2] + 1;
+        nl.fs_write(4, unopened, "Hello".as_bytes().to_vec(), SeekFrom::Start(0));
+        if let Response::Error { id, msg, .. } = nl.recv().unwrap() {
+            assert_eq!(id, 4);
+            assert_eq!(
+                msg,
+                std::io::Error::from_raw_os_error(libc::EBADF).to_string()
+            );
+        } else {
+            panic!("Wrong type returned");
+        };
+        nl.fs_read(5, 1, 0, 100, SeekFrom::Start(0));
+        if let Response::Error { id, .. } = nl.recv().unwrap() {
+            assert_eq!(id, 5);
+        } else {
+            panic!("Wrong type returned");
+        };
     }
 
     #[test]
-    fn stdio() {
+    fn large_file_offsets() {
         let mut nl = IOLoop::new();
-        // This is still not guaranteed to run sequentially even with a single
-        // thread in the runtime. Not meaningfully checking callback ids for
-        // now
+        let file = Builder::new().tempfile_in(nl.real_path(".")).unwrap();
+        // sparse, so this doesn't actually take up 5GB of disk
+        let size: u64 = 5 * 1024 * 1024 * 1024;
+        file.as_file().set_len(size).unwrap();
+        let path = file
+            .path()
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
 
-        nl.stdout(0, "Hello stdout\n".as_bytes().to_vec());
-        nl.stderr(0, "Hello stderr\n".as_bytes().to_vec());
-        println!("I should be first");
-        // should confirm printing happened
+        nl.fs_open(1, path, O_RDWR, 0);
+        let fd = if let Response::FileRef { fd, .. } = nl.recv().unwrap() {
+            fd
+        } else {
+            panic!("Wrong type returned");
+        };
 
-        if let Response::Written { id, .. } = nl.recv().unwrap() {
-            assert_eq!(0, id);
+        let offset = size - 10;
+        nl.fs_write(2, fd, "Hello".as_bytes().to_vec(), SeekFrom::Start(offset));
+        if let Response::Written { len, .. } = nl.recv().unwrap() {
+            assert_eq!(len, 5);
         } else {
             panic!("Wrong type returned");
         };
-        if let Response::Written { id, .. } = nl.recv().unwrap() {
-            assert_eq!(0, id);
+
+        nl.fs_read(3, fd, 0, 100, SeekFrom::Start(offset));
+        if let Response::Read { buf, len, .. } = nl.recv().unwrap() {
+            assert_eq!(len, 10);
+            assert_eq!(&buf[..5], "Hello".as_bytes());
         } else {
             panic!("Wrong type returned");
         };
-    }
 
-    #[test]
-    fn ensure_chroot() {
-        let mut nl = IOLoop::new();
-        assert_eq!("/", nl.cwd());
-        nl.chdir("../../../");
-        assert_eq!("/", nl.cwd());
+        nl.fs_metadata(4, fd);
+        if let Response::Metadata { md, .. } = nl.recv().unwrap() {
+            assert_eq!(md.len(), size);
+        } else {
+            panic!("Wrong type returned");
+        };
     }
 
     #[test]
-    fn lookup_ip_localhost() {
-        let mut nl = IOLoop::new();
-        nl.lookup_ip(0, "localhost");
-        if let Response::Ips { ips, id } = nl.recv().unwrap() {
-            assert_eq!(id, 0);
-            if let IpAddr::V4(ip) = ips.iter().next().unwrap() {
-                assert_eq!(ip, Ipv4Addr::new(127, 0, 0, 1));
+    fn flock_released_on_close() {
+        let file = Builder::new().tempfile().unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+
+        // two instances locking the same file
+        let mut first = IOLoop::new();
+        let mut second = IOLoop::new();
+        let open = |nl: &mut IOLoop| {
+            nl.fs_open(0, path.clone(), O_RDWR, 0);
+            if let Response::FileRef { fd, .. } = nl.recv().unwrap() {
+                fd
             } else {
-                panic!("ipv4 expected");
+                panic!("Wrong type returned");
             }
-        } else {
-            panic!("expected Response::Event");
-        }
-    }
+        };
+        let first_fd = open(&mut first);
+        let second_fd = open(&mut second);
 
-    #[test]
-    fn test_addr_to_bytes() {
-        let mut mem = vec![0u8; 6];
-        addr_to_bytes("1.2.3.4:100".parse().unwrap(), &mut mem).unwrap();
-        assert_eq!(mem, [1, 2, 3, 4, 100, 0]);
+        first.fs_flock(1, first_fd, LOCK_EX);
+        if let Response::Success { .. } = first.recv().unwrap() {
+        } else {
+            panic!("Wrong type returned");
+        };
+        second.fs_flock(1, second_fd, LOCK_SH);
+        if let Response::Error { kind, .. } = second.recv().unwrap() {
+            assert_eq!(kind, std::io::ErrorKind::WouldBlock);
+        } else {
+            panic!("expected the lock to be held");
+        };
 
-        let mut mem = vec![0u8; 6];
-        addr_to_bytes("127.0.0.1:34254".parse().unwrap(), &mut mem).unwrap();
-        assert_eq!(as_u16_le(&mem[4..6]), 34254u16);
+        first.fs_close(2, first_fd);
+        first.recv().unwrap();
+        second.fs_flock(1, second_fd, LOCK_SH);
+        if let Response::Success { .. } = second.recv().unwrap() {
+        } else {
+            panic!("expected the lock to be released");
+        };
     }
 
     #[test]
-    fn listen_connect_read_write() {
+    fn watch_directory() {
         let mut nl = IOLoop::new();
-        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
-        let conn = nl
-            .tcp_connect(&nl.get_listener_ref(listener).unwrap().local_addr().unwrap())
-            .unwrap();
+        let dir = Builder::new().tempdir_in(nl.real_path(".")).unwrap();
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
 
-        let to_write = [0, 1, 2, 3, 4, 5, 6, 7, 8];
-        loop {
+        nl.watch(1, 42, name);
+        let watch = if let Response::Watch { id, watch } = nl.recv().unwrap() {
+            assert_eq!(id, 1);
+            watch
+        } else {
+            panic!("Wrong type returned");
+        };
+        assert!(nl.is_active());
+
+        fs::write(dir.path().join("config"), "reload").unwrap();
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                if nl.is_watch_event(&event) {
+                    break;
+                }
+            }
+        }
+        let events = nl.watch_events().unwrap();
+        assert_eq!(
+            events[0],
+            WatchEvent {
+                callback: 42,
+                kind: WatchKind::Create,
+                path: format!("/{}/config", name),
+            }
+        );
+
+        nl.unwatch(watch).unwrap();
+        assert!(!nl.is_active());
+    }
+
+    #[test]
+    fn watch_path_twice() {
+        let mut nl = IOLoop::new();
+        let dir = Builder::new().tempdir_in(nl.real_path(".")).unwrap();
+        let name = dir.path().file_name().unwrap().to_str().unwrap();
+        let mut watches = vec![];
+        for callback in 1..3 {
+            nl.watch(callback, callback, name);
+            if let Response::Watch { watch, .. } = nl.recv().unwrap() {
+                watches.push(watch);
+            }
+        }
+        assert_ne!(watches[0], watches[1]);
+        let changed = |nl: &mut IOLoop, file: &str| {
+            fs::write(dir.path().join(file), "").unwrap();
+            loop {
+                if let Response::Event(event) = nl.recv().unwrap() {
+                    if nl.is_watch_event(&event) {
+                        break;
+                    }
+                }
+            }
+            let mut callbacks: Vec<i64> = nl
+                .watch_events()
+                .unwrap()
+                .iter()
+                .map(|event| event.callback)
+                .collect();
+            callbacks.sort();
+            callbacks.dedup();
+            callbacks
+        };
+        assert_eq!(changed(&mut nl, "a"), vec![1, 2]);
+
+        // the other watch keeps getting events
+        nl.unwatch(watches[0]).unwrap();
+        assert!(nl.is_active());
+        assert_eq!(changed(&mut nl, "b"), vec![2]);
+        nl.unwatch(watches[1]).unwrap();
+        assert!(!nl.is_active());
+    }
+
+    #[test]
+    fn audit_log() {
+        let mut nl = IOLoop::new();
+        let log = Builder::new().tempfile().unwrap();
+        nl.enable_audit_log(Box::new(log.reopen().unwrap()));
+
+        let file = Builder::new().tempfile_in(nl.real_path(".")).unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+        nl.fs_open(1, path.clone(), O_RDWR, 0);
+        let fd = if let Response::FileRef { fd, .. } = nl.recv().unwrap() {
+            fd
+        } else {
+            panic!("Wrong type returned");
+        };
+        nl.fs_write(2, fd, "Hello".as_bytes().to_vec(), SeekFrom::Start(0));
+        nl.recv().unwrap();
+        nl.fs_mkdir(3, String::from("/does/not/exist"), 0);
+        nl.recv().unwrap();
+        nl.metadata_by_name(4, String::from("src/../Cargo.toml"));
+        nl.recv().unwrap();
+
+        let contents = fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("\"op\":\"open\""));
+        assert!(lines[0].contains(&format!("\"guest_path\":\"{}\"", path)));
+        assert!(lines[0].contains(&format!("\"mode\":{}", O_RDWR)));
+        assert!(lines[1].contains("\"op\":\"write\""));
+        assert!(lines[1].contains("\"bytes\":5"));
+        assert!(lines[2].contains("\"op\":\"mkdir\""));
+        assert!(lines[2].contains("\"result\":\"error\""));
+        let host_path = current_dir().unwrap().join("Cargo.toml");
+        assert!(lines[3].contains(&format!("\"host_path\":\"{}\"", host_path.display())));
+    }
+
+    #[test]
+    fn policy_denies_before_creating_sockets() {
+        let mut nl = IOLoop::new();
+        let log = Builder::new().tempfile().unwrap();
+        nl.enable_audit_log(Box::new(log.reopen().unwrap()));
+        let mut policy = Policy::default();
+        policy.deny_egress("127.0.0.0/8").unwrap();
+        policy.allow_listen("0").unwrap();
+        nl.set_policy(policy);
+
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = nl.local_addr(listener).unwrap();
+        let err = nl.tcp_connect(&addr).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            std::io::Error::from_raw_os_error(libc::ECONNREFUSED).to_string()
+        );
+        assert!(nl.udp_bind(&"127.0.0.1:53".parse().unwrap()).is_err());
+        assert_eq!(nl.slab.len(), 1);
+
+        let contents = fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"op\":\"connect\""));
+        assert!(lines[0].contains(&format!("\"target\":\"{}\"", addr)));
+        assert!(lines[1].contains("\"op\":\"listen\""));
+        assert!(lines[1].contains("\"result\":\"denied\""));
+    }
+
+    #[test]
+    fn realpath() {
+        let mut nl = IOLoop::new();
+        nl.chdir("/foo");
+        let mut cd = current_dir().unwrap();
+        cd.push("foo");
+        assert_eq!(nl.real_path("."), cd);
+    }
+
+    #[test]
+    fn stdio() {
+        let mut nl = IOLoop::new();
+        // This is still not guaranteed to run sequentially even with a single
+        // thread in the runtime. Not meaningfully checking callback ids for
+        // now
+
+        nl.stdout(0, "Hello stdout\n".as_bytes().to_vec());
+        nl.stderr(0, "Hello stderr\n".as_bytes().to_vec());
+        println!("I should be first");
+        // should confirm printing happened
+
+        if let Response::Written { id, .. } = nl.recv().unwrap() {
+            assert_eq!(0, id);
+        } else {
+            panic!("Wrong type returned");
+        };
+        if let Response::Written { id, .. } = nl.recv().unwrap() {
+            assert_eq!(0, id);
+        } else {
+            panic!("Wrong type returned");
+        };
+    }
+
+    #[test]
+    fn ensure_chroot() {
+        let mut nl = IOLoop::new();
+        assert_eq!("/", nl.cwd());
+        nl.chdir("../../../");
+        assert_eq!("/", nl.cwd());
+    }
+
+    #[test]
+    fn lookup_ip_localhost() {
+        let mut nl = IOLoop::new();
+        nl.lookup_ip(0, "localhost");
+        if let Response::Ips { ips, id, .. } = nl.recv().unwrap() {
+            assert_eq!(id, 0);
+            if let IpAddr::V4(ip) = ips[0] {
+                assert_eq!(ip, Ipv4Addr::new(127, 0, 0, 1));
+            } else {
+                panic!("ipv4 expected");
+            }
+        } else {
+            panic!("expected Response::Event");
+        }
+    }
+
+    #[test]
+    fn lookup_ip_timeout() {
+        // an upstream that never answers
+        let blackhole = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = DnsConfig::default();
+        config
+            .add_upstream(&blackhole.local_addr().unwrap().to_string())
+            .unwrap();
+        let mut nl = IOLoop::new();
+        nl.set_dns_config(config).unwrap();
+        nl.set_dns_timeout(time::Duration::from_millis(50));
+        nl.lookup_ip(0, "example.com");
+        match nl.recv().unwrap() {
+            Response::Error { id, kind, .. } => {
+                assert_eq!(id, 0);
+                assert_eq!(kind, std::io::ErrorKind::TimedOut);
+            }
+            resp => panic!("expected a timeout, got {:?}", resp),
+        }
+    }
+
+    #[test]
+    fn lookup_ptr_static_host() {
+        let mut nl = IOLoop::new();
+        let mut config = DnsConfig::default();
+        config.add_host("db.internal=10.0.0.5").unwrap();
+        nl.set_dns_config(config).unwrap();
+        nl.lookup(0, "10.0.0.5", RecordKind::Ptr);
+        match nl.recv().unwrap() {
+            Response::Names { id, names } => {
+                assert_eq!(id, 0);
+                assert_eq!(names, vec!["db.internal.".to_string()]);
+            }
+            resp => panic!("expected names, got {:?}", resp),
+        }
+    }
+
+    #[test]
+    fn test_addr_to_bytes() {
+        let mut mem = vec![0u8; ADDR_LEN];
+        addr_to_bytes("1.2.3.4:100".parse().unwrap(), &mut mem).unwrap();
+        assert_eq!(mem[0], ADDR_V4);
+        assert_eq!(mem[1..5], [1u8, 2, 3, 4]);
+        assert_eq!(mem[17..19], [100u8, 0]);
+
+        let mut mem = vec![0u8; ADDR_LEN];
+        addr_to_bytes("127.0.0.1:34254".parse().unwrap(), &mut mem).unwrap();
+        assert_eq!(as_u16_le(&mem[17..19]), 34254u16);
+
+        let mut mem = vec![0u8; ADDR_LEN];
+        addr_to_bytes("[2001:db8::1]:8080".parse().unwrap(), &mut mem).unwrap();
+        assert_eq!(mem[0], ADDR_V6);
+        assert_eq!(
+            mem[1..17],
+            [0x20u8, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+        assert_eq!(as_u16_le(&mem[17..19]), 8080u16);
+
+        let mut mem = vec![0u8; 6];
+        assert!(addr_to_bytes("1.2.3.4:100".parse().unwrap(), &mut mem).is_err());
+    }
+
+    #[test]
+    fn test_bytes_to_addr() {
+        for addr in &["1.2.3.4:100", "[2001:db8::1]:8080"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut mem = vec![0u8; ADDR_LEN];
+            addr_to_bytes(addr, &mut mem).unwrap();
+            assert_eq!(bytes_to_addr(&mem).unwrap(), addr);
+        }
+        assert!(bytes_to_addr(&[0u8; ADDR_LEN]).is_err());
+    }
+
+    #[test]
+    fn unix_listen_connect() {
+        let mut nl = IOLoop::new();
+        let dir = Builder::new().tempdir_in(nl.real_path(".")).unwrap();
+        let path = format!("{}/sock", dir.path().file_name().unwrap().to_str().unwrap());
+        let listener = nl.unix_listen(&path).unwrap();
+        assert!(dir.path().join("sock").exists());
+        let conn = nl.unix_connect(&path).unwrap();
+
+        let mut accepted = None;
+        let mut buf = [0u8; 16];
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                let (token, state) = event_to_ints(&event);
+                if token as usize == listener && state & 1 != 0 {
+                    let id = nl.unix_accept(listener).unwrap();
+                    nl.write_stream(conn, b"hello").unwrap();
+                    accepted = Some(id);
+                }
+                if accepted == Some(token as usize) && state & 1 != 0 {
+                    let n = nl.read_stream(token as usize, &mut buf).unwrap();
+                    assert_eq!(&buf[..n], b"hello");
+                    break;
+                }
+            }
+        }
+        nl.close_conn(listener).unwrap();
+        assert!(!dir.path().join("sock").exists());
+    }
+
+    #[test]
+    fn virtual_network_between_loops() {
+        let network = VirtualNetwork::new();
+        let mut server = IOLoop::new();
+        let mut client = IOLoop::new();
+        server.set_virtual_network(network.clone());
+        client.set_virtual_network(network.clone());
+        // each loop starts on its own network
+        assert!(IOLoop::new().virtual_connect("db").is_err());
+
+        let listener = server.virtual_listen("db").unwrap();
+        assert!(client.virtual_listen("db").is_err());
+        let conn = client.virtual_connect("db").unwrap();
+        client.write_stream(conn, b"hello").unwrap();
+
+        let mut accepted = None;
+        let mut buf = [0u8; 16];
+        loop {
+            if let Response::Event(event) = server.recv().unwrap() {
+                let (token, state) = event_to_ints(&event);
+                if token as usize == listener && state & 1 != 0 {
+                    accepted = Some(server.virtual_accept(listener).unwrap());
+                }
+                if let Some(id) = accepted {
+                    if let Ok(n) = server.read_stream(id, &mut buf) {
+                        assert_eq!(&buf[..n], b"hello");
+                        break;
+                    }
+                }
+            }
+        }
+        let accepted = accepted.unwrap();
+        server.write_stream(accepted, b"bye").unwrap();
+        server.close_conn(accepted).unwrap();
+        loop {
+            if let Response::Event(_) = client.recv().unwrap() {
+                if let Ok(n) = client.read_stream(conn, &mut buf) {
+                    assert_eq!(&buf[..n], b"bye");
+                    break;
+                }
+            }
+        }
+        // closed by the server
+        assert_eq!(client.read_stream(conn, &mut buf).unwrap(), 0);
+
+        server.close_conn(listener).unwrap();
+        assert!(network.endpoints().is_empty());
+    }
+
+    // writes ping once the stream is connected and reads until EOF
+    fn ping(nl: &mut IOLoop, conn: usize) -> Vec<u8> {
+        let mut written = false;
+        let mut received = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            if !written {
+                written = nl.write_stream(conn, b"ping").is_ok();
+            }
+            while written {
+                match nl.read_stream(conn, &mut buf) {
+                    Ok(0) => return received,
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                    Err(_) => break,
+                }
+            }
+            nl.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn record_and_replay_network() {
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut conn, _) = server.accept().unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).unwrap();
+            conn.write_all(b"pong").unwrap();
+        });
+
+        let capture = Builder::new().tempfile().unwrap();
+        let mut nl = IOLoop::new();
+        nl.record_network(Box::new(capture.reopen().unwrap()));
+        nl.lookup_ip(0, "localhost");
+        nl.recv().unwrap();
+        let conn = nl.tcp_connect(&addr).unwrap();
+        assert_eq!(ping(&mut nl, conn), b"pong");
+        nl.close_conn(conn).unwrap();
+
+        let contents = fs::read_to_string(capture.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].starts_with("lookup ip localhost 127.0.0.1"));
+        assert!(lines[1].starts_with("connect 1 127.0.0.1:"));
+        assert!(lines[1].ends_with(&format!(" {}", addr)));
+        assert_eq!(
+            &lines[2..],
+            &["write 1 70696e67", "read 1 706f6e67", "eof 1", "close 1"]
+        );
+
+        // nothing is listening anymore, so this only works from the capture
+        let mut nl = IOLoop::new();
+        nl.replay_network(&contents).unwrap();
+        nl.lookup_ip(0, "localhost");
+        match nl.recv().unwrap() {
+            Response::Ips { ips, .. } => assert_eq!(ips[0], IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            _ => panic!("expected Response::Ips"),
+        }
+        nl.lookup_ip(1, "example.com");
+        match nl.recv().unwrap() {
+            Response::Error { kind, .. } => assert_eq!(kind, std::io::ErrorKind::NotFound),
+            _ => panic!("expected Response::Error"),
+        }
+        let conn = nl.tcp_connect(&addr).unwrap();
+        assert_eq!(nl.peer_addr(conn).unwrap(), addr);
+        assert_eq!(ping(&mut nl, conn), b"pong");
+        assert!(nl.tcp_connect(&addr).is_err());
+        nl.close_conn(conn).unwrap();
+        assert!(!nl.is_active());
+        // only tcp and lookups are recorded
+        assert!(nl.udp_bind(&"127.0.0.1:0".parse().unwrap()).is_err());
+        assert!(nl.virtual_connect("db").is_err());
+    }
+
+    #[test]
+    fn udp_send_recv() {
+        let mut nl = IOLoop::new();
+        let server = nl.udp_bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = nl.local_addr(server).unwrap();
+        let client = nl.udp_connect(&server_addr).unwrap();
+        assert_eq!(nl.peer_addr(client).unwrap(), server_addr);
+        assert!(nl.peer_addr(server).is_err());
+
+        let mut sent = false;
+        let mut buf = [0u8; 16];
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                let (token, state) = event_to_ints(&event);
+                if token as usize == client && state & 2 != 0 && !sent {
+                    assert_eq!(nl.write_stream(client, b"ping").unwrap(), 4);
+                    sent = true;
+                }
+                if token as usize == server && state & 1 != 0 && sent {
+                    let (n, from) = nl.udp_recv_from(server, &mut buf).unwrap();
+                    assert_eq!(&buf[..n], b"ping");
+                    assert_eq!(from, nl.local_addr(client).unwrap());
+                    nl.udp_send_to(server, b"pong", &from).unwrap();
+                }
+                if token as usize == client && state & 1 != 0 {
+                    let n = nl.read_stream(client, &mut buf).unwrap();
+                    assert_eq!(&buf[..n], b"pong");
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn listen_connect_ipv6() {
+        let mut nl = IOLoop::new();
+        let listener = match nl.tcp_listen(&"[::1]:0".parse().unwrap()) {
+            Ok(listener) => listener,
+            // no ipv6 on this host
+            Err(_) => return,
+        };
+        let addr = nl.local_addr(listener).unwrap();
+        assert!(addr.is_ipv6());
+        let conn = nl.tcp_connect(&addr).unwrap();
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                if event.token().0 == listener && event.readiness().is_readable() {
+                    let accepted = nl.tcp_accept(listener).unwrap();
+                    assert_eq!(
+                        nl.peer_addr(accepted).unwrap(),
+                        nl.local_addr(conn).unwrap()
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn port_map_shares_guest_ports() {
+        // hold the guest port on the host so that binding it directly fails
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let guest = taken.local_addr().unwrap();
+        let mut port_map = PortMap::default();
+        port_map.set_ephemeral(true);
+
+        let (sender, receiver) = mpsc::channel();
+        let mut loops = vec![];
+        for _ in 0..2 {
+            let mut nl = IOLoop::new();
+            nl.set_port_map(port_map.clone());
+            nl.notify_port_mappings(sender.clone());
+            let listener = nl.tcp_listen(&guest).unwrap();
+            assert_eq!(nl.local_addr(listener).unwrap(), guest);
+            loops.push((nl, listener));
+        }
+        let (nl, listener) = &mut loops[0];
+        let mapping = receiver.recv().unwrap();
+        assert_eq!(mapping.guest, guest);
+        assert_ne!(mapping.host.port(), guest.port());
+        assert_eq!(nl.port_mappings(), vec![mapping]);
+
+        let _conn = std::net::TcpStream::connect(mapping.host).unwrap();
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                if event.token().0 == *listener && event.readiness().is_readable() {
+                    let accepted = nl.tcp_accept(*listener).unwrap();
+                    assert_eq!(nl.local_addr(accepted).unwrap().port(), guest.port());
+                    break;
+                }
+            }
+        }
+        nl.close_conn(*listener).unwrap();
+        assert!(nl.port_mappings().is_empty());
+    }
+
+    #[test]
+    fn proxy_protocol() {
+        let mut nl = IOLoop::new();
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        nl.set_proxy_protocol(listener, true).unwrap();
+        let addr = nl.local_addr(listener).unwrap();
+
+        let mut malformed = std::net::TcpStream::connect(addr).unwrap();
+        malformed.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut proxied = std::net::TcpStream::connect(addr).unwrap();
+        // the header arrives in two parts
+        proxied.write_all(b"PROXY TCP4 192.0.2.1 ").unwrap();
+        std::thread::sleep(time::Duration::from_millis(20));
+        proxied
+            .write_all(b"198.51.100.1 56324 443\r\nhello")
+            .unwrap();
+
+        let accepted = loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                assert!(!nl.proxied.is_held(event.token().0));
+                if event.token().0 == listener {
+                    if let Ok(accepted) = nl.tcp_accept(listener) {
+                        break accepted;
+                    }
+                }
+            }
+        };
+        assert_eq!(
+            nl.peer_addr(accepted).unwrap(),
+            "192.0.2.1:56324".parse().unwrap()
+        );
+        let mut buf = [0; 5];
+        loop {
+            match nl.read_stream(accepted, &mut buf) {
+                Ok(n) => {
+                    assert_eq!(&buf[..n], b"hello");
+                    break;
+                }
+                Err(_) => {
+                    nl.recv().unwrap();
+                }
+            }
+        }
+        // closed without reaching the guest
+        malformed
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(malformed.read(&mut buf).unwrap_or(0), 0);
+        assert!(nl.tcp_accept(listener).is_err());
+    }
+
+    #[test]
+    fn inherits_listeners() {
+        use std::os::unix::io::IntoRawFd;
+        // binding the address again would fail while this is open
+        let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = inherited.local_addr().unwrap();
+        let mut fds = ListenFds::default();
+        fds.add(&format!("web={}", inherited.into_raw_fd()))
+            .unwrap();
+        let mut nl = IOLoop::new();
+        nl.inherit_listeners(&fds).unwrap();
+        // passed on before the guest listens on it too
+        let passed = nl.listener_set().duplicate().unwrap();
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].0, "web");
+
+        let listener = nl.tcp_listen(&addr).unwrap();
+        assert_eq!(nl.local_addr(listener).unwrap(), addr);
+        let passed = nl.listener_set().duplicate().unwrap();
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].0, "web");
+        assert_eq!(passed[0].1.local_addr().unwrap(), addr);
+
+        let _conn = std::net::TcpStream::connect(addr).unwrap();
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                if event.token().0 == listener && event.readiness().is_readable() {
+                    nl.tcp_accept(listener).unwrap();
+                    break;
+                }
+            }
+        }
+        nl.close_conn(listener).unwrap();
+        assert!(nl.listener_set().duplicate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn listen_connect_read_write() {
+        let mut nl = IOLoop::new();
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = nl
+            .tcp_connect(&nl.get_listener_ref(listener).unwrap().local_addr().unwrap())
+            .unwrap();
+
+        let to_write = [0, 1, 2, 3, 4, 5, 6, 7, 8];
+        loop {
             if let Response::Event(event) = nl.recv().unwrap() {
                 if event.token().0 == conn && event.readiness().is_writable() {
                     nl.write_stream(event.token().0, &to_write).unwrap();
@@ -719,4 +3018,300 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn send_file_range() {
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let received = thread::spawn(move || {
+            let (mut conn, _) = server.accept().unwrap();
+            let mut received = Vec::new();
+            conn.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut nl = IOLoop::new();
+        let file = Builder::new().tempfile_in(nl.real_path(".")).unwrap();
+        fs::write(file.path(), "Hello, world!").unwrap();
+        let path = file.path().file_name().unwrap().to_str().unwrap();
+        nl.fs_open(1, path.to_string(), 0, 0);
+        let fd = if let Response::FileRef { fd, .. } = nl.recv().unwrap() {
+            fd
+        } else {
+            panic!("Wrong type returned");
+        };
+
+        let conn = nl.tcp_connect(&addr).unwrap();
+        let mut sent = 0;
+        while sent < 5 {
+            match nl.send_file(conn, fd, 7 + sent as u64, 5 - sent) {
+                Ok(n) => sent += n,
+                // not connected yet
+                Err(_) => {
+                    nl.recv().unwrap();
+                }
+            }
+        }
+        // the end of the file
+        assert_eq!(nl.send_file(conn, fd, 13, 5).unwrap(), 0);
+        // an offset that doesn't fit off_t
+        let err = nl.send_file(conn, fd, u64::max_value(), 5).unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        nl.close_conn(conn).unwrap();
+        assert_eq!(received.join().unwrap(), b"world");
+    }
+
+    #[test]
+    fn set_sockopt_limits() {
+        let mut nl = IOLoop::new();
+        nl.set_sockopt_limits(SockOptLimits {
+            max_buffer: Some(8192),
+            max_linger: None,
+        });
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = nl
+            .tcp_connect(&nl.get_listener_ref(listener).unwrap().local_addr().unwrap())
+            .unwrap();
+
+        nl.set_sockopt(conn, SockOpt::NoDelay(true)).unwrap();
+        assert!(nl.get_stream_ref(conn).unwrap().nodelay().unwrap());
+        nl.set_sockopt(conn, SockOpt::RecvBuffer(1 << 20)).unwrap();
+        // linux doubles the requested size for bookkeeping
+        let size = nl.get_stream_ref(conn).unwrap().recv_buffer_size().unwrap();
+        assert!(size <= 2 * 8192);
+        assert!(nl.set_sockopt(listener, SockOpt::NoDelay(true)).is_err());
+    }
+
+    #[test]
+    fn idle_timeout_fails_stream() {
+        let mut nl = IOLoop::new();
+        nl.set_timeouts(Timeouts {
+            connect: None,
+            idle: Some(time::Duration::from_millis(50)),
+        });
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = nl
+            .tcp_connect(&nl.get_listener_ref(listener).unwrap().local_addr().unwrap())
+            .unwrap();
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                let (token, state) = event_to_ints(&event);
+                if token as usize == conn && state & 1 << 3 != 0 {
+                    break;
+                }
+            }
+        }
+        let err = nl.get_error(conn).unwrap().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn read_deadline() {
+        let mut nl = IOLoop::new();
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = nl
+            .tcp_connect(&nl.get_listener_ref(listener).unwrap().local_addr().unwrap())
+            .unwrap();
+        let at = time::Instant::now() + time::Duration::from_millis(20);
+        nl.set_deadline(conn, Deadline::Read, Some(at)).unwrap();
+        // moving it later doesn't add another timer
+        nl.set_deadline(
+            conn,
+            Deadline::Read,
+            Some(at + time::Duration::from_millis(20)),
+        )
+        .unwrap();
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                let (token, state) = event_to_ints(&event);
+                // the guest's reads are woken without failing the socket
+                if token as usize == conn && state & 1 != 0 {
+                    assert_eq!(state & 1 << 3, 0);
+                    break;
+                }
+            }
+        }
+        let mut buf = [0u8; 4];
+        let err = nl.read_stream(conn, &mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "read timed out");
+        assert!(nl.write_stream(conn, b"ok").is_ok());
+        assert!(nl.get_error(conn).unwrap().is_none());
+        // moving the deadline makes the stream readable again
+        nl.set_deadline(conn, Deadline::Read, None).unwrap();
+        assert!(nl.check_deadline(conn, Deadline::Read).is_ok());
+    }
+
+    #[test]
+    fn rate_limits() {
+        let mut nl = IOLoop::new();
+        nl.set_rate_limits(RateLimits {
+            write_bytes_per_sec: Some(100),
+            max_sockets: Some(2),
+            ..RateLimits::default()
+        });
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = nl
+            .tcp_connect(&nl.get_listener_ref(listener).unwrap().local_addr().unwrap())
+            .unwrap();
+        let err = nl.udp_bind(&"127.0.0.1:0".parse().unwrap()).unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+
+        loop {
+            match nl.write_stream(conn, &[0u8; 1000]) {
+                Ok(n) => assert_eq!(n, 100),
+                Err(err) => {
+                    let err = err.downcast::<std::io::Error>().unwrap();
+                    assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+                    break;
+                }
+            }
+        }
+        // the stream is woken once the budget refills
+        loop {
+            if let Response::Event(event) = nl.recv().unwrap() {
+                let (token, state) = event_to_ints(&event);
+                if token as usize == conn && state & 1 << 1 != 0 {
+                    if let Ok(n) = nl.write_stream(conn, &[0u8; 1000]) {
+                        assert!(n > 0 && n <= 100);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dial_wait() {
+        let mut nl = IOLoop::new();
+        nl.set_rate_limits(RateLimits {
+            connections_per_sec: Some(1),
+            ..RateLimits::default()
+        });
+        assert_eq!(nl.dial_wait(), time::Duration::from_secs(0));
+        let listener = nl.tcp_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = nl.get_listener_ref(listener).unwrap().local_addr().unwrap();
+        nl.tcp_connect(&addr).unwrap();
+        let err = nl.tcp_connect(&addr).unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+        let wait = nl.dial_wait();
+        assert!(wait > time::Duration::from_millis(0) && wait <= time::Duration::from_secs(1));
+    }
+
+    #[test]
+    fn tls_listen_connect() {
+        let mut nl = IOLoop::new();
+        let testdata = format!("{}/testdata", env!("CARGO_MANIFEST_DIR"));
+        let mut config = TlsConfig::default();
+        config.add_ca_file(&format!("{}/ca.pem", testdata));
+        assert!(nl.set_tls_config(config.clone()).is_ok());
+        assert!(nl.tls_listen(&"127.0.0.1:0".parse().unwrap()).is_err());
+
+        config.set_cert_file(&format!("{}/localhost.pem", testdata));
+        config.set_key_file(&format!("{}/localhost.key", testdata));
+        nl.set_tls_config(config).unwrap();
+        let listener = nl.tls_listen(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = nl.get_listener_ref(listener).unwrap().local_addr().unwrap();
+        assert!(nl.tls_connect(&addr, "127.0.0.1").is_err());
+        let conn = nl.tls_connect(&addr, "localhost").unwrap();
+
+        let mut accepted = None;
+        let mut written = false;
+        let mut buf = [0u8; 16];
+        loop {
+            let _ = nl.recv_timeout(time::Duration::from_millis(10));
+            if accepted.is_none() {
+                accepted = nl.tcp_accept(listener).ok();
+            }
+            if !written {
+                written = nl.write_stream(conn, b"hello").is_ok();
+            }
+            // the client reads to finish its side of the handshake
+            let _ = nl.read_stream(conn, &mut [0u8; 16]);
+            if let Some(server) = accepted {
+                if let Ok(n) = nl.read_stream(server, &mut buf) {
+                    assert_eq!(&buf[..n], b"hello");
+                    break;
+                }
+            }
+        }
+        // the guest only ever sees plaintext
+        assert_eq!(nl.write_stream(accepted.unwrap(), b"bye").unwrap(), 3);
+    }
+
+    #[test]
+    fn fetch() {
+        let mut nl = IOLoop::new();
+        let mut config = TlsConfig::default();
+        config.add_ca_file(&format!("{}/testdata/ca.pem", env!("CARGO_MANIFEST_DIR")));
+        nl.set_tls_config(config).unwrap();
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut conn, _) = server.accept().unwrap();
+            let mut buf = [0u8; 1024];
+            let n = conn.read(&mut buf).unwrap();
+            assert!(buf[..n].starts_with(b"POST /echo HTTP/1.1\r\n"));
+            conn.write_all(
+                b"HTTP/1.1 201 Created\r\nX-Test: yes\r\nContent-Length: 5\r\n\r\nhello",
+            )
+            .unwrap();
+        });
+        nl.fetch(
+            1,
+            FetchRequest {
+                method: "POST".to_string(),
+                url: format!("http://{}/echo", addr),
+                headers: vec![],
+                body: b"hi".to_vec(),
+            },
+        );
+        let body = match nl.recv().unwrap() {
+            Response::Fetch(FetchResponse::Head {
+                id,
+                status,
+                headers,
+                body,
+            }) => {
+                assert_eq!(id, 1);
+                assert_eq!(status, 201);
+                assert!(headers.contains(&("x-test".to_string(), "yes".to_string())));
+                body
+            }
+            resp => panic!("unexpected response {:?}", resp),
+        };
+        let mut read = Vec::new();
+        loop {
+            nl.fetch_read(2, body);
+            match nl.recv().unwrap() {
+                Response::Fetch(FetchResponse::Chunk {
+                    chunk: Some(chunk), ..
+                }) => read.extend(chunk),
+                Response::Fetch(FetchResponse::Chunk { chunk: None, .. }) => break,
+                resp => panic!("unexpected response {:?}", resp),
+            }
+        }
+        assert_eq!(read, b"hello");
+        assert!(!nl.is_active());
+
+        // fetches are held to the egress policy
+        let mut policy = Policy::default();
+        policy.deny_egress("127.0.0.1").unwrap();
+        nl.set_policy(policy);
+        nl.fetch(
+            3,
+            FetchRequest {
+                method: "GET".to_string(),
+                url: format!("http://{}/", addr),
+                ..FetchRequest::default()
+            },
+        );
+        match nl.recv().unwrap() {
+            Response::Fetch(FetchResponse::Failed { id, .. }) => assert_eq!(id, 3),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
 }
@@ -0,0 +1,299 @@
+//! Inherited listeners
+//!
+//! Listeners can be bound before an instance starts, by systemd socket
+//! activation or by a wasabi process that is being upgraded, and handed to
+//! the IOLoop as file descriptors. When the guest listens on an address one
+//! of them is bound to, it gets that listener instead of a fresh socket, so
+//! connections that queued up during a restart aren't refused.
+//!
+//! The tcp listeners the guest holds, and the inherited ones it hasn't
+//! listened on yet, are kept in a ListenerSet that can be read from another
+//! thread to pass them on to a new process.
+
+use failure::{err_msg, Error};
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+// systemd passes its sockets starting at this fd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Names and file descriptors of listeners bound outside of the instance
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenFds {
+    fds: Vec<(String, RawFd)>,
+}
+
+impl ListenFds {
+    /// The sockets systemd passed with LISTEN_FDS and LISTEN_FDNAMES, if
+    /// LISTEN_PID is this process. The variables are removed so that they
+    /// aren't seen by processes started later.
+    pub fn from_env() -> Result<Self, Error> {
+        let pid = env::var("LISTEN_PID");
+        let count = env::var("LISTEN_FDS");
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+        let mut listen_fds = Self::default();
+        let count: RawFd = match (pid, count) {
+            (Ok(ref pid), Ok(count)) if pid.parse() == Ok(process::id()) => count.parse()?,
+            _ => return Ok(listen_fds),
+        };
+        let mut names = names.split(':');
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            let name = match names.next() {
+                Some(name) if !name.is_empty() => name.to_string(),
+                // what systemd names sockets that it wasn't given a name for
+                _ => "unknown".to_string(),
+            };
+            listen_fds.fds.push((name, fd));
+        }
+        Ok(listen_fds)
+    }
+    /// add a listener in the form `name=fd`
+    pub fn add(&mut self, spec: &str) -> Result<(), Error> {
+        let mut parts = spec.rsplitn(2, '=');
+        let fd = parts.next().unwrap_or("");
+        let name = match parts.next() {
+            Some(name) if !name.is_empty() => name,
+            _ => return Err(err_msg(format!("expected name=fd, got {}", spec))),
+        };
+        self.fds.push((name.to_string(), fd.parse()?));
+        Ok(())
+    }
+    /// Removes the fds that aren't listening tcp sockets, which systemd can
+    /// pass along with them, and returns their names with why they were
+    /// removed
+    pub fn retain_tcp_listeners(&mut self) -> Vec<(String, Error)> {
+        let mut removed = Vec::new();
+        let fds = mem::replace(&mut self.fds, Vec::new());
+        for (name, fd) in fds {
+            match check_listener(fd) {
+                Ok(()) => self.fds.push((name, fd)),
+                Err(err) => removed.push((name, err)),
+            }
+        }
+        removed
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &(String, RawFd)> {
+        self.fds.iter()
+    }
+}
+
+/// Listeners waiting for the guest to listen on their address
+#[derive(Debug, Default)]
+pub(crate) struct Inherited {
+    listeners: Vec<(String, net::TcpListener)>,
+}
+
+impl Inherited {
+    /// takes ownership of the listeners in fds. Each must be a listening tcp
+    /// socket. All of them are checked before any is taken, so none are
+    /// taken if one is refused.
+    pub(crate) fn adopt(&mut self, fds: &ListenFds) -> Result<(), Error> {
+        for (i, (_, fd)) in fds.iter().enumerate() {
+            let fd = *fd;
+            if self.listeners.iter().any(|(_, l)| l.as_raw_fd() == fd)
+                || fds.fds[..i].iter().any(|(_, other)| *other == fd)
+            {
+                return Err(err_msg(format!("fd {} was already inherited", fd)));
+            }
+            check_listener(fd)?;
+        }
+        for (name, fd) in fds.iter() {
+            // started processes don't get it, upgrades pass it on explicitly
+            unsafe { libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let listener = unsafe { net::TcpListener::from_raw_fd(*fd) };
+            self.listeners.push((name.clone(), listener));
+        }
+        Ok(())
+    }
+    /// the listener bound to addr. An unspecified address on either side
+    /// matches any address with the same port.
+    pub(crate) fn take(&mut self, addr: &SocketAddr) -> Option<(String, net::TcpListener)> {
+        let i = self.listeners.iter().position(|(_, listener)| {
+            listener.local_addr().ok().map_or(false, |bound| {
+                bound.port() == addr.port()
+                    && (bound.ip() == addr.ip()
+                        || bound.ip().is_unspecified()
+                        || addr.ip().is_unspecified())
+            })
+        })?;
+        Some(self.listeners.remove(i))
+    }
+}
+
+/// errs unless fd is a listening tcp socket
+fn check_listener(fd: RawFd) -> Result<(), Error> {
+    if !is_listening(fd)? {
+        return Err(err_msg(format!("fd {} is not a listening socket", fd)));
+    }
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET | libc::AF_INET6 => Ok(()),
+        _ => Err(err_msg(format!("fd {} is not a tcp socket", fd))),
+    }
+}
+
+fn is_listening(fd: RawFd) -> Result<bool, io::Error> {
+    let mut listening: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listening != 0)
+}
+
+#[derive(Debug, Default)]
+struct Listeners {
+    // by the id of the guest's listener
+    held: HashMap<usize, (String, RawFd)>,
+    // inherited listeners the guest hasn't listened on yet
+    unclaimed: Vec<(String, RawFd)>,
+}
+
+/// The tcp listeners an instance holds, which can be read from other threads
+#[derive(Debug, Clone, Default)]
+pub struct ListenerSet {
+    listeners: Arc<Mutex<Listeners>>,
+}
+
+impl ListenerSet {
+    pub(crate) fn inherit(&self, name: String, fd: RawFd) {
+        self.listeners.lock().unwrap().unclaimed.push((name, fd));
+    }
+    /// adds the guest's listener id. An inherited listener moves over in one
+    /// step, so it's never missing from duplicate.
+    pub(crate) fn insert(&self, id: usize, name: String, fd: RawFd) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners
+            .unclaimed
+            .retain(|(_, unclaimed)| *unclaimed != fd);
+        listeners.held.insert(id, (name, fd));
+    }
+    pub(crate) fn remove(&self, id: usize) {
+        self.listeners.lock().unwrap().held.remove(&id);
+    }
+    /// forgets every listener, as their fds are about to be closed
+    pub(crate) fn clear(&self) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.held.clear();
+        listeners.unclaimed.clear();
+    }
+    /// Duplicates of every listener and their names, to pass to another
+    /// process. The duplicates stay open if the guest closes its listener.
+    pub fn duplicate(&self) -> Result<Vec<(String, net::TcpListener)>, io::Error> {
+        // held while duplicating, so a listener can't be closed in between
+        let listeners = self.listeners.lock().unwrap();
+        listeners
+            .held
+            .values()
+            .chain(listeners.unclaimed.iter())
+            .map(|(name, fd)| {
+                let dup = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if dup == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok((name.clone(), unsafe { net::TcpListener::from_raw_fd(dup) }))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn parses_specs() {
+        let mut fds = ListenFds::default();
+        fds.add("web=3").unwrap();
+        fds.add("127.0.0.1:8080=4").unwrap();
+        assert_eq!(
+            fds.iter().cloned().collect::<Vec<_>>(),
+            vec![("web".to_string(), 3), ("127.0.0.1:8080".to_string(), 4)]
+        );
+        assert!(fds.add("web").is_err());
+        assert!(fds.add("=3").is_err());
+        assert!(fds.add("web=x").is_err());
+    }
+
+    #[test]
+    fn matches_addresses() {
+        let listener = net::TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut fds = ListenFds::default();
+        fds.add(&format!("web={}", listener.into_raw_fd())).unwrap();
+        let mut inherited = Inherited::default();
+        inherited.adopt(&fds).unwrap();
+        assert!(inherited.adopt(&fds).is_err());
+
+        let other: SocketAddr = format!("127.0.0.1:{}", port.wrapping_add(1))
+            .parse()
+            .unwrap();
+        assert!(inherited.take(&other).is_none());
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (name, _) = inherited.take(&addr).unwrap();
+        assert_eq!(name, "web");
+        assert!(inherited.take(&addr).is_none());
+    }
+
+    #[test]
+    fn rejects_sockets_that_arent_listening() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut fds = ListenFds::default();
+        fds.add(&format!("tcp={}", listener.as_raw_fd())).unwrap();
+        fds.add(&format!("udp={}", socket.as_raw_fd())).unwrap();
+        let mut inherited = Inherited::default();
+        assert!(inherited.adopt(&fds).is_err());
+        // the tcp listener wasn't taken either
+        assert!(inherited.listeners.is_empty());
+
+        let removed = fds.retain_tcp_listeners();
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].0, "udp");
+        assert_eq!(fds.iter().count(), 1);
+    }
+
+    #[test]
+    fn skips_unix_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(dir.path().join("sock")).unwrap();
+        let mut fds = ListenFds::default();
+        fds.add(&format!("unix={}", listener.as_raw_fd())).unwrap();
+        assert_eq!(fds.retain_tcp_listeners().len(), 1);
+        assert!(fds.is_empty());
+    }
+}
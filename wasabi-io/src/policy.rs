@@ -0,0 +1,310 @@
+//! Network access policy
+//!
+//! A Policy decides which addresses a guest may connect to and which ports
+//! it may listen on. Egress rules are checked in the order they were added
+//! and the first matching rule wins. When no rule matches, connections are
+//! denied if any allow rule exists and allowed otherwise, so an empty policy
+//! allows everything.
+//!
+//! Egress rules have the form `target[:port[-port]]`, where target is `*`,
+//! an ip, a CIDR such as `10.0.0.0/8` or `[2001:db8::]/32`, or a hostname.
+//! A hostname matches any address that the guest resolved it to, and
+//! `*.example.com` matches every subdomain of example.com. IPv4-mapped ipv6
+//! addresses are matched as the ipv4 address they map to.
+
+use failure::{err_msg, Error};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Any,
+    Cidr(IpAddr, u8),
+    Host(String),
+}
+
+type PortRange = (u16, u16);
+
+#[derive(Debug, Clone, PartialEq)]
+struct EgressRule {
+    action: Action,
+    target: Target,
+    ports: Option<PortRange>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    egress: Vec<EgressRule>,
+    listen_ports: Option<Vec<PortRange>>,
+    // hostnames the guest resolved to each address, only tracked when there
+    // are hostname rules
+    resolved: HashMap<IpAddr, HashSet<String>>,
+}
+
+impl Policy {
+    pub fn allow_egress(&mut self, rule: &str) -> Result<(), Error> {
+        self.egress.push(parse_egress_rule(Action::Allow, rule)?);
+        Ok(())
+    }
+    pub fn deny_egress(&mut self, rule: &str) -> Result<(), Error> {
+        self.egress.push(parse_egress_rule(Action::Deny, rule)?);
+        Ok(())
+    }
+    /// allow listening on a port or range of ports, such as `8080` or
+    /// `8000-8100`. Once any port is allowed all other ports are denied,
+    /// including port 0.
+    pub fn allow_listen(&mut self, ports: &str) -> Result<(), Error> {
+        let range = parse_port_range(ports)?;
+        self.listen_ports.get_or_insert_with(Vec::new).push(range);
+        Ok(())
+    }
+    /// remember that host resolved to ips so hostname rules can match them
+    pub fn record_resolution<I: IntoIterator<Item = IpAddr>>(&mut self, host: &str, ips: I) {
+        if !self.has_host_rules() {
+            return;
+        }
+        let host = normalize_host(host);
+        for ip in ips {
+            self.resolved
+                .entry(unmapped(ip))
+                .or_insert_with(HashSet::new)
+                .insert(host.clone());
+        }
+    }
+    /// returns ECONNREFUSED if the guest may not connect to addr
+    pub fn check_connect(&self, addr: &SocketAddr) -> Result<(), io::Error> {
+        let matched = self
+            .egress
+            .iter()
+            .find(|rule| self.rule_matches(rule, addr))
+            .map(|rule| rule.action);
+        let allowed = match matched {
+            Some(action) => action == Action::Allow,
+            None => !self.egress.iter().any(|rule| rule.action == Action::Allow),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(libc::ECONNREFUSED))
+        }
+    }
+    /// returns EACCES if the guest may not listen on addr
+    pub fn check_listen(&self, addr: &SocketAddr) -> Result<(), io::Error> {
+        match &self.listen_ports {
+            Some(ranges) if !ranges.iter().any(|r| in_range(*r, addr.port())) => {
+                Err(io::Error::from_raw_os_error(libc::EACCES))
+            }
+            _ => Ok(()),
+        }
+    }
+    fn has_host_rules(&self) -> bool {
+        self.egress.iter().any(|rule| match rule.target {
+            Target::Host(_) => true,
+            _ => false,
+        })
+    }
+    fn rule_matches(&self, rule: &EgressRule, addr: &SocketAddr) -> bool {
+        if let Some(range) = rule.ports {
+            if !in_range(range, addr.port()) {
+                return false;
+            }
+        }
+        let ip = unmapped(addr.ip());
+        match &rule.target {
+            Target::Any => true,
+            Target::Cidr(net, prefix) => cidr_contains(*net, *prefix, ip),
+            Target::Host(pattern) => self.resolved.get(&ip).map_or(false, |hosts| {
+                hosts.iter().any(|h| host_matches(pattern, h))
+            }),
+        }
+    }
+}
+
+fn in_range(range: PortRange, port: u16) -> bool {
+    range.0 <= port && port <= range.1
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if pattern.starts_with("*.") {
+        host.ends_with(&pattern[1..])
+    } else {
+        pattern == host
+    }
+}
+
+/// The ipv4 address behind an ipv4-mapped or ipv4-compatible ipv6 address,
+/// which a dual-stack socket connects to, so ipv4 rules can't be bypassed
+/// with the ipv6 form. :: and ::1 are left alone.
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if !v6.is_unspecified() && !v6.is_loopback() => {
+            v6.to_ipv4().map_or(ip, IpAddr::V4)
+        }
+        _ => ip,
+    }
+}
+
+fn cidr_contains(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::max_value()
+                .checked_shl(32 - u32::from(prefix))
+                .unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::max_value()
+                .checked_shl(128 - u32::from(prefix))
+                .unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn parse_port_range(s: &str) -> Result<PortRange, Error> {
+    let mut parts = s.splitn(2, '-');
+    let start: u16 = parts.next().unwrap_or("").parse()?;
+    let end = match parts.next() {
+        Some(end) => end.parse()?,
+        None => start,
+    };
+    if end < start {
+        return Err(err_msg(format!("invalid port range {}", s)));
+    }
+    Ok((start, end))
+}
+
+fn parse_egress_rule(action: Action, s: &str) -> Result<EgressRule, Error> {
+    // ipv6 addresses are bracketed so their colons aren't read as a port
+    let (target, ports) = if s.starts_with('[') {
+        let end = s
+            .find(']')
+            .ok_or_else(|| err_msg(format!("missing ] in {}", s)))?;
+        let rest = &s[end + 1..];
+        let mut parts = rest.splitn(2, ':');
+        let prefix = parts.next().unwrap_or("");
+        (format!("{}{}", &s[1..end], prefix), parts.next())
+    } else {
+        let mut parts = s.splitn(2, ':');
+        (parts.next().unwrap_or("").to_string(), parts.next())
+    };
+    let ports = match ports {
+        Some(ports) => Some(parse_port_range(ports)?),
+        None => None,
+    };
+    Ok(EgressRule {
+        action,
+        target: parse_target(&target)?,
+        ports,
+    })
+}
+
+fn parse_target(s: &str) -> Result<Target, Error> {
+    if s == "*" {
+        return Ok(Target::Any);
+    }
+    if s.is_empty() {
+        return Err(err_msg("empty egress rule"));
+    }
+    let mut parts = s.splitn(2, '/');
+    let ip_part = parts.next().unwrap();
+    if let Ok(ip) = ip_part.parse::<IpAddr>() {
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse()?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(err_msg(format!("invalid prefix length in {}", s)));
+        }
+        // addresses are matched in their ipv4 form, so mapped networks are
+        // too
+        return Ok(match unmapped(ip) {
+            IpAddr::V4(v4) if ip.is_ipv6() && prefix >= 96 => {
+                Target::Cidr(IpAddr::V4(v4), prefix - 96)
+            }
+            _ => Target::Cidr(ip, prefix),
+        });
+    }
+    if s.contains('/') {
+        return Err(err_msg(format!("invalid CIDR {}", s)));
+    }
+    Ok(Target::Host(normalize_host(s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = Policy::default();
+        assert!(policy.check_connect(&addr("1.2.3.4:80")).is_ok());
+        assert!(policy.check_listen(&addr("0.0.0.0:0")).is_ok());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let mut policy = Policy::default();
+        policy.deny_egress("10.1.0.0/16").unwrap();
+        policy.allow_egress("10.0.0.0/8:443").unwrap();
+        policy.allow_egress("[2001:db8::]/32:80-81").unwrap();
+        assert!(policy.check_connect(&addr("10.2.3.4:443")).is_ok());
+        assert!(policy.check_connect(&addr("10.1.3.4:443")).is_err());
+        assert!(policy.check_connect(&addr("10.2.3.4:80")).is_err());
+        assert!(policy.check_connect(&addr("[2001:db8::1]:81")).is_ok());
+        // allow rules exist so unmatched addresses are denied
+        let err = policy.check_connect(&addr("8.8.8.8:53")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ECONNREFUSED));
+    }
+
+    #[test]
+    fn hostname_rules_match_resolved_addresses() {
+        let mut policy = Policy::default();
+        policy.allow_egress("*.example.com:443").unwrap();
+        policy.record_resolution("API.example.com.", vec!["93.184.216.34".parse().unwrap()]);
+        assert!(policy.check_connect(&addr("93.184.216.34:443")).is_ok());
+        assert!(policy.check_connect(&addr("93.184.216.35:443")).is_err());
+    }
+
+    #[test]
+    fn ipv4_rules_match_mapped_addresses() {
+        let mut policy = Policy::default();
+        policy.deny_egress("10.0.0.0/8").unwrap();
+        policy.deny_egress("[::ffff:192.168.0.0]/112").unwrap();
+        policy.deny_egress("*.internal").unwrap();
+        policy.record_resolution("db.internal", vec!["::ffff:172.16.0.1".parse().unwrap()]);
+        assert!(policy.check_connect(&addr("[::ffff:10.0.0.1]:80")).is_err());
+        assert!(policy.check_connect(&addr("[::10.0.0.1]:80")).is_err());
+        assert!(policy.check_connect(&addr("192.168.1.1:80")).is_err());
+        assert!(policy.check_connect(&addr("172.16.0.1:80")).is_err());
+        assert!(policy.check_connect(&addr("[::ffff:11.0.0.1]:80")).is_ok());
+        assert!(policy.check_connect(&addr("[::1]:80")).is_ok());
+    }
+
+    #[test]
+    fn listen_ports() {
+        let mut policy = Policy::default();
+        policy.allow_listen("8000-8100").unwrap();
+        assert!(policy.check_listen(&addr("0.0.0.0:8080")).is_ok());
+        let err = policy.check_listen(&addr("0.0.0.0:22")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        assert!(policy.allow_listen("9-1").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Read buffers
+//!
+//! File reads land in a buffer that the embedder copies into guest memory
+//! when it handles Response::Read. Copying on the guest's thread, against
+//! the memory as it is at that point, keeps the read safe if the guest grew
+//! or moved its memory while the read was in flight. The buffer is then
+//! handed back with IOLoop::recycle so the next read reuses it, rather than
+//! allocating and zeroing a fresh one every time. The pool belongs to an
+//! IOLoop and is only used from its thread.
+
+use std::cell::RefCell;
+
+// buffers kept for reuse
+const MAX_POOLED: usize = 16;
+// larger buffers are freed rather than kept around
+const MAX_POOLED_LEN: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+    // sorted by capacity
+    buffers: RefCell<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+    /// a buffer of len bytes. Only the bytes beyond what the buffer held
+    /// before are zeroed.
+    pub(crate) fn take(&self, len: usize) -> Vec<u8> {
+        let mut buf = {
+            let mut buffers = self.buffers.borrow_mut();
+            // the smallest buffer that fits, so larger ones are left for
+            // larger reads. Failing that the largest needs the least growing.
+            match buffers.iter().position(|buf| buf.capacity() >= len) {
+                Some(i) => buffers.remove(i),
+                None => buffers.pop().unwrap_or_default(),
+            }
+        };
+        buf.resize(len, 0);
+        buf
+    }
+    pub(crate) fn put(&self, buf: Vec<u8>) {
+        if buf.capacity() > MAX_POOLED_LEN {
+            return;
+        }
+        let mut buffers = self.buffers.borrow_mut();
+        if buffers.len() < MAX_POOLED {
+            buffers.push(buf);
+            buffers.sort_by_key(Vec::capacity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers() {
+        let pool = BufferPool::default();
+        let mut buf = pool.take(64);
+        assert_eq!(buf.len(), 64);
+        buf[0] = 7;
+        let ptr = buf.as_ptr();
+        pool.put(buf);
+        let buf = pool.take(32);
+        assert_eq!(buf.len(), 32);
+        assert_eq!(buf.as_ptr(), ptr);
+
+        // the smallest buffer that fits is used
+        let small = pool.take(16);
+        let large = pool.take(4096);
+        let (small_ptr, large_ptr) = (small.as_ptr(), large.as_ptr());
+        pool.put(large);
+        pool.put(small);
+        assert_eq!(pool.take(8).as_ptr(), small_ptr);
+        assert_eq!(pool.take(1024).as_ptr(), large_ptr);
+
+        // too large to keep
+        pool.put(vec![0; MAX_POOLED_LEN + 1]);
+        assert!(pool.take(1).capacity() < MAX_POOLED_LEN);
+    }
+}
@@ -0,0 +1,85 @@
+//! Virtual ports
+//!
+//! A PortMap translates the port a guest listens on to the port that is
+//! actually bound on the host, so that several instances can each listen on
+//! the same guest port. Ports are either mapped to a fixed host port or to
+//! an ephemeral one picked by the OS.
+
+use failure::{err_msg, Error};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// A guest listen address and the host address it was bound to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortMapping {
+    /// id of the listener in the IOLoop
+    pub id: usize,
+    pub guest: SocketAddr,
+    pub host: SocketAddr,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PortMap {
+    // guest port to host port, 0 means ephemeral
+    fixed: HashMap<u16, u16>,
+    // map every port that isn't listed in fixed to an ephemeral port
+    ephemeral: bool,
+}
+
+impl PortMap {
+    /// add a mapping in the form `guest:host`. A host port of 0 maps the
+    /// guest port to an ephemeral port.
+    pub fn add(&mut self, mapping: &str) -> Result<(), Error> {
+        let mut parts = mapping.splitn(2, ':');
+        let guest: u16 = parts.next().unwrap_or("").parse()?;
+        let host: u16 = match parts.next() {
+            Some(host) => host.parse()?,
+            None => return Err(err_msg(format!("expected guest:host, got {}", mapping))),
+        };
+        self.fixed.insert(guest, host);
+        Ok(())
+    }
+    /// map every guest port that doesn't have a mapping to an ephemeral port
+    pub fn set_ephemeral(&mut self, ephemeral: bool) {
+        self.ephemeral = ephemeral;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fixed.is_empty() && !self.ephemeral
+    }
+    /// the address to bind on the host for a guest listen address
+    pub fn host_addr(&self, guest: &SocketAddr) -> SocketAddr {
+        let mut host = *guest;
+        match self.fixed.get(&guest.port()) {
+            Some(port) => host.set_port(*port),
+            None if self.ephemeral => host.set_port(0),
+            None => {}
+        }
+        host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_guest_ports() {
+        let mut map = PortMap::default();
+        assert!(map.is_empty());
+        map.add("8080:18080").unwrap();
+        map.add("9090:0").unwrap();
+        let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+        assert_eq!(
+            map.host_addr(&addr("127.0.0.1:8080")),
+            addr("127.0.0.1:18080")
+        );
+        assert_eq!(map.host_addr(&addr("127.0.0.1:9090")), addr("127.0.0.1:0"));
+        assert_eq!(
+            map.host_addr(&addr("127.0.0.1:7070")),
+            addr("127.0.0.1:7070")
+        );
+        map.set_ephemeral(true);
+        assert_eq!(map.host_addr(&addr("127.0.0.1:7070")), addr("127.0.0.1:0"));
+        assert!(map.add("8080").is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! PROXY protocol
+//!
+//! Load balancers that forward TCP connections can send a PROXY protocol
+//! header, version 1 in text or version 2 in binary, with the address of the
+//! client before any of the client's bytes. On listeners that expect one,
+//! the IOLoop reads the header of every accepted stream before the guest
+//! accepts it, and the guest sees the client as the stream's peer. Streams
+//! with a malformed header, or none within HEADER_TIMEOUT, are closed
+//! without reaching the guest.
+
+use failure::{err_msg, Error};
+use mio::net::TcpStream;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use std::time::{Duration, Instant};
+
+/// how long an accepted stream has to send its header
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest v1 header, with both addresses in full ipv6 form
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Parsed {
+    /// more bytes are needed, the header is at most this long or, for
+    /// version 2, exactly this long
+    Incomplete(usize),
+    /// a header of len bytes. source is None when the connection is the
+    /// proxy's own, like a health check, and keeps its address.
+    Header {
+        len: usize,
+        source: Option<SocketAddr>,
+    },
+}
+
+/// parses the header at the start of buf
+pub(crate) fn parse(buf: &[u8]) -> Result<Parsed, Error> {
+    let n = buf.len().min(V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        if buf.len() < V2_HEADER_LEN {
+            return Ok(Parsed::Incomplete(V2_HEADER_LEN));
+        }
+        return parse_v2(buf);
+    }
+    let n = buf.len().min(V1_PREFIX.len());
+    if buf[..n] == V1_PREFIX[..n] {
+        return parse_v1(buf);
+    }
+    Err(err_msg("missing PROXY protocol header"))
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Parsed, Error> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(Parsed::Incomplete(V1_MAX_LEN)),
+        _ => return Err(err_msg("PROXY protocol v1 header is too long")),
+    };
+    let invalid = || err_msg("invalid PROXY protocol v1 header");
+    let line = str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| invalid())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    // the proxy doesn't know where the connection came from
+    if fields[0] == "UNKNOWN" {
+        return Ok(Parsed::Header {
+            len: end + 2,
+            source: None,
+        });
+    }
+    if fields.len() != 5 {
+        return Err(invalid());
+    }
+    let source: IpAddr = fields[1].parse().map_err(|_| invalid())?;
+    let destination: IpAddr = fields[2].parse().map_err(|_| invalid())?;
+    let ipv4 = match fields[0] {
+        "TCP4" => true,
+        "TCP6" => false,
+        _ => return Err(invalid()),
+    };
+    if source.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+        return Err(invalid());
+    }
+    let port = parse_port(fields[3]).ok_or_else(invalid)?;
+    parse_port(fields[4]).ok_or_else(invalid)?;
+    Ok(Parsed::Header {
+        len: end + 2,
+        source: Some(SocketAddr::new(source, port)),
+    })
+}
+
+// decimal without a sign or leading zeros
+fn parse_port(port: &str) -> Option<u16> {
+    if port.is_empty()
+        || !port.bytes().all(|b| b.is_ascii_digit())
+        || (port.len() > 1 && port.starts_with('0'))
+    {
+        return None;
+    }
+    port.parse().ok()
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed, Error> {
+    let command = buf[12];
+    if command >> 4 != 2 {
+        return Err(err_msg("unsupported PROXY protocol version"));
+    }
+    let len = V2_HEADER_LEN + ((buf[14] as usize) << 8 | buf[15] as usize);
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete(len));
+    }
+    let addrs = &buf[V2_HEADER_LEN..len];
+    let source = match command & 0xf {
+        // LOCAL
+        0 => None,
+        // PROXY
+        1 => match (buf[13] >> 4, buf[13] & 0xf, addrs.len()) {
+            // unspecified keeps the connection's address
+            (0, _, _) => None,
+            // a datagram's client can't be the peer of a stream
+            (_, transport, _) if transport != 1 => {
+                return Err(err_msg("unsupported PROXY protocol transport"))
+            }
+            // as do unix sockets
+            (3, _, _) => None,
+            (1, _, n) if n >= 12 => {
+                let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+                Some(SocketAddr::new(ip.into(), port(&addrs[8..10])))
+            }
+            (2, _, n) if n >= 36 => {
+                let mut ip = [0; 16];
+                ip.copy_from_slice(&addrs[..16]);
+                Some(SocketAddr::new(
+                    Ipv6Addr::from(ip).into(),
+                    port(&addrs[32..34]),
+                ))
+            }
+            _ => return Err(err_msg("invalid PROXY protocol v2 address")),
+        },
+        _ => return Err(err_msg("unsupported PROXY protocol command")),
+    };
+    Ok(Parsed::Header { len, source })
+}
+
+fn port(b: &[u8]) -> u16 {
+    u16::from(b[0]) << 8 | u16::from(b[1])
+}
+
+/// reads the header from stream. Nothing is read until all of it has
+/// arrived, so the bytes after it are left for the guest.
+pub(crate) fn read_header(stream: &TcpStream) -> Result<Parsed, Error> {
+    let mut buf = vec![0; V1_MAX_LEN];
+    loop {
+        let n = match stream.peek(&mut buf) {
+            Ok(0) => {
+                return Err(err_msg(
+                    "connection closed before its PROXY protocol header",
+                ))
+            }
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Parsed::Incomplete(buf.len()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match parse(&buf[..n])? {
+            Parsed::Incomplete(len) if len > buf.len() => buf.resize(len, 0),
+            Parsed::Header { len, source } => {
+                // all of it was peeked, so this doesn't block
+                let mut stream = stream;
+                stream.read_exact(&mut buf[..len])?;
+                return Ok(Parsed::Header { len, source });
+            }
+            incomplete => return Ok(incomplete),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Held {
+    listener: usize,
+    peer: SocketAddr,
+    ready: bool,
+    // when the stream is closed if its header hasn't been read
+    deadline: Instant,
+}
+
+/// The listeners that expect a header and the streams accepted from them
+/// that the guest doesn't know about yet
+#[derive(Debug, Default)]
+pub(crate) struct Proxied {
+    listeners: HashSet<usize>,
+    held: HashMap<usize, Held>,
+    // streams whose header was read, by listener, in the order they're
+    // handed to the guest
+    ready: HashMap<usize, VecDeque<usize>>,
+    // the clients named by the headers of accepted streams
+    peers: HashMap<usize, SocketAddr>,
+}
+
+impl Proxied {
+    pub(crate) fn set(&mut self, listener: usize, enabled: bool) {
+        if enabled {
+            self.listeners.insert(listener);
+        } else {
+            self.listeners.remove(&listener);
+        }
+    }
+    pub(crate) fn expects(&self, listener: usize) -> bool {
+        self.listeners.contains(&listener)
+    }
+    /// keeps stream from the guest until its header is read, which has to
+    /// be before deadline
+    pub(crate) fn hold(
+        &mut self,
+        stream: usize,
+        listener: usize,
+        peer: SocketAddr,
+        deadline: Instant,
+    ) {
+        let held = Held {
+            listener,
+            peer,
+            ready: false,
+            deadline,
+        };
+        self.held.insert(stream, held);
+    }
+    pub(crate) fn is_held(&self, stream: usize) -> bool {
+        self.held.contains_key(&stream)
+    }
+    pub(crate) fn is_pending(&self, stream: usize) -> bool {
+        self.held.get(&stream).map_or(false, |held| !held.ready)
+    }
+    /// whether the header of stream is still missing at its deadline
+    pub(crate) fn is_overdue(&self, stream: usize, now: Instant) -> bool {
+        self.held
+            .get(&stream)
+            .map_or(false, |held| !held.ready && now >= held.deadline)
+    }
+    /// marks the header of stream as read and returns its listener
+    pub(crate) fn ready(&mut self, stream: usize, source: Option<SocketAddr>) -> Option<usize> {
+        let held = self.held.get_mut(&stream)?;
+        held.ready = true;
+        if let Some(source) = source {
+            held.peer = source;
+        }
+        let listener = held.listener;
+        self.ready.entry(listener).or_default().push_back(stream);
+        Some(listener)
+    }
+    /// the next stream from listener that can be handed to the guest, and
+    /// the address of its client
+    pub(crate) fn accept(&mut self, listener: usize) -> Option<(usize, SocketAddr)> {
+        let stream = self.ready.get_mut(&listener)?.pop_front()?;
+        let held = self.held.remove(&stream)?;
+        self.peers.insert(stream, held.peer);
+        Some((stream, held.peer))
+    }
+    pub(crate) fn peer_addr(&self, stream: usize) -> Option<SocketAddr> {
+        self.peers.get(&stream).cloned()
+    }
+    /// forgets socket i. If it's a listener, the streams held for it are
+    /// returned so that they can be closed too.
+    pub(crate) fn close(&mut self, i: usize) -> Vec<usize> {
+        self.peers.remove(&i);
+        if let Some(held) = self.held.remove(&i) {
+            if let Some(ready) = self.ready.get_mut(&held.listener) {
+                ready.retain(|stream| *stream != i);
+            }
+        }
+        if !self.listeners.remove(&i) {
+            return vec![];
+        }
+        self.ready.remove(&i);
+        let streams: Vec<usize> = self
+            .held
+            .iter()
+            .filter(|(_, held)| held.listener == i)
+            .map(|(stream, _)| *stream)
+            .collect();
+        for stream in &streams {
+            self.held.remove(stream);
+        }
+        streams
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(buf: &[u8]) -> Option<SocketAddr> {
+        match parse(buf).unwrap() {
+            Parsed::Header { source, .. } => source,
+            incomplete => panic!("{:?}", incomplete),
+        }
+    }
+
+    #[test]
+    fn parses_v1() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        assert_eq!(
+            parse(header).unwrap(),
+            Parsed::Header {
+                len: header.len() - 5,
+                source: Some("192.0.2.1:56324".parse().unwrap()),
+            }
+        );
+        assert_eq!(
+            source(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n"),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+        assert_eq!(source(b"PROXY UNKNOWN\r\n"), None);
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1").unwrap(),
+            Parsed::Incomplete(V1_MAX_LEN)
+        );
+        assert_eq!(parse(b"PRO").unwrap(), Parsed::Incomplete(V2_HEADER_LEN));
+
+        for header in &[
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 +5632 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443\r\n",
+        ] {
+            assert!(parse(header).is_err(), "{:?}", str::from_utf8(header));
+        }
+        assert!(parse(&[b'P', b'R', b'O', b'X', b'Y', b' ', b'x'].repeat(20)).is_err());
+    }
+
+    #[test]
+    fn parses_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        // PROXY over TCP and ipv4, 12 bytes of addresses
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187]);
+        assert_eq!(
+            parse(&header[..20]).unwrap(),
+            Parsed::Incomplete(V2_HEADER_LEN + 12)
+        );
+        header.extend_from_slice(b"hello");
+        assert_eq!(
+            parse(&header).unwrap(),
+            Parsed::Header {
+                len: V2_HEADER_LEN + 12,
+                source: Some("192.0.2.1:56324".parse().unwrap()),
+            }
+        );
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(source(&local), None);
+
+        let mut version = V2_SIGNATURE.to_vec();
+        version.extend_from_slice(&[0x11, 0x11, 0, 0]);
+        assert!(parse(&version).is_err());
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x21, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(parse(&short).is_err());
+        // PROXY over UDP
+        let mut udp = V2_SIGNATURE.to_vec();
+        udp.extend_from_slice(&[0x21, 0x12, 0, 12]);
+        udp.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187]);
+        assert!(parse(&udp).is_err());
+    }
+
+    #[test]
+    fn holds_streams_until_ready() {
+        let peer: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+        let client: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut proxied = Proxied::default();
+        proxied.set(1, true);
+        let now = Instant::now();
+        let deadline = now + HEADER_TIMEOUT;
+        proxied.hold(2, 1, peer, deadline);
+        proxied.hold(3, 1, peer, deadline);
+        assert!(proxied.is_pending(2));
+        assert_eq!(proxied.accept(1), None);
+        assert!(!proxied.is_overdue(2, now));
+        assert!(proxied.is_overdue(2, deadline));
+
+        assert_eq!(proxied.ready(3, Some(client)), Some(1));
+        assert!(!proxied.is_pending(3));
+        assert!(!proxied.is_overdue(3, deadline));
+        assert!(proxied.is_held(3));
+        assert_eq!(proxied.accept(1), Some((3, client)));
+        assert_eq!(proxied.peer_addr(3), Some(client));
+        assert!(!proxied.is_held(3));
+
+        // closing the listener closes the streams the guest never got
+        assert_eq!(proxied.close(1), vec![2]);
+        assert!(!proxied.expects(1));
+        assert!(!proxied.is_held(2));
+    }
+}
@@ -0,0 +1,176 @@
+//! Rate limits
+//!
+//! A RateLimiter holds token buckets for the bytes an instance reads and
+//! writes each second and for the connections it makes or accepts each
+//! second, plus a cap on the number of sockets it has open. Every bucket
+//! holds at most one second worth of tokens. Transfers are charged for what
+//! they actually moved, so a datagram may take a bucket below zero and the
+//! next transfer waits until it's refilled.
+//!
+//! The limiter is shared, so limits can be changed from another thread
+//! while the instance runs.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Per instance limits, None is unlimited. A rate of Some(0) is treated like
+/// None rather than letting nothing through.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateLimits {
+    pub read_bytes_per_sec: Option<u64>,
+    pub write_bytes_per_sec: Option<u64>,
+    /// connections dialed or accepted per second
+    pub connections_per_sec: Option<u64>,
+    /// open listeners, connections and udp sockets
+    pub max_sockets: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Budget {
+    Read,
+    Write,
+    Connections,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: i64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as i64,
+            last: Instant::now(),
+        }
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        let nanos = elapsed.as_secs() * NANOS_PER_SEC + u64::from(elapsed.subsec_nanos());
+        let added = nanos.saturating_mul(self.rate) / NANOS_PER_SEC;
+        if self.tokens + added as i64 >= self.rate as i64 {
+            self.tokens = self.rate as i64;
+            self.last = now;
+        } else if added > 0 {
+            self.tokens += added as i64;
+            // keep the time that didn't add up to a whole token
+            self.last += Duration::from_nanos(added * NANOS_PER_SEC / self.rate);
+        }
+    }
+    /// the time until there is at least one token
+    fn wait(&self) -> Duration {
+        let missing = (1 - self.tokens) as u64;
+        let nanos = (missing * NANOS_PER_SEC + self.rate - 1) / self.rate;
+        let ready = self.last + Duration::from_nanos(nanos);
+        let now = Instant::now();
+        if ready > now {
+            ready - now
+        } else {
+            Duration::from_nanos(1)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    limits: RateLimits,
+    read: Option<Bucket>,
+    write: Option<Bucket>,
+    connections: Option<Bucket>,
+}
+
+impl Buckets {
+    fn bucket(&mut self, budget: Budget) -> Option<&mut Bucket> {
+        match budget {
+            Budget::Read => self.read.as_mut(),
+            Budget::Write => self.write.as_mut(),
+            Budget::Connections => self.connections.as_mut(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// Replace the limits. Buckets whose rate didn't change keep their
+    /// tokens, the others start full.
+    pub fn set_limits(&self, limits: RateLimits) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let update = |bucket: &mut Option<Bucket>, rate: Option<u64>| {
+            if bucket.as_ref().map(|b| b.rate) != rate {
+                *bucket = rate.filter(|rate| *rate > 0).map(Bucket::new);
+            }
+        };
+        update(&mut buckets.read, limits.read_bytes_per_sec);
+        update(&mut buckets.write, limits.write_bytes_per_sec);
+        update(&mut buckets.connections, limits.connections_per_sec);
+        buckets.limits = limits;
+    }
+    pub fn limits(&self) -> RateLimits {
+        self.buckets.lock().unwrap().limits
+    }
+    /// How much of want can be used right now, without using it. Errs with
+    /// the time to wait when the budget is used up.
+    pub fn reserve(&self, budget: Budget, want: usize) -> Result<usize, Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.bucket(budget) {
+            Some(bucket) if want > 0 => {
+                bucket.refill();
+                if bucket.tokens <= 0 {
+                    Err(bucket.wait())
+                } else {
+                    Ok(want.min(bucket.tokens as usize))
+                }
+            }
+            _ => Ok(want),
+        }
+    }
+    /// use up n from budget
+    pub fn charge(&self, budget: Budget, n: usize) {
+        if let Some(bucket) = self.buckets.lock().unwrap().bucket(budget) {
+            bucket.tokens -= n as i64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_and_refills() {
+        let limiter = RateLimiter::default();
+        assert_eq!(limiter.reserve(Budget::Write, 1 << 20), Ok(1 << 20));
+
+        limiter.set_limits(RateLimits {
+            write_bytes_per_sec: Some(1000),
+            ..RateLimits::default()
+        });
+        assert_eq!(limiter.reserve(Budget::Write, 1 << 20), Ok(1000));
+        assert_eq!(limiter.reserve(Budget::Read, 1 << 20), Ok(1 << 20));
+        // a datagram can overdraw the bucket
+        limiter.charge(Budget::Write, 1500);
+        let wait = limiter.reserve(Budget::Write, 1).unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(501));
+
+        // unchanged rates keep their tokens
+        limiter.set_limits(RateLimits {
+            write_bytes_per_sec: Some(1000),
+            connections_per_sec: Some(1),
+            ..RateLimits::default()
+        });
+        assert!(limiter.reserve(Budget::Write, 1).is_err());
+        assert_eq!(limiter.reserve(Budget::Connections, 1), Ok(1));
+        limiter.charge(Budget::Connections, 1);
+        assert!(limiter.reserve(Budget::Connections, 1).is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Sharded reactors
+//!
+//! A reactor is a single thread that drives the sockets, DNS lookups, timers
+//! and fetches of the IOLoops running on it. The thread blocks in the mio poll
+//! the sockets are registered with, and tokio's reactor, timer and executor
+//! run from that same loop: the tokio reactor's fd is registered with the mio
+//! poll, and waking a task makes the poll return. Blocking file operations
+//! run on a small pool next to it and send their results to the instance
+//! directly.
+//!
+//! Every IOLoop runs on a reactor, either one of its own or a shard of a
+//! Reactors pool, so a host running many instances needs a thread per core
+//! rather than threads per instance. The thread stops once the last IOLoop
+//! on it is dropped.
+//!
+//! The mio token of a socket carries the instance it belongs to in its upper
+//! bits. The reactor owns the table of instances and hands the events of
+//! each poll to their instance in one message, without taking a lock.
+//! Instances join and leave through a second channel that is drained before
+//! events are delivered.
+
+use crate::Message;
+use failure::{err_msg, Error};
+use futures::sync::oneshot;
+use mio::unix::EventedFd;
+use mio::{PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio_current_thread::{CurrentThread, TaskExecutor};
+use tokio_executor::park::{Park, Unpark};
+use tokio_threadpool::ThreadPool;
+use tokio_timer::timer::Timer;
+
+// socket tokens get the lower half of the bits, instances the upper one
+const TOKEN_BITS: usize = mem::size_of::<usize>() * 8 / 2;
+/// the largest token an instance can use for its sockets
+pub const MAX_TOKEN: usize = (1 << TOKEN_BITS) - 1;
+// instance ids wrap around before they reach the reactor's own instance,
+// skipping the ids of instances that are still running
+const MAX_INSTANCE: usize = usize::max_value() >> TOKEN_BITS;
+
+// tokens of the reactor's own registrations, in an instance that is never
+// handed out
+const IO: Token = Token(usize::max_value() - 1);
+const WAKEUP: Token = Token(usize::max_value() - 2);
+
+// threads each reactor has for blocking file operations
+const BLOCKING_THREADS: usize = 4;
+
+enum Route {
+    Join(usize, mpsc::Sender<Message>),
+    Leave(usize),
+}
+
+#[derive(Default)]
+struct Instances {
+    next: usize,
+    live: HashSet<usize>,
+}
+
+/// A thread and the instances assigned to it
+pub struct Reactor {
+    poll: Arc<mio::Poll>,
+    // handles are Send but not Sync
+    executor: Mutex<tokio_current_thread::Handle>,
+    blocking: ThreadPool,
+    routes: Mutex<mpsc::Sender<Route>>,
+    instances: Mutex<Instances>,
+    // dropped to stop the thread
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reactor {
+    pub fn new() -> Result<Arc<Self>, Error> {
+        let poll = Arc::new(mio::Poll::new()?);
+        let (routes, route_receiver) = mpsc::channel();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let (started, start) = mpsc::channel();
+        let t_poll = poll.clone();
+        let thread = thread::Builder::new()
+            .name("wasabi-reactor".to_string())
+            .spawn(move || match Driver::new(t_poll, route_receiver) {
+                Ok(driver) => run(driver, &started, stopped),
+                Err(err) => drop(started.send(Err(err))),
+            })?;
+        let executor = start.recv()??;
+        let blocking = tokio_threadpool::Builder::new()
+            .pool_size(BLOCKING_THREADS)
+            .name_prefix("wasabi-blocking-")
+            .build();
+        Ok(Arc::new(Self {
+            poll,
+            executor: Mutex::new(executor),
+            blocking,
+            routes: Mutex::new(routes),
+            instances: Mutex::new(Instances::default()),
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        }))
+    }
+    pub(crate) fn poll(&self) -> &Arc<mio::Poll> {
+        &self.poll
+    }
+    /// spawns on the reactor's thread
+    pub(crate) fn executor(&self) -> tokio_current_thread::Handle {
+        self.executor.lock().unwrap().clone()
+    }
+    /// spawns where blocking file operations can run
+    pub(crate) fn blocking(&self) -> tokio_threadpool::Sender {
+        self.blocking.sender().clone()
+    }
+    /// adds an instance whose events are sent to sender and returns its id
+    pub(crate) fn join(&self, sender: mpsc::Sender<Message>) -> Result<usize, Error> {
+        let mut instances = self.instances.lock().unwrap();
+        if instances.live.len() >= MAX_INSTANCE {
+            return Err(err_msg("no instance ids left on the reactor"));
+        }
+        let instance = loop {
+            let id = instances.next;
+            instances.next = (id + 1) % MAX_INSTANCE;
+            if instances.live.insert(id) {
+                break id;
+            }
+        };
+        // the route is sent before any of the instance's sockets are
+        // registered, so it's in place by the time their events arrive. it's
+        // sent under the lock so a Leave of an id can't follow its next Join
+        let _ = self
+            .routes
+            .lock()
+            .unwrap()
+            .send(Route::Join(instance, sender));
+        Ok(instance)
+    }
+    /// removes an instance, whose sockets must be closed already as its id
+    /// can be handed out again
+    pub(crate) fn leave(&self, instance: usize) {
+        let mut instances = self.instances.lock().unwrap();
+        instances.live.remove(&instance);
+        let _ = self.routes.lock().unwrap().send(Route::Leave(instance));
+    }
+    /// the number of instances running on this reactor
+    pub fn instances(&self) -> usize {
+        self.instances.lock().unwrap().live.len()
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        self.shutdown.take();
+        if let Some(thread) = self.thread.take() {
+            // a task on the reactor's thread can hold the last handle, and
+            // the thread can't wait for itself to end
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Reactor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reactor")
+            .field("instances", &self.instances())
+            .finish()
+    }
+}
+
+/// the mio token of socket token in instance
+pub(crate) fn token(instance: usize, token: usize) -> mio::Token {
+    mio::Token(instance << TOKEN_BITS | token)
+}
+
+type Started = mpsc::Sender<io::Result<tokio_current_thread::Handle>>;
+
+// runs the reactor's tasks until it is dropped
+fn run(driver: Driver, started: &Started, stopped: oneshot::Receiver<()>) {
+    let mut enter = tokio_executor::enter().unwrap();
+    let io = driver.io.handle();
+    let timer = Timer::new(driver);
+    let timers = timer.handle();
+    let mut executor = CurrentThread::new_with_park(timer);
+    let _ = started.send(Ok(executor.handle()));
+    tokio_reactor::with_default(&io, &mut enter, |enter| {
+        tokio_timer::with_default(&timers, enter, |enter| {
+            // so hyper and the resolver can spawn their own tasks
+            let mut default = TaskExecutor::current();
+            tokio_executor::with_default(&mut default, enter, |enter| {
+                // tasks that haven't finished are dropped with the executor
+                let _ = executor.enter(enter).block_on(stopped);
+            })
+        })
+    });
+}
+
+/// Wakes the reactor's thread when a task is notified from another one
+struct Wakeup(SetReadiness);
+
+impl Unpark for Wakeup {
+    fn unpark(&self) {
+        let _ = self.0.set_readiness(Ready::readable());
+    }
+}
+
+/// Parks the reactor's thread in the mio poll and delivers what it returns
+struct Driver {
+    poll: Arc<mio::Poll>,
+    io: tokio_reactor::Reactor,
+    // kept registered for the wakeups
+    _registration: Registration,
+    wakeup: SetReadiness,
+    events: mio::Events,
+    routes: mpsc::Receiver<Route>,
+    instances: HashMap<usize, mpsc::Sender<Message>>,
+    batches: HashMap<usize, Vec<mio::Event>>,
+}
+
+impl Driver {
+    fn new(poll: Arc<mio::Poll>, routes: mpsc::Receiver<Route>) -> io::Result<Self> {
+        let io = tokio_reactor::Reactor::new()?;
+        let fd = io.as_raw_fd();
+        poll.register(&EventedFd(&fd), IO, Ready::readable(), PollOpt::level())?;
+        let (registration, wakeup) = Registration::new2();
+        // edge triggered, so every wakeup queues it again without a reset
+        poll.register(&registration, WAKEUP, Ready::readable(), PollOpt::edge())?;
+        Ok(Self {
+            poll,
+            io,
+            _registration: registration,
+            wakeup,
+            events: mio::Events::with_capacity(1024),
+            routes,
+            instances: HashMap::new(),
+            batches: HashMap::new(),
+        })
+    }
+    fn turn(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.poll.poll(&mut self.events, timeout)?;
+        for route in self.routes.try_iter() {
+            match route {
+                Route::Join(instance, sender) => self.instances.insert(instance, sender),
+                Route::Leave(instance) => self.instances.remove(&instance),
+            };
+        }
+        let mut io = false;
+        for event in self.events.iter() {
+            match event.token() {
+                IO => io = true,
+                WAKEUP => {}
+                Token(t) => {
+                    let instance = t >> TOKEN_BITS;
+                    // events of sockets that were closed as the instance left
+                    // are dropped
+                    if self.instances.contains_key(&instance) {
+                        self.batches
+                            .entry(instance)
+                            .or_insert_with(Vec::new)
+                            .push(mio::Event::new(event.readiness(), Token(t & MAX_TOKEN)));
+                    }
+                }
+            }
+        }
+        if io {
+            // notifies the tasks waiting on tokio's sockets
+            self.io.turn(Some(Duration::from_millis(0)))?;
+        }
+        for (instance, batch) in self.batches.drain() {
+            if self.instances[&instance]
+                .send(Message::Events(batch))
+                .is_err()
+            {
+                // the instance's receiver has been dropped
+                self.instances.remove(&instance);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Park for Driver {
+    type Unpark = Wakeup;
+    type Error = io::Error;
+
+    fn unpark(&self) -> Wakeup {
+        Wakeup(self.wakeup.clone())
+    }
+    fn park(&mut self) -> io::Result<()> {
+        self.turn(None)
+    }
+    fn park_timeout(&mut self, duration: Duration) -> io::Result<()> {
+        self.turn(Some(duration))
+    }
+}
+
+/// A fixed set of reactors that instances are spread across
+pub struct Reactors {
+    shards: Vec<Arc<Reactor>>,
+}
+
+impl Reactors {
+    /// count reactors, at least one
+    pub fn new(count: usize) -> Result<Self, Error> {
+        let shards = (0..count.max(1))
+            .map(|_| Reactor::new())
+            .collect::<Result<_, _>>()?;
+        Ok(Self { shards })
+    }
+    /// one reactor for each online core
+    pub fn per_core() -> Result<Self, Error> {
+        let cores = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        Self::new(if cores > 0 { cores as usize } else { 1 })
+    }
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+    /// the reactor with the fewest instances
+    pub fn pick(&self) -> Arc<Reactor> {
+        self.shards
+            .iter()
+            .min_by_key(|shard| shard.instances())
+            .unwrap()
+            .clone()
+    }
+}
+
+impl fmt::Debug for Reactors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.shards.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{future, Future};
+
+    #[test]
+    fn spreads_instances() {
+        let reactors = Reactors::new(2).unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        let a = reactors.pick();
+        let id = a.join(sender.clone()).unwrap();
+        let b = reactors.pick();
+        assert!(!Arc::ptr_eq(&a, &b));
+        b.join(sender).unwrap();
+        a.leave(id);
+        assert!(Arc::ptr_eq(&reactors.pick(), &a));
+    }
+
+    #[test]
+    fn tokens_carry_the_instance() {
+        let mio::Token(t) = token(3, 7);
+        assert_eq!(t >> TOKEN_BITS, 3);
+        assert_eq!(t & MAX_TOKEN, 7);
+        // mio reserves usize::MAX
+        assert_ne!(token(2, MAX_TOKEN).0, usize::max_value());
+    }
+
+    #[test]
+    fn skips_ids_in_use() {
+        let reactor = Reactor::new().unwrap();
+        let (sender, _receiver) = mpsc::channel();
+        assert_eq!(reactor.join(sender.clone()).unwrap(), 0);
+        reactor.instances.lock().unwrap().next = MAX_INSTANCE - 1;
+        assert_eq!(reactor.join(sender.clone()).unwrap(), MAX_INSTANCE - 1);
+        // wraps around past the instance that's still running
+        assert_eq!(reactor.join(sender.clone()).unwrap(), 1);
+        reactor.leave(0);
+        reactor.instances.lock().unwrap().next = 0;
+        assert_eq!(reactor.join(sender).unwrap(), 0);
+        assert_eq!(reactor.instances(), 3);
+    }
+
+    #[test]
+    fn batches_events() {
+        let reactor = Reactor::new().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let instance = reactor.join(sender).unwrap();
+        let (a, a_set) = mio::Registration::new2();
+        let (b, b_set) = mio::Registration::new2();
+        for (i, registration) in [&a, &b].iter().enumerate() {
+            reactor
+                .poll()
+                .register(
+                    *registration,
+                    token(instance, i),
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )
+                .unwrap();
+        }
+        a_set.set_readiness(Ready::readable()).unwrap();
+        b_set.set_readiness(Ready::readable()).unwrap();
+        let mut tokens = Vec::new();
+        while tokens.len() < 2 {
+            match receiver.recv().unwrap() {
+                Message::Events(events) => {
+                    tokens.extend(events.iter().map(|event| event.token().0))
+                }
+                _ => panic!("expected events"),
+            }
+        }
+        tokens.sort();
+        assert_eq!(tokens, vec![0, 1]);
+    }
+
+    #[test]
+    fn stops_when_dropped() {
+        let reactor = Reactor::new().unwrap();
+        let (sender, receiver) = mpsc::channel::<()>();
+        // a task that never finishes on its own
+        let task = future::empty::<(), ()>().map(move |()| drop(sender));
+        let _ = reactor.executor().spawn(task);
+        // returns once the thread has exited and dropped the task
+        drop(reactor);
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn can_be_dropped_on_its_thread() {
+        let reactor = Reactor::new().unwrap();
+        let handle = reactor.clone();
+        let (release, released) = oneshot::channel::<()>();
+        let (sender, receiver) = mpsc::channel();
+        let task = released.then(move |_| {
+            // the last handle, so the reactor is dropped on its own thread
+            drop(handle);
+            sender.send(()).unwrap();
+            Ok(())
+        });
+        let _ = reactor.executor().spawn(task);
+        drop(reactor);
+        release.send(()).unwrap();
+        assert!(receiver.recv().is_ok());
+    }
+}
@@ -0,0 +1,690 @@
+//! Network capture and replay
+//!
+//! A Capture logs the guest's TCP connections and DNS answers, one event per
+//! line, so a run against real services can be replayed offline later:
+//!
+//! ```text
+//! lookup ip example.com 93.184.216.34 2606:2800:220:1::1
+//! lookup-error mx nope.example not-found
+//! connect 1 10.0.0.2:51234 93.184.216.34:80
+//! write 1 474554202f20485454502f312e310d0a0d0a
+//! read 1 485454502f312e3120323030204f4b0d0a
+//! eof 1
+//! close 1
+//! accept 2 0.0.0.0:8080 127.0.0.1:8080 127.0.0.1:40112
+//! ```
+//!
+//! Connections are numbered in the order they were made. Connects record
+//! the local and remote address, accepts the address the guest listened on
+//! followed by the local and remote address. Data is hex encoded and is the
+//! plaintext of TLS streams. `eof` is a read that returned nothing.
+//!
+//! A Replay serves a capture back without real sockets. The nth connect to
+//! an address gets the nth connection recorded to it, and listening on an
+//! address queues the connections that were accepted on it. Each recorded
+//! read is held back until the guest has written as many bytes as it did
+//! before that read, so request/response protocols see their answers in
+//! order. What the guest writes is counted but not compared.
+
+use crate::dns::{MxRecord, RecordKind, SrvRecord};
+use crate::vnet::{VirtualListener, VirtualNetwork, VirtualStream};
+use crate::Response;
+use failure::{err_msg, Error};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::str::FromStr;
+
+/// Writes the network events of an IOLoop to a sink
+pub struct Capture {
+    // written to from reads and writes, which only borrow the IOLoop
+    sink: RefCell<Box<dyn Write + Send>>,
+    next_conn: u64,
+    // recorded connection of each stream token
+    conns: HashMap<usize, u64>,
+    // the addresses the guest listened on, by listener token
+    listeners: HashMap<usize, SocketAddr>,
+    // lookups waiting for their answer, by callback id
+    lookups: HashMap<i64, (&'static str, String)>,
+    // why the sink couldn't be written to. Nothing more is recorded after
+    // that, as the capture would have a hole in it.
+    failed: RefCell<Option<String>>,
+}
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Capture")
+            .field("conns", &self.conns)
+            .field("listeners", &self.listeners)
+            .field("lookups", &self.lookups)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+impl Capture {
+    pub fn new(sink: Box<dyn Write + Send>) -> Self {
+        Self {
+            sink: RefCell::new(sink),
+            next_conn: 1,
+            conns: HashMap::new(),
+            listeners: HashMap::new(),
+            lookups: HashMap::new(),
+            failed: RefCell::new(None),
+        }
+    }
+    /// Errs once the sink couldn't be written to, so that network operations
+    /// fail instead of going unrecorded
+    pub fn check(&self) -> Result<(), Error> {
+        match &*self.failed.borrow() {
+            Some(err) => Err(err_msg(format!("failed to write network capture: {}", err))),
+            None => Ok(()),
+        }
+    }
+    fn conn(&mut self, token: usize) -> u64 {
+        let conn = self.next_conn;
+        self.next_conn += 1;
+        self.conns.insert(token, conn);
+        conn
+    }
+    pub fn connect(&mut self, token: usize, local: SocketAddr, peer: SocketAddr) {
+        let conn = self.conn(token);
+        self.write_line(&format!("connect {} {} {}\n", conn, local, peer));
+    }
+    pub fn listen(&mut self, token: usize, addr: SocketAddr) {
+        self.listeners.insert(token, addr);
+    }
+    pub fn accept(&mut self, token: usize, listener: usize, local: SocketAddr, peer: SocketAddr) {
+        let listener = match self.listeners.get(&listener) {
+            Some(addr) => *addr,
+            None => return,
+        };
+        let conn = self.conn(token);
+        self.write_line(&format!(
+            "accept {} {} {} {}\n",
+            conn, listener, local, peer
+        ));
+    }
+    /// record what a read returned. Empty data is recorded as EOF.
+    pub fn read(&self, token: usize, data: &[u8]) {
+        if let Some(conn) = self.conns.get(&token) {
+            if data.is_empty() {
+                self.write_line(&format!("eof {}\n", conn));
+            } else {
+                self.write_line(&format!("read {} {}\n", conn, hex(data)));
+            }
+        }
+    }
+    pub fn write(&self, token: usize, data: &[u8]) {
+        if let Some(conn) = self.conns.get(&token) {
+            self.write_line(&format!("write {} {}\n", conn, hex(data)));
+        }
+    }
+    pub fn close(&mut self, token: usize) {
+        self.listeners.remove(&token);
+        if let Some(conn) = self.conns.remove(&token) {
+            self.write_line(&format!("close {}\n", conn));
+        }
+    }
+    /// remember a lookup so its answer can be recorded by complete
+    pub fn lookup(&mut self, id: i64, kind: Option<RecordKind>, host: &str) {
+        self.lookups
+            .insert(id, (kind.map_or("ip", kind_name), host.to_string()));
+    }
+    pub fn complete(&mut self, resp: &Response) {
+        let (kind, host) = match resp.id().and_then(|id| self.lookups.remove(&id)) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        let answers: Vec<String> = match resp {
+            Response::Ips { ips, .. } => ips.iter().map(|ip| ip.to_string()).collect(),
+            Response::Names { names, .. } => {
+                names.iter().map(|name| hex(name.as_bytes())).collect()
+            }
+            Response::Mx { mx, .. } => mx
+                .iter()
+                .map(|mx| format!("{}:{}", mx.pref, mx.host))
+                .collect(),
+            Response::Srv { srv, .. } => srv
+                .iter()
+                .map(|srv| {
+                    format!(
+                        "{}:{}:{}:{}",
+                        srv.priority, srv.weight, srv.port, srv.target
+                    )
+                })
+                .collect(),
+            Response::Error { kind: err, .. } => {
+                let err = match err {
+                    io::ErrorKind::TimedOut => "timed-out",
+                    _ => "not-found",
+                };
+                self.write_line(&format!("lookup-error {} {} {}\n", kind, host, err));
+                return;
+            }
+            _ => return,
+        };
+        let mut line = format!("lookup {} {}", kind, host);
+        for answer in answers {
+            line.push(' ');
+            line.push_str(&answer);
+        }
+        line.push('\n');
+        self.write_line(&line);
+    }
+    fn write_line(&self, line: &str) {
+        if self.failed.borrow().is_some() {
+            return;
+        }
+        if let Err(err) = self.sink.borrow_mut().write_all(line.as_bytes()) {
+            *self.failed.borrow_mut() = Some(err.to_string());
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Answer {
+    Ips(Vec<IpAddr>),
+    Names(Vec<String>),
+    Mx(Vec<MxRecord>),
+    Srv(Vec<SrvRecord>),
+    Failed(io::ErrorKind),
+}
+
+#[derive(Debug)]
+enum Step {
+    // bytes the guest wrote before the next step
+    Write(usize),
+    Read(Vec<u8>),
+    Eof,
+}
+
+#[derive(Debug)]
+struct Conversation {
+    local: SocketAddr,
+    peer: SocketAddr,
+    steps: VecDeque<Step>,
+}
+
+/// The remote end of a replayed connection
+#[derive(Debug)]
+pub struct Playback {
+    end: VirtualStream,
+    local: SocketAddr,
+    peer: SocketAddr,
+    // advanced from reads and writes, which only borrow the IOLoop
+    steps: RefCell<VecDeque<Step>>,
+}
+
+impl Playback {
+    fn new(end: VirtualStream, conversation: Conversation) -> Self {
+        Self {
+            end,
+            local: conversation.local,
+            peer: conversation.peer,
+            steps: RefCell::new(conversation.steps),
+        }
+    }
+    /// play as many steps as the guest's reads and writes so far allow
+    fn pump(&self) {
+        let mut steps = self.steps.borrow_mut();
+        let mut buf = [0u8; 4096];
+        loop {
+            let result = match steps.front_mut() {
+                Some(Step::Write(len)) => {
+                    let want = (*len).min(buf.len());
+                    self.end.read(&mut buf[..want]).map(|n| {
+                        // the guest closing its side ends the write too
+                        *len -= if n == 0 { *len } else { n };
+                        *len == 0
+                    })
+                }
+                Some(Step::Read(data)) => self.end.write(data).map(|n| {
+                    data.drain(..n);
+                    data.is_empty()
+                }),
+                Some(Step::Eof) => self.end.shutdown(Shutdown::Write).map(|_| true),
+                // writes beyond the capture are discarded so the guest
+                // doesn't block on them
+                None => match self.end.read(&mut buf) {
+                    Ok(0) => return,
+                    result => result.map(|_| false),
+                },
+            };
+            match result {
+                Ok(true) => {
+                    steps.pop_front();
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    if err.raw_os_error() != Some(libc::EAGAIN) {
+                        // the guest is gone, nothing else can be played
+                        steps.clear();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Serves a capture to an IOLoop in place of the network
+pub struct Replay {
+    lookups: HashMap<(String, String), VecDeque<Answer>>,
+    connects: HashMap<SocketAddr, VecDeque<Conversation>>,
+    accepts: HashMap<SocketAddr, VecDeque<Conversation>>,
+    // replayed listeners live on a network of their own
+    network: VirtualNetwork,
+    // accepted connections waiting in a listener's backlog, by name
+    backlogs: HashMap<String, VecDeque<Playback>>,
+    listeners: HashMap<usize, SocketAddr>,
+    streams: HashMap<usize, Playback>,
+}
+
+impl fmt::Debug for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("listeners", &self.listeners)
+            .field("streams", &self.streams)
+            .finish()
+    }
+}
+
+impl FromStr for Replay {
+    type Err = Error;
+    fn from_str(capture: &str) -> Result<Self, Error> {
+        let mut lookups: HashMap<(String, String), VecDeque<Answer>> = HashMap::new();
+        let mut conversations = HashMap::new();
+        let mut connects = Vec::new();
+        let mut accepts = Vec::new();
+        for (i, line) in capture.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            let parsed = parse_line(&fields).and_then(|event| {
+                match event {
+                    Event::Lookup(kind, host, answer) => {
+                        lookups.entry((kind, host)).or_default().push_back(answer)
+                    }
+                    Event::Connect(conn, conversation) => {
+                        connects.push((conversation.peer, conn));
+                        conversations.insert(conn, conversation);
+                    }
+                    Event::Accept(conn, listener, conversation) => {
+                        accepts.push((listener, conn));
+                        conversations.insert(conn, conversation);
+                    }
+                    Event::Step(conn, step) => match conversations.get_mut(&conn) {
+                        Some(conversation) => add_step(conversation, step),
+                        None => return Err(err_msg(format!("unknown connection {}", conn))),
+                    },
+                    Event::Close => {}
+                }
+                Ok(())
+            });
+            if let Err(err) = parsed {
+                return Err(err_msg(format!("line {}: {}", i + 1, err)));
+            }
+        }
+        let mut queue = |events: Vec<(SocketAddr, u64)>| {
+            let mut queues: HashMap<SocketAddr, VecDeque<Conversation>> = HashMap::new();
+            for (addr, conn) in events {
+                if let Some(conversation) = conversations.remove(&conn) {
+                    queues.entry(addr).or_default().push_back(conversation);
+                }
+            }
+            queues
+        };
+        let connects = queue(connects);
+        let accepts = queue(accepts);
+        Ok(Self {
+            lookups,
+            connects,
+            accepts,
+            network: VirtualNetwork::new(),
+            backlogs: HashMap::new(),
+            listeners: HashMap::new(),
+            streams: HashMap::new(),
+        })
+    }
+}
+
+impl Replay {
+    /// the recorded answer to a lookup. Lookups are answered in the order
+    /// they were recorded and the last answer is repeated.
+    pub fn lookup(&mut self, id: i64, kind: Option<RecordKind>, host: &str) -> Response {
+        let key = (kind.map_or("ip", kind_name).to_string(), host.to_string());
+        let answer = self.lookups.get_mut(&key).and_then(|answers| {
+            if answers.len() > 1 {
+                answers.pop_front()
+            } else {
+                answers.front().cloned()
+            }
+        });
+        match answer {
+            Some(Answer::Ips(ips)) => Response::Ips {
+                id,
+                host: host.to_string(),
+                ips,
+            },
+            Some(Answer::Names(names)) => Response::Names { id, names },
+            Some(Answer::Mx(mx)) => Response::Mx { id, mx },
+            Some(Answer::Srv(srv)) => Response::Srv { id, srv },
+            Some(Answer::Failed(kind)) => Response::Error {
+                id,
+                msg: format!("lookup {}: failed when it was recorded", host),
+                kind,
+            },
+            None => Response::Error {
+                id,
+                msg: format!("lookup {}: not in the network capture", host),
+                kind: io::ErrorKind::NotFound,
+            },
+        }
+    }
+    /// the guest's end of the next connection recorded to addr. Fails with
+    /// ECONNREFUSED when there are no more.
+    pub fn connect(&mut self, addr: &SocketAddr) -> io::Result<(VirtualStream, Playback)> {
+        let conversation = self
+            .connects
+            .get_mut(addr)
+            .and_then(|conversations| conversations.pop_front())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ECONNREFUSED))?;
+        let (guest, end) = VirtualStream::pair();
+        Ok((guest, Playback::new(end, conversation)))
+    }
+    /// a listener with every connection that was accepted on addr already
+    /// in its backlog
+    pub fn listen(&mut self, addr: &SocketAddr) -> io::Result<VirtualListener> {
+        let name = addr.to_string();
+        let listener = self.network.listen(&name)?;
+        let mut backlog = VecDeque::new();
+        for conversation in self.accepts.remove(addr).unwrap_or_default() {
+            backlog.push_back(Playback::new(self.network.connect(&name)?, conversation));
+        }
+        self.backlogs.insert(name, backlog);
+        Ok(listener)
+    }
+    pub fn listening(&mut self, token: usize, addr: SocketAddr) {
+        self.listeners.insert(token, addr);
+    }
+    pub fn accept(&mut self, listener: &VirtualListener) -> io::Result<(VirtualStream, Playback)> {
+        let stream = listener.accept()?;
+        let playback = self
+            .backlogs
+            .get_mut(listener.name())
+            .and_then(|backlog| backlog.pop_front())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ECONNABORTED))?;
+        Ok((stream, playback))
+    }
+    /// start playing a connection once its stream has a token
+    pub fn play(&mut self, token: usize, playback: Playback) {
+        playback.pump();
+        self.streams.insert(token, playback);
+    }
+    pub fn is_replayed(&self, token: usize) -> bool {
+        self.streams.contains_key(&token)
+    }
+    pub fn pump(&self, token: usize) {
+        if let Some(playback) = self.streams.get(&token) {
+            playback.pump();
+        }
+    }
+    pub fn local_addr(&self, token: usize) -> Option<SocketAddr> {
+        self.listeners
+            .get(&token)
+            .cloned()
+            .or_else(|| self.streams.get(&token).map(|playback| playback.local))
+    }
+    pub fn peer_addr(&self, token: usize) -> Option<SocketAddr> {
+        self.streams.get(&token).map(|playback| playback.peer)
+    }
+    pub fn close(&mut self, token: usize) {
+        self.listeners.remove(&token);
+        self.streams.remove(&token);
+    }
+}
+
+enum Event {
+    Lookup(String, String, Answer),
+    Connect(u64, Conversation),
+    Accept(u64, SocketAddr, Conversation),
+    Step(u64, Step),
+    Close,
+}
+
+fn parse_line(fields: &[&str]) -> Result<Event, Error> {
+    let field = |i: usize| {
+        fields
+            .get(i)
+            .cloned()
+            .ok_or_else(|| err_msg(format!("{} is missing a field", fields[0])))
+    };
+    let conn = || -> Result<u64, Error> { Ok(field(1)?.parse()?) };
+    let conversation = |local: &str, peer: &str| -> Result<Conversation, Error> {
+        Ok(Conversation {
+            local: local.parse()?,
+            peer: peer.parse()?,
+            steps: VecDeque::new(),
+        })
+    };
+    Ok(match fields[0] {
+        "lookup" => {
+            let kind = field(1)?;
+            let answer = parse_answer(kind, &fields[3.min(fields.len())..])?;
+            Event::Lookup(kind.to_string(), field(2)?.to_string(), answer)
+        }
+        "lookup-error" => {
+            let kind = match field(3)? {
+                "timed-out" => io::ErrorKind::TimedOut,
+                "not-found" => io::ErrorKind::NotFound,
+                err => return Err(err_msg(format!("unknown lookup error {}", err))),
+            };
+            Event::Lookup(
+                field(1)?.to_string(),
+                field(2)?.to_string(),
+                Answer::Failed(kind),
+            )
+        }
+        "connect" => Event::Connect(conn()?, conversation(field(2)?, field(3)?)?),
+        "accept" => Event::Accept(
+            conn()?,
+            field(2)?.parse()?,
+            conversation(field(3)?, field(4)?)?,
+        ),
+        "write" => Event::Step(conn()?, Step::Write(unhex(field(2)?)?.len())),
+        "read" => Event::Step(conn()?, Step::Read(unhex(field(2)?)?)),
+        "eof" => Event::Step(conn()?, Step::Eof),
+        "close" => Event::Close,
+        event => return Err(err_msg(format!("unknown event {}", event))),
+    })
+}
+
+fn parse_answer(kind: &str, answers: &[&str]) -> Result<Answer, Error> {
+    let fields = |answer: &str, n: usize| -> Result<Vec<String>, Error> {
+        let fields: Vec<String> = answer.splitn(n, ':').map(|f| f.to_string()).collect();
+        if fields.len() != n {
+            return Err(err_msg(format!("invalid {} answer {}", kind, answer)));
+        }
+        Ok(fields)
+    };
+    if kind == "ip" {
+        let ips: Result<Vec<IpAddr>, _> = answers.iter().map(|ip| ip.parse()).collect();
+        return Ok(Answer::Ips(ips?));
+    }
+    Ok(match kind.parse::<RecordKind>()? {
+        RecordKind::Mx => Answer::Mx(
+            answers
+                .iter()
+                .map(|answer| {
+                    let fields = fields(answer, 2)?;
+                    Ok(MxRecord {
+                        pref: fields[0].parse()?,
+                        host: fields[1].clone(),
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        ),
+        RecordKind::Srv => Answer::Srv(
+            answers
+                .iter()
+                .map(|answer| {
+                    let fields = fields(answer, 4)?;
+                    Ok(SrvRecord {
+                        priority: fields[0].parse()?,
+                        weight: fields[1].parse()?,
+                        port: fields[2].parse()?,
+                        target: fields[3].clone(),
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        ),
+        _ => Answer::Names(
+            answers
+                .iter()
+                .map(|answer| Ok(String::from_utf8(unhex(answer)?)?))
+                .collect::<Result<_, Error>>()?,
+        ),
+    })
+}
+
+/// consecutive writes are merged, as only their length matters
+fn add_step(conversation: &mut Conversation, step: Step) {
+    if let (Some(Step::Write(len)), Step::Write(more)) = (conversation.steps.back_mut(), &step) {
+        *len += more;
+        return;
+    }
+    conversation.steps.push_back(step);
+}
+
+fn kind_name(kind: RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Txt => "txt",
+        RecordKind::Mx => "mx",
+        RecordKind::Srv => "srv",
+        RecordKind::Cname => "cname",
+        RecordKind::Ptr => "ptr",
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, Error> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(err_msg(format!("invalid hex {}", s)));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn would_block(result: io::Result<usize>) -> bool {
+        result.err().and_then(|e| e.raw_os_error()) == Some(libc::EAGAIN)
+    }
+
+    #[test]
+    fn parses_captures() {
+        let mut replay: Replay = "
+            # a comment
+            lookup ip db.internal 10.0.0.5
+            lookup txt db.internal 763d31 68656c6c6f20776f726c64
+            lookup mx db.internal 10:mail.internal
+            lookup srv db.internal 1:5:5432:db.internal
+            lookup-error ip nope.internal timed-out
+        "
+        .parse()
+        .unwrap();
+        match replay.lookup(1, None, "db.internal") {
+            Response::Ips { id, ips, .. } => {
+                assert_eq!(id, 1);
+                assert_eq!(ips, vec!["10.0.0.5".parse::<IpAddr>().unwrap()]);
+            }
+            resp => panic!("unexpected {:?}", resp),
+        }
+        match replay.lookup(2, Some(RecordKind::Txt), "db.internal") {
+            Response::Names { names, .. } => assert_eq!(names, vec!["v=1", "hello world"]),
+            resp => panic!("unexpected {:?}", resp),
+        }
+        match replay.lookup(3, Some(RecordKind::Srv), "db.internal") {
+            Response::Srv { srv, .. } => assert_eq!(srv[0].port, 5432),
+            resp => panic!("unexpected {:?}", resp),
+        }
+        match replay.lookup(4, None, "nope.internal") {
+            Response::Error { kind, .. } => assert_eq!(kind, io::ErrorKind::TimedOut),
+            resp => panic!("unexpected {:?}", resp),
+        }
+        match replay.lookup(5, None, "other.internal") {
+            Response::Error { kind, .. } => assert_eq!(kind, io::ErrorKind::NotFound),
+            resp => panic!("unexpected {:?}", resp),
+        }
+
+        let err = "connect 1 127.0.0.1:1 127.0.0.1:2\nread 2 00"
+            .parse::<Replay>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unknown connection 2");
+        assert!("read 1 0".parse::<Replay>().is_err());
+        assert!("listen 127.0.0.1:80".parse::<Replay>().is_err());
+    }
+
+    #[test]
+    fn replays_accepted_connections() {
+        let mut replay: Replay = "
+            accept 1 0.0.0.0:80 127.0.0.1:80 127.0.0.1:4000
+            read 1 7069
+            read 1 6e67
+            write 1 706f6e67
+            read 1 71756974
+            eof 1
+        "
+        .parse()
+        .unwrap();
+        let addr = "0.0.0.0:80".parse().unwrap();
+        assert!(replay.connect(&addr).is_err());
+        let listener = replay.listen(&addr).unwrap();
+        let (stream, playback) = replay.accept(&listener).unwrap();
+        assert!(replay.accept(&listener).is_err());
+        replay.play(7, playback);
+        assert_eq!(replay.peer_addr(7), Some("127.0.0.1:4000".parse().unwrap()));
+
+        let mut buf = [0u8; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        // the rest waits for the guest's answer
+        assert!(would_block(stream.read(&mut buf)));
+        stream.write(b"pong").unwrap();
+        replay.pump(7);
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"quit");
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+    }
+
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fails_once_the_sink_does() {
+        let mut capture = Capture::new(Box::new(Full));
+        assert!(capture.check().is_ok());
+        let addr: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        capture.connect(1, addr, addr);
+        assert!(capture.check().is_err());
+    }
+}
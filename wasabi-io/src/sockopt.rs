@@ -0,0 +1,107 @@
+//! Socket options
+//!
+//! A SockOpt is an option the guest sets on one of its tcp streams. Guests
+//! pass options as a code and an integer value, which are validated before
+//! anything touches the socket. Buffer sizes and linger times are then
+//! capped by the instance's SockOptLimits, the same way the kernel silently
+//! caps buffer sizes at its own maximum.
+
+use std::io;
+use std::time::Duration;
+
+const NO_DELAY: i32 = 1;
+const KEEP_ALIVE: i32 = 2;
+const LINGER: i32 = 3;
+const RECV_BUFFER: i32 = 4;
+const SEND_BUFFER: i32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SockOpt {
+    NoDelay(bool),
+    /// the idle time before keepalive probes are sent, None disables them
+    KeepAlive(Option<Duration>),
+    /// how long close waits for unsent data, None closes in the background
+    Linger(Option<Duration>),
+    RecvBuffer(usize),
+    SendBuffer(usize),
+}
+
+impl SockOpt {
+    /// decode an option passed by the guest. Keepalive and linger values are
+    /// seconds, a keepalive of 0 or less disables keepalive and a negative
+    /// linger restores the default behavior. Buffer sizes must be positive.
+    pub fn from_code(code: i32, value: i64) -> Result<Self, io::Error> {
+        let invalid = || io::Error::from_raw_os_error(libc::EINVAL);
+        Ok(match code {
+            NO_DELAY => match value {
+                0 => SockOpt::NoDelay(false),
+                1 => SockOpt::NoDelay(true),
+                _ => return Err(invalid()),
+            },
+            KEEP_ALIVE if value <= 0 => SockOpt::KeepAlive(None),
+            KEEP_ALIVE => SockOpt::KeepAlive(Some(Duration::from_secs(value as u64))),
+            LINGER if value < 0 => SockOpt::Linger(None),
+            LINGER => SockOpt::Linger(Some(Duration::from_secs(value as u64))),
+            RECV_BUFFER | SEND_BUFFER if value <= 0 || value > i64::from(i32::max_value()) => {
+                return Err(invalid())
+            }
+            RECV_BUFFER => SockOpt::RecvBuffer(value as usize),
+            SEND_BUFFER => SockOpt::SendBuffer(value as usize),
+            _ => return Err(io::Error::from_raw_os_error(libc::ENOPROTOOPT)),
+        })
+    }
+    /// cap the option at limits
+    pub fn limit(self, limits: &SockOptLimits) -> Self {
+        match (self, limits.max_buffer, limits.max_linger) {
+            (SockOpt::RecvBuffer(size), Some(max), _) => SockOpt::RecvBuffer(size.min(max)),
+            (SockOpt::SendBuffer(size), Some(max), _) => SockOpt::SendBuffer(size.min(max)),
+            (SockOpt::Linger(Some(linger)), _, Some(max)) => SockOpt::Linger(Some(linger.min(max))),
+            (opt, _, _) => opt,
+        }
+    }
+}
+
+/// Per instance caps on socket options. None leaves the value to the kernel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SockOptLimits {
+    pub max_buffer: Option<usize>,
+    pub max_linger: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_and_limits_options() {
+        assert_eq!(SockOpt::from_code(1, 1).unwrap(), SockOpt::NoDelay(true));
+        assert_eq!(SockOpt::from_code(2, 0).unwrap(), SockOpt::KeepAlive(None));
+        assert_eq!(SockOpt::from_code(3, -1).unwrap(), SockOpt::Linger(None));
+        assert_eq!(
+            SockOpt::from_code(3, 0).unwrap(),
+            SockOpt::Linger(Some(Duration::from_secs(0)))
+        );
+        let err = SockOpt::from_code(4, 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        assert!(SockOpt::from_code(1, 2).is_err());
+        let err = SockOpt::from_code(42, 1).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOPROTOOPT));
+
+        let limits = SockOptLimits {
+            max_buffer: Some(4096),
+            max_linger: Some(Duration::from_secs(5)),
+        };
+        assert_eq!(
+            SockOpt::RecvBuffer(1 << 20).limit(&limits),
+            SockOpt::RecvBuffer(4096)
+        );
+        assert_eq!(
+            SockOpt::SendBuffer(1024).limit(&limits),
+            SockOpt::SendBuffer(1024)
+        );
+        assert_eq!(
+            SockOpt::Linger(Some(Duration::from_secs(60))).limit(&limits),
+            SockOpt::Linger(Some(Duration::from_secs(5)))
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Host TLS
+//!
+//! Guests can dial and listen with TLS done on the host by rustls, so
+//! handshakes and encryption run natively instead of in wasm. The guest sees
+//! a plaintext stream on the usual read, write and event calls.
+//!
+//! Servers are verified against the system's CA bundle, or against the CA
+//! bundles in the instance's TlsConfig when it has any. Listening needs the
+//! instance's certificate chain and key.
+
+use failure::{err_msg, Error};
+use mio::net::TcpStream;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, ClientSession, NoClientAuth, PrivateKey, RootCertStore,
+    ServerConfig, ServerSession, Session, TLSError,
+};
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// where distributions keep their CA bundle, the same list Go's crypto/x509
+// checks on linux
+const SYSTEM_BUNDLES: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/pki/tls/cacert.pem",
+    "/etc/pki/ca-trust/extracted/pem/tls-ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    ca_files: Vec<PathBuf>,
+    cert_file: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// trust the certificates in a PEM bundle. Once a bundle is added the
+    /// system's roots aren't used.
+    pub fn add_ca_file(&mut self, path: &str) {
+        self.ca_files.push(PathBuf::from(path));
+    }
+    /// the PEM certificate chain presented by listeners
+    pub fn set_cert_file(&mut self, path: &str) {
+        self.cert_file = Some(PathBuf::from(path));
+    }
+    /// the PEM key, PKCS#8 or RSA, for the certificate
+    pub fn set_key_file(&mut self, path: &str) {
+        self.key_file = Some(PathBuf::from(path));
+    }
+}
+
+/// The rustls configurations built from a TlsConfig
+pub struct Tls {
+    client: Arc<ClientConfig>,
+    server: Option<Arc<ServerConfig>>,
+}
+
+impl Tls {
+    pub fn new(config: &TlsConfig) -> Result<Self, Error> {
+        let mut client = ClientConfig::new();
+        if config.ca_files.is_empty() {
+            load_system_roots(&mut client.root_store)?;
+        }
+        for path in &config.ca_files {
+            load_roots(&mut client.root_store, path)?;
+        }
+        let server = match (&config.cert_file, &config.key_file) {
+            (Some(cert), Some(key)) => {
+                let mut server = ServerConfig::new(NoClientAuth::new());
+                server
+                    .set_single_cert(load_certs(cert)?, load_key(key)?)
+                    .map_err(|e| err_msg(format!("{}: {}", cert.display(), e)))?;
+                Some(Arc::new(server))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(err_msg(
+                    "a tls certificate needs a key and the other way around",
+                ))
+            }
+        };
+        Ok(Self {
+            client: Arc::new(client),
+            server,
+        })
+    }
+    /// a session for dialing server_name, which must be a dns name
+    pub fn client(&self, server_name: &str) -> Result<TlsSession, Error> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(server_name)
+            .map_err(|_| err_msg(format!("invalid tls server name {}", server_name)))?;
+        Ok(TlsSession::new(Box::new(ClientSession::new(
+            &self.client,
+            name,
+        ))))
+    }
+    /// the client config, for connections made by the host itself
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.client.clone()
+    }
+    /// a session for an accepted stream
+    pub fn server(&self) -> Result<TlsSession, Error> {
+        match &self.server {
+            Some(config) => Ok(TlsSession::new(Box::new(ServerSession::new(config)))),
+            None => Err(err_msg("no tls certificate configured")),
+        }
+    }
+    pub fn can_serve(&self) -> bool {
+        self.server.is_some()
+    }
+}
+
+// the rustls configs and sessions aren't Debug
+impl fmt::Debug for Tls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tls")
+            .field("can_serve", &self.can_serve())
+            .finish()
+    }
+}
+
+/// The TLS state of a tcp stream
+pub struct TlsSession {
+    // reads and writes only have a shared reference to the IOLoop
+    session: RefCell<Box<dyn Session>>,
+    // the peer closed the tcp stream
+    eof: Cell<bool>,
+}
+
+impl TlsSession {
+    fn new(session: Box<dyn Session>) -> Self {
+        Self {
+            session: RefCell::new(session),
+            eof: Cell::new(false),
+        }
+    }
+    /// reads plaintext, reading records from stream until there is some.
+    /// Errs with WouldBlock when the stream has nothing more to read.
+    pub fn read(&self, mut stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        let mut session = self.session.borrow_mut();
+        loop {
+            match session.read(buf) {
+                Ok(0) => {}
+                Ok(n) => return Ok(n),
+                // the peer sent close_notify
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                Err(err) => return Err(err),
+            }
+            if buf.is_empty() || self.eof.get() {
+                return Ok(0);
+            }
+            if session.read_tls(&mut stream)? == 0 {
+                self.eof.set(true);
+                continue;
+            }
+            let processed = session.process_new_packets();
+            // handshake messages, or the alert for a failed handshake
+            flush(&mut **session, stream)?;
+            processed.map_err(tls_error)?;
+        }
+    }
+    /// encrypts and writes plaintext. Errs with WouldBlock while earlier
+    /// records are waiting for the stream, so they can't pile up.
+    pub fn write(&self, stream: &TcpStream, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.borrow_mut();
+        flush(&mut **session, stream)?;
+        if session.wants_write() {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+        let n = session.write(buf)?;
+        flush(&mut **session, stream)?;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+        Ok(n)
+    }
+    /// writes pending records, for when the stream is writable again
+    pub fn flush(&self, stream: &TcpStream) -> io::Result<()> {
+        flush(&mut **self.session.borrow_mut(), stream)
+    }
+    /// tells the peer no more data will be sent
+    pub fn close_notify(&self, stream: &TcpStream) -> io::Result<()> {
+        let mut session = self.session.borrow_mut();
+        session.send_close_notify();
+        flush(&mut **session, stream)
+    }
+}
+
+impl fmt::Debug for TlsSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsSession")
+            .field("eof", &self.eof.get())
+            .finish()
+    }
+}
+
+fn flush(session: &mut dyn Session, mut stream: &TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        match session.write_tls(&mut stream) {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn tls_error(err: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("tls: {}", err))
+}
+
+fn load_system_roots(store: &mut RootCertStore) -> Result<(), Error> {
+    if let Some(path) = env::var_os("SSL_CERT_FILE") {
+        return load_roots(store, Path::new(&path));
+    }
+    match SYSTEM_BUNDLES
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+    {
+        Some(path) => load_roots(store, path),
+        None => Err(err_msg("no system CA bundle found")),
+    }
+}
+
+fn load_roots(store: &mut RootCertStore, path: &Path) -> Result<(), Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match store.add_pem_file(&mut reader) {
+        Ok((added, _)) if added > 0 => Ok(()),
+        _ => Err(err_msg(format!("no CA certificates in {}", path.display()))),
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(err_msg(format!("no certificates in {}", path.display()))),
+    }
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(key),
+        None => Err(err_msg(format!("no private key in {}", path.display()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn loads_config() {
+        let mut config = TlsConfig::default();
+        config.add_ca_file(&testdata("ca.pem"));
+        let tls = Tls::new(&config).unwrap();
+        assert!(!tls.can_serve());
+        assert!(tls.server().is_err());
+        assert!(tls.client("localhost").is_ok());
+        assert!(tls.client("not a name").is_err());
+
+        config.set_cert_file(&testdata("localhost.pem"));
+        assert!(Tls::new(&config).is_err());
+        config.set_key_file(&testdata("localhost.key"));
+        assert!(Tls::new(&config).unwrap().can_serve());
+
+        // a key isn't a CA bundle
+        let mut config = TlsConfig::default();
+        config.add_ca_file(&testdata("localhost.key"));
+        assert!(Tls::new(&config).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Virtual network
+//!
+//! Named in-memory endpoints that the IOLoops in one process can listen on
+//! and dial, so tenants can talk to each other without going through the
+//! kernel. A connection is a pair of bounded pipes. Readiness is reported
+//! through mio like it is for real sockets, and reads and writes fail with
+//! EAGAIN until there is data or room.
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+
+// bytes a connection buffers in each direction before writes would block
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+type Backlog = Arc<Mutex<VecDeque<VirtualStream>>>;
+
+struct Endpoint {
+    backlog: Backlog,
+    readiness: SetReadiness,
+}
+
+/// The endpoints IOLoops can reach each other on. Clones share the same
+/// endpoints.
+#[derive(Clone, Default)]
+pub struct VirtualNetwork {
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+}
+
+impl VirtualNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// the names that are currently listened on
+    pub fn endpoints(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.endpoints.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+    pub(crate) fn listen(&self, name: &str) -> io::Result<VirtualListener> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if endpoints.contains_key(name) {
+            return Err(io::Error::from_raw_os_error(libc::EADDRINUSE));
+        }
+        let (registration, readiness) = Registration::new2();
+        let backlog = Backlog::default();
+        endpoints.insert(
+            name.to_string(),
+            Endpoint {
+                backlog: backlog.clone(),
+                readiness,
+            },
+        );
+        Ok(VirtualListener {
+            name: name.to_string(),
+            network: self.clone(),
+            backlog,
+            registration,
+        })
+    }
+    pub(crate) fn connect(&self, name: &str) -> io::Result<VirtualStream> {
+        let endpoints = self.endpoints.lock().unwrap();
+        let endpoint = endpoints
+            .get(name)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ECONNREFUSED))?;
+        let (client, server) = VirtualStream::pair();
+        endpoint.backlog.lock().unwrap().push_back(server);
+        endpoint.readiness.set_readiness(Ready::readable())?;
+        Ok(client)
+    }
+}
+
+impl fmt::Debug for VirtualNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualNetwork")
+            .field("endpoints", &self.endpoints())
+            .finish()
+    }
+}
+
+/// A name listened on. Dropping it frees the name and refuses the
+/// connections that weren't accepted.
+pub struct VirtualListener {
+    name: String,
+    network: VirtualNetwork,
+    backlog: Backlog,
+    registration: Registration,
+}
+
+impl VirtualListener {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn accept(&self) -> io::Result<VirtualStream> {
+        self.backlog
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EAGAIN))
+    }
+}
+
+impl Evented for VirtualListener {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        Evented::register(&self.registration, poll, token, interest, opts)
+    }
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        Evented::reregister(&self.registration, poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+impl Drop for VirtualListener {
+    fn drop(&mut self) {
+        let mut endpoints = self.network.endpoints.lock().unwrap();
+        // the name may have been listened on again since
+        let ours = endpoints
+            .get(&self.name)
+            .map_or(false, |e| Arc::ptr_eq(&e.backlog, &self.backlog));
+        if ours {
+            endpoints.remove(&self.name);
+        }
+    }
+}
+
+impl fmt::Debug for VirtualListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualListener")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    // the writer is done, reads see EOF once buf is drained
+    write_closed: bool,
+    // the reader is gone, writes fail with EPIPE
+    read_closed: bool,
+}
+
+/// One end of a virtual connection
+pub struct VirtualStream {
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+    peer: SetReadiness,
+    registration: Registration,
+}
+
+impl VirtualStream {
+    pub(crate) fn pair() -> (Self, Self) {
+        let (a_registration, a_readiness) = Registration::new2();
+        let (b_registration, b_readiness) = Registration::new2();
+        let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+        let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+        // connected right away, so both ends can write
+        let _ = a_readiness.set_readiness(Ready::writable());
+        let _ = b_readiness.set_readiness(Ready::writable());
+        let a = Self {
+            incoming: b_to_a.clone(),
+            outgoing: a_to_b.clone(),
+            peer: b_readiness,
+            registration: a_registration,
+        };
+        let b = Self {
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            peer: a_readiness,
+            registration: b_registration,
+        };
+        (a, b)
+    }
+    pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.incoming.lock().unwrap();
+        if pipe.read_closed {
+            return Ok(0);
+        }
+        if pipe.buf.is_empty() {
+            if pipe.write_closed || buf.is_empty() {
+                return Ok(0);
+            }
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+        let n = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        drop(pipe);
+        // there's room for the peer to write again
+        self.notify_peer();
+        Ok(n)
+    }
+    pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        if pipe.write_closed || pipe.read_closed {
+            return Err(io::Error::from_raw_os_error(libc::EPIPE));
+        }
+        let n = buf.len().min(PIPE_CAPACITY - pipe.buf.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        }
+        pipe.buf.extend(&buf[..n]);
+        drop(pipe);
+        self.notify_peer();
+        Ok(n)
+    }
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.incoming.lock().unwrap().read_closed = true;
+        }
+        if how != Shutdown::Read {
+            self.outgoing.lock().unwrap().write_closed = true;
+        }
+        self.notify_peer();
+        Ok(())
+    }
+    fn notify_peer(&self) {
+        // readiness is only a hint to retry, so the peer is told about both
+        // rather than tracking which one changed
+        let _ = self
+            .peer
+            .set_readiness(Ready::readable() | Ready::writable());
+    }
+}
+
+impl Evented for VirtualStream {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        Evented::register(&self.registration, poll, token, interest, opts)
+    }
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        Evented::reregister(&self.registration, poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        Evented::deregister(&self.registration, poll)
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl fmt::Debug for VirtualStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtualStream").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn would_block(result: io::Result<usize>) -> bool {
+        result.err().and_then(|e| e.raw_os_error()) == Some(libc::EAGAIN)
+    }
+
+    #[test]
+    fn names() {
+        let network = VirtualNetwork::new();
+        assert!(network.connect("db").is_err());
+        let listener = network.listen("db").unwrap();
+        assert!(network.clone().listen("db").is_err());
+        assert_eq!(network.endpoints(), vec!["db".to_string()]);
+        assert!(listener.accept().is_err());
+
+        let _client = network.connect("db").unwrap();
+        assert!(listener.accept().is_ok());
+        drop(listener);
+        assert!(network.endpoints().is_empty());
+        assert!(network.connect("db").is_err());
+    }
+
+    #[test]
+    fn pipes() {
+        let (a, b) = VirtualStream::pair();
+        let mut buf = [0u8; 8];
+        assert!(would_block(b.read(&mut buf)));
+        assert_eq!(a.write(b"hello").unwrap(), 5);
+        assert_eq!(b.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // writes stop when the peer isn't reading
+        let big = vec![0u8; PIPE_CAPACITY + 1];
+        assert_eq!(a.write(&big).unwrap(), PIPE_CAPACITY);
+        assert!(would_block(a.write(b"x")));
+        assert_eq!(b.read(&mut buf).unwrap(), 8);
+        assert_eq!(a.write(b"x").unwrap(), 1);
+
+        // buffered data is still read after the writer is gone
+        drop(a);
+        let mut rest = vec![0u8; PIPE_CAPACITY];
+        assert_eq!(b.read(&mut rest).unwrap(), PIPE_CAPACITY - 8 + 1);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert!(b.write(b"x").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Filesystem change notifications
+//!
+//! A Watcher wraps a non-blocking inotify instance. Its fd is registered with
+//! the IOLoop's mio poll and read whenever it becomes readable. Each watch
+//! remembers the guest callback that its events are delivered to. inotify
+//! hands out one descriptor per path, so watches of the same path share it
+//! and it is only removed with the last of them.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// The kind of change a WatchEvent reports. The values are passed to the
+/// guest as is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Create = 1,
+    Modify = 2,
+    Delete = 3,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct WatchEvent {
+    pub callback: i64,
+    pub kind: WatchKind,
+    /// guest path of the file that changed
+    pub path: String,
+}
+
+#[derive(Debug)]
+struct Watch {
+    wd: i32,
+    callback: i64,
+    guest_path: String,
+}
+
+#[derive(Debug)]
+pub struct Watcher {
+    fd: RawFd,
+    watches: HashMap<usize, Watch>,
+    // the watches using each inotify descriptor
+    descriptors: HashMap<i32, Vec<usize>>,
+    next_id: usize,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            fd: sys::init()?,
+            watches: HashMap::new(),
+            descriptors: HashMap::new(),
+            next_id: 0,
+        })
+    }
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+    /// watch host_path for changes, returns the watch id
+    pub fn add(
+        &mut self,
+        callback: i64,
+        guest_path: String,
+        host_path: &Path,
+    ) -> io::Result<usize> {
+        let wd = sys::add_watch(self.fd, host_path)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watches.insert(
+            id,
+            Watch {
+                wd,
+                callback,
+                guest_path,
+            },
+        );
+        self.descriptors.entry(wd).or_default().push(id);
+        Ok(id)
+    }
+    pub fn remove(&mut self, id: usize) -> io::Result<()> {
+        let wd = match self.watches.remove(&id) {
+            Some(watch) => watch.wd,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "watch not found")),
+        };
+        let ids = self.descriptors.get_mut(&wd).unwrap();
+        ids.retain(|other| *other != id);
+        if !ids.is_empty() {
+            return Ok(());
+        }
+        self.descriptors.remove(&wd);
+        sys::rm_watch(self.fd, wd)
+    }
+    /// Drains all pending events. The fd is registered edge triggered so
+    /// this has to read until there is nothing left.
+    pub fn read_events(&mut self) -> io::Result<Vec<WatchEvent>> {
+        let mut events = Vec::new();
+        for (wd, mask, name) in sys::read_events(self.fd)? {
+            if mask & sys::IGNORED != 0 {
+                // the watch was removed or the watched path is gone
+                for id in self.descriptors.remove(&wd).unwrap_or_default() {
+                    self.watches.remove(&id);
+                }
+                continue;
+            }
+            let ids = match self.descriptors.get(&wd) {
+                Some(ids) => ids,
+                None => continue,
+            };
+            let kind = if mask & sys::CREATE != 0 {
+                WatchKind::Create
+            } else if mask & sys::DELETE != 0 {
+                WatchKind::Delete
+            } else if mask & sys::MODIFY != 0 {
+                WatchKind::Modify
+            } else {
+                continue;
+            };
+            for watch in ids.iter().map(|id| &self.watches[id]) {
+                let path = if name.is_empty() {
+                    watch.guest_path.clone()
+                } else {
+                    Path::new(&watch.guest_path)
+                        .join(&name)
+                        .to_string_lossy()
+                        .into_owned()
+                };
+                events.push(WatchEvent {
+                    callback: watch.callback,
+                    kind,
+                    path,
+                });
+            }
+        }
+        Ok(events)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::mem::size_of;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::RawFd;
+    use std::path::Path;
+    use std::ptr;
+
+    pub const CREATE: u32 = libc::IN_CREATE | libc::IN_MOVED_TO;
+    pub const MODIFY: u32 = libc::IN_MODIFY | libc::IN_ATTRIB;
+    pub const DELETE: u32 = libc::IN_DELETE | libc::IN_DELETE_SELF | libc::IN_MOVED_FROM;
+    pub const IGNORED: u32 = libc::IN_IGNORED;
+
+    pub fn init() -> io::Result<RawFd> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(fd)
+    }
+    pub fn add_watch(fd: RawFd, path: &Path) -> io::Result<i32> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), CREATE | MODIFY | DELETE) };
+        if wd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+    pub fn rm_watch(fd: RawFd, wd: i32) -> io::Result<()> {
+        if unsafe { libc::inotify_rm_watch(fd, wd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    pub fn read_events(fd: RawFd) -> io::Result<Vec<(i32, u32, String)>> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(out);
+                }
+                return Err(err);
+            }
+            let n = n as usize;
+            let mut offset = 0;
+            while offset + size_of::<libc::inotify_event>() <= n {
+                let event: libc::inotify_event = unsafe {
+                    ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let start = offset + size_of::<libc::inotify_event>();
+                let end = start + event.len as usize;
+                // the name is padded with nul bytes
+                let name = buf[start..end]
+                    .split(|b| *b == 0)
+                    .next()
+                    .map(|name| String::from_utf8_lossy(name).into_owned())
+                    .unwrap_or_default();
+                out.push((event.wd, event.mask, name));
+                offset = end;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use std::path::Path;
+
+    pub const CREATE: u32 = 0;
+    pub const MODIFY: u32 = 0;
+    pub const DELETE: u32 = 0;
+    pub const IGNORED: u32 = 0;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "file watching requires inotify")
+    }
+    pub fn init() -> io::Result<RawFd> {
+        Err(unsupported())
+    }
+    pub fn add_watch(_fd: RawFd, _path: &Path) -> io::Result<i32> {
+        Err(unsupported())
+    }
+    pub fn rm_watch(_fd: RawFd, _wd: i32) -> io::Result<()> {
+        Err(unsupported())
+    }
+    pub fn read_events(_fd: RawFd) -> io::Result<Vec<(i32, u32, String)>> {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,481 @@
+use failure::{err_msg, Error};
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use upgrade;
+use wasabi_io::{
+    DnsConfig, IOLoop, ListenFds, Policy, PortMap, RateLimits, SockOptLimits, Timeouts, TlsConfig,
+};
+
+/// Runtime options that are passed as `--name=value` flags before the wasm
+/// file. Everything from the wasm file onward is handed to the program.
+/// Flags that build up a list, like `--allow-egress`, can be repeated.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub audit_log: Option<PathBuf>,
+    pub record_network: Option<PathBuf>,
+    pub replay_network: Option<PathBuf>,
+    pub policy: Policy,
+    pub port_map: PortMap,
+    pub listen_fds: ListenFds,
+    pub dns_timeout: Option<Duration>,
+    pub dns: Option<DnsConfig>,
+    pub sockopt_limits: SockOptLimits,
+    pub timeouts: Timeouts,
+    pub rate_limits: RateLimits,
+    pub tls: Option<TlsConfig>,
+}
+
+/// Parses a per second rate. A rate of 0 would let nothing through, so it's
+/// refused rather than treated as no limit, which is leaving the flag out.
+fn parse_rate(value: &str) -> Result<u64, Error> {
+    match value.parse()? {
+        0 => Err(err_msg("the rate must be at least 1")),
+        rate => Ok(rate),
+    }
+}
+
+impl Config {
+    pub fn from_args(args: Vec<String>) -> Result<(Self, Vec<String>), String> {
+        let mut config = Self::default();
+        let mut rest = Vec::new();
+        let mut iter = args.into_iter();
+        if let Some(bin) = iter.next() {
+            rest.push(bin);
+        }
+        let mut iter = iter.peekable();
+        while iter.peek().map_or(false, |arg| arg.starts_with("--")) {
+            let arg = iter.next().unwrap();
+            let mut parts = arg[2..].splitn(2, '=');
+            let name = parts.next().unwrap();
+            let value = match parts.next() {
+                Some(value) => value.to_string(),
+                None => return Err(format!("flag --{} expects a value", name)),
+            };
+            config.set(name, value)?;
+        }
+        rest.extend(iter);
+        Ok((config, rest))
+    }
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        let result = match name {
+            "audit-log" => {
+                self.audit_log = Some(PathBuf::from(value));
+                Ok(())
+            }
+            "record-network" => {
+                self.record_network = Some(PathBuf::from(value));
+                Ok(())
+            }
+            "replay-network" => {
+                self.replay_network = Some(PathBuf::from(value));
+                Ok(())
+            }
+            "allow-egress" => self.policy.allow_egress(&value),
+            "deny-egress" => self.policy.deny_egress(&value),
+            "allow-listen" => self.policy.allow_listen(&value),
+            "map-port" => self.port_map.add(&value),
+            "listen-fd" => self.listen_fds.add(&value),
+            "dns-timeout" => match value.parse() {
+                Ok(ms) => {
+                    self.dns_timeout = Some(Duration::from_millis(ms));
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "dns-server" => self.dns_config().add_upstream(&value),
+            "dns-host" => self.dns_config().add_host(&value),
+            "dns-search" => self.dns_config().add_search(&value),
+            "dns-cache-ttl" => match value.parse() {
+                Ok(ms) => {
+                    self.dns_config().set_cache_ttl(Duration::from_millis(ms));
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "dns-negative-ttl" => match value.parse() {
+                Ok(ms) => {
+                    self.dns_config()
+                        .set_negative_ttl(Duration::from_millis(ms));
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "dns" if value == "off" => {
+                self.dns_config().disable();
+                Ok(())
+            }
+            "dns" => return Err(format!("invalid --dns: expected off, got {}", value)),
+            "max-socket-buffer" => match value.parse() {
+                Ok(bytes) => {
+                    self.sockopt_limits.max_buffer = Some(bytes);
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "max-linger" => match value.parse() {
+                Ok(secs) => {
+                    self.sockopt_limits.max_linger = Some(Duration::from_secs(secs));
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "connect-timeout" => match value.parse() {
+                Ok(ms) => {
+                    self.timeouts.connect = Some(Duration::from_millis(ms));
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "idle-timeout" => match value.parse() {
+                Ok(ms) => {
+                    self.timeouts.idle = Some(Duration::from_millis(ms));
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "max-read-rate" => parse_rate(&value).map(|bytes| {
+                self.rate_limits.read_bytes_per_sec = Some(bytes);
+            }),
+            "max-write-rate" => parse_rate(&value).map(|bytes| {
+                self.rate_limits.write_bytes_per_sec = Some(bytes);
+            }),
+            "max-connection-rate" => parse_rate(&value).map(|connections| {
+                self.rate_limits.connections_per_sec = Some(connections);
+            }),
+            "max-sockets" => match value.parse() {
+                Ok(sockets) => {
+                    self.rate_limits.max_sockets = Some(sockets);
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            "tls-ca" => {
+                self.tls_config().add_ca_file(&value);
+                Ok(())
+            }
+            "tls-cert" => {
+                self.tls_config().set_cert_file(&value);
+                Ok(())
+            }
+            "tls-key" => {
+                self.tls_config().set_key_file(&value);
+                Ok(())
+            }
+            "ephemeral-ports" => match value.parse() {
+                Ok(ephemeral) => {
+                    self.port_map.set_ephemeral(ephemeral);
+                    Ok(())
+                }
+                Err(err) => Err(err.into()),
+            },
+            _ => return Err(format!("unknown flag --{}", name)),
+        };
+        result.map_err(|err| format!("invalid --{}: {}", name, err))
+    }
+    fn dns_config(&mut self) -> &mut DnsConfig {
+        self.dns.get_or_insert_with(DnsConfig::default)
+    }
+    fn tls_config(&mut self) -> &mut TlsConfig {
+        self.tls.get_or_insert_with(TlsConfig::default)
+    }
+    pub fn apply(&self, io: &mut IOLoop) -> Result<(), Error> {
+        if let Some(path) = &self.audit_log {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            io.enable_audit_log(Box::new(file));
+        }
+        if let Some(path) = &self.record_network {
+            io.record_network(Box::new(File::create(path)?));
+        }
+        if let Some(path) = &self.replay_network {
+            io.replay_network(&fs::read_to_string(path)?)?;
+        }
+        io.set_policy(self.policy.clone());
+        io.set_sockopt_limits(self.sockopt_limits);
+        io.set_timeouts(self.timeouts);
+        io.set_rate_limits(self.rate_limits);
+        if let Some(timeout) = self.dns_timeout {
+            io.set_dns_timeout(timeout);
+        }
+        if let Some(dns) = &self.dns {
+            io.set_dns_config(dns.clone())?;
+        }
+        if let Some(tls) = &self.tls {
+            io.set_tls_config(tls.clone())?;
+        }
+        if !self.port_map.is_empty() {
+            io.set_port_map(self.port_map.clone());
+            // the guest only ever sees its own ports, so tell whoever is
+            // running us where it can actually be reached
+            let (sender, receiver) = mpsc::channel();
+            io.notify_port_mappings(sender);
+            thread::spawn(move || {
+                for mapping in receiver {
+                    eprintln!(
+                        "wasabi: guest address {} is listening on {}",
+                        mapping.guest, mapping.host
+                    );
+                }
+            });
+        }
+        // sockets passed by systemd socket activation. The unit can pass
+        // other kinds of sockets as well, the program only gets tcp listeners.
+        let mut activated = ListenFds::from_env()?;
+        for (name, err) in activated.retain_tcp_listeners() {
+            eprintln!("wasabi: ignoring socket {}: {}", name, err);
+        }
+        io.inherit_listeners(&activated)?;
+        io.inherit_listeners(&self.listen_fds)?;
+        upgrade::on_sigusr2(io.listener_set())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_flags_from_program_args() {
+        let args = vec!["wasabi", "--audit-log=/tmp/audit", "main.wasm", "--flag=1"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let (config, rest) = Config::from_args(args).unwrap();
+        assert_eq!(config.audit_log, Some(PathBuf::from("/tmp/audit")));
+        assert_eq!(config.dns_timeout, None);
+        assert!(config.dns.is_none());
+        assert_eq!(rest, vec!["wasabi", "main.wasm", "--flag=1"]);
+    }
+
+    #[test]
+    fn policy_flags() {
+        let args = vec![
+            "wasabi",
+            "--deny-egress=10.1.0.0/16",
+            "--allow-egress=10.0.0.0/8:443",
+            "--allow-listen=8080",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        let policy = config.policy;
+        assert!(policy
+            .check_connect(&"10.2.0.1:443".parse().unwrap())
+            .is_ok());
+        assert!(policy
+            .check_connect(&"10.1.0.1:443".parse().unwrap())
+            .is_err());
+        assert!(policy
+            .check_listen(&"0.0.0.0:8080".parse().unwrap())
+            .is_ok());
+        assert!(policy.check_listen(&"0.0.0.0:22".parse().unwrap()).is_err());
+
+        let args = vec!["wasabi", "--allow-egress=10.0.0.0/99", "main.wasm"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(Config::from_args(args).is_err());
+    }
+
+    #[test]
+    fn port_map_flags() {
+        let args = vec![
+            "wasabi",
+            "--map-port=8080:18080",
+            "--ephemeral-ports=true",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        let host = config
+            .port_map
+            .host_addr(&"127.0.0.1:8080".parse().unwrap());
+        assert_eq!(host.port(), 18080);
+        let host = config
+            .port_map
+            .host_addr(&"127.0.0.1:9000".parse().unwrap());
+        assert_eq!(host.port(), 0);
+
+        let args = vec!["wasabi", "--ephemeral-ports=maybe", "main.wasm"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(Config::from_args(args).is_err());
+    }
+
+    #[test]
+    fn listen_fd_flags() {
+        let args = vec![
+            "wasabi",
+            "--listen-fd=web=3",
+            "--listen-fd=0.0.0.0:8080=4",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        let fds: Vec<_> = config.listen_fds.iter().cloned().collect();
+        assert_eq!(
+            fds,
+            vec![("web".to_string(), 3), ("0.0.0.0:8080".to_string(), 4)]
+        );
+
+        for flag in &["--listen-fd=web", "--listen-fd=web=three"] {
+            let args = vec!["wasabi", flag, "main.wasm"]
+                .into_iter()
+                .map(String::from)
+                .collect();
+            assert!(Config::from_args(args).is_err());
+        }
+    }
+
+    #[test]
+    fn dns_flags() {
+        let args = vec![
+            "wasabi",
+            "--dns-server=127.0.0.1:5353",
+            "--dns-host=db.internal=10.0.0.5",
+            "--dns-cache-ttl=30000",
+            "--dns=off",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        assert!(config.dns.is_some());
+
+        for flag in &["--dns=on", "--dns-host=db.internal", "--dns-server=nope"] {
+            let args = vec!["wasabi", flag, "main.wasm"]
+                .into_iter()
+                .map(String::from)
+                .collect();
+            assert!(Config::from_args(args).is_err());
+        }
+    }
+
+    #[test]
+    fn sockopt_flags() {
+        let args = vec![
+            "wasabi",
+            "--max-socket-buffer=65536",
+            "--max-linger=5",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        assert_eq!(config.sockopt_limits.max_buffer, Some(65536));
+        assert_eq!(
+            config.sockopt_limits.max_linger,
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn timeout_flags() {
+        let args = vec![
+            "wasabi",
+            "--connect-timeout=3000",
+            "--idle-timeout=60000",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        assert_eq!(config.timeouts.connect, Some(Duration::from_secs(3)));
+        assert_eq!(config.timeouts.idle, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rate_limit_flags() {
+        let args = vec![
+            "wasabi",
+            "--max-read-rate=1048576",
+            "--max-connection-rate=100",
+            "--max-sockets=256",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        assert_eq!(config.rate_limits.read_bytes_per_sec, Some(1 << 20));
+        assert_eq!(config.rate_limits.write_bytes_per_sec, None);
+        assert_eq!(config.rate_limits.connections_per_sec, Some(100));
+        assert_eq!(config.rate_limits.max_sockets, Some(256));
+
+        let args = vec!["wasabi", "--max-sockets=-1", "main.wasm"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(Config::from_args(args).is_err());
+
+        let args = vec!["wasabi", "--max-connection-rate=0", "main.wasm"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(Config::from_args(args).is_err());
+    }
+
+    #[test]
+    fn tls_flags() {
+        let args = vec![
+            "wasabi",
+            "--tls-ca=/etc/wasabi/ca.pem",
+            "--tls-cert=/etc/wasabi/tenant.pem",
+            "--tls-key=/etc/wasabi/tenant.key",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, rest) = Config::from_args(args).unwrap();
+        assert!(config.tls.is_some());
+        assert_eq!(rest, vec!["wasabi", "main.wasm"]);
+        // the files are only read once the config is applied
+        assert!(config.apply(&mut IOLoop::new()).is_err());
+    }
+
+    #[test]
+    fn network_capture_flags() {
+        let args = vec![
+            "wasabi",
+            "--record-network=/does/not/exist/capture",
+            "--replay-network=/does/not/exist/replay",
+            "main.wasm",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let (config, _) = Config::from_args(args).unwrap();
+        assert_eq!(
+            config.record_network,
+            Some(PathBuf::from("/does/not/exist/capture"))
+        );
+        assert_eq!(
+            config.replay_network,
+            Some(PathBuf::from("/does/not/exist/replay"))
+        );
+        assert!(config.apply(&mut IOLoop::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let args = vec!["wasabi", "--nope=1", "main.wasm"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(Config::from_args(args).is_err());
+    }
+}
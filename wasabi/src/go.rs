@@ -1,4 +1,5 @@
 use bytes::u32_as_u8_le;
+use config::Config;
 use cranelift_codegen::ir::types;
 use cranelift_codegen::{ir, isa};
 use cranelift_entity::PrimaryMap;
@@ -6,19 +7,20 @@ use cranelift_wasm::DefinedFuncIndex;
 use cranelift_wasm::Memory;
 use failure::Error;
 use js;
+use libc;
 use mem::{Actions, Mem};
 use rand::{thread_rng, Rng};
 use shared_state::SharedState;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io;
 use std::net;
-use std::net::{IpAddr, ToSocketAddrs};
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{slice, str};
 use target_lexicon::HOST;
 use util::epoch_ns;
-use wasabi_io::addr_to_bytes;
+use wasabi_io::{addr_to_bytes, bytes_to_addr, Deadline, FetchRequest, SockOpt};
 use wasmtime_environ::MemoryPlan;
 use wasmtime_environ::{translate_signature, Export, MemoryStyle, Module};
 use wasmtime_jit::{ActionOutcome, Compiler, Context, InstantiationError, RuntimeValue};
@@ -154,6 +156,62 @@ trait ContextHelpers {
                     .lookup_ip(argument_list[1].0, &value);
                 Some(argument_list[1])
             }
+            ("lookup", "wasabi") => {
+                // kind, host, callback
+                let (kind, host) = {
+                    match (
+                        self.js().slab_get(argument_list[0].0).unwrap(),
+                        self.js().slab_get(argument_list[1].0).unwrap(),
+                    ) {
+                        (js::Value::String(kind), js::Value::String(host)) => {
+                            (kind.to_owned(), host.to_owned())
+                        }
+                        _ => {
+                            return None;
+                        }
+                    }
+                };
+                let kind = match kind.parse() {
+                    Ok(kind) => kind,
+                    Err(err) => {
+                        let msg = self
+                            .js_mut()
+                            .slab_add(js::Value::String(format!("{}", err)));
+                        return Some((msg, true));
+                    }
+                };
+                self.shared_state_mut()
+                    .net_loop
+                    .lookup(argument_list[2].0, &host, kind);
+                Some(argument_list[2])
+            }
+            ("watch", "wasabi") => {
+                // path          on change     callback
+                // [(59, true), (60, true), (61, true)]
+                let path = {
+                    match self.js().slab_get(argument_list[0].0).unwrap() {
+                        js::Value::String(s) => (s.to_owned()),
+                        _ => {
+                            return None;
+                        }
+                    }
+                };
+                let on_change = self.js().reflect_get(argument_list[1].0, "id").unwrap().0;
+                self.shared_state_mut()
+                    .net_loop
+                    .watch(argument_list[2].0, on_change, &path);
+                Some((2, true))
+            }
+            ("unwatch", "wasabi") => {
+                let watch = js::int_from_value(argument_list[0]) as usize;
+                match self.shared_state_mut().net_loop.unwatch(watch) {
+                    Ok(()) => Some((2, true)),
+                    Err(err) => {
+                        let msg = self.js_mut().slab_add(js::Value::String(err.to_string()));
+                        Some((msg, true))
+                    }
+                }
+            }
             ("isDirectory", "fstat") => self.js().reflect_get(this_argument, "is_dir"),
             ("fstat", "fs") => {
                 // fd          callback
@@ -164,6 +222,25 @@ trait ContextHelpers {
                 );
                 Some((2, true))
             }
+            ("close", "fs") => {
+                // fd          callback
+                // [(3, false), (42, true)]
+                self.shared_state_mut().net_loop.fs_close(
+                    argument_list[1].0,
+                    js::int_from_value(argument_list[0]) as usize,
+                );
+                Some((2, true))
+            }
+            ("flock", "fs") => {
+                // fd          how          callback
+                // [(3, false), (2, false), (42, true)]
+                self.shared_state_mut().net_loop.fs_flock(
+                    argument_list[2].0,
+                    js::int_from_value(argument_list[0]) as usize,
+                    js::int_from_value(argument_list[1]),
+                );
+                Some((2, true))
+            }
             ("read", "fs") => {
                 // [
                 //     (1,   true), fd
@@ -187,7 +264,7 @@ trait ContextHelpers {
                     js::int_from_value(argument_list[0]) as usize,
                     address,
                     js::int_from_value(argument_list[3]) as usize,
-                    std::io::SeekFrom::Current(0),
+                    js::seek_from_value(argument_list[4]),
                 );
                 Some((2, true))
             }
@@ -275,7 +352,7 @@ trait ContextHelpers {
                 //     buffer:   (33, true),
                 //     offset:   (1, true),
                 //     len:      (85, false),
-                //               (2, true),
+                //     position: (2, true),
                 //     callback: (34, true),
                 // ];
                 let (address, len) = {
@@ -286,12 +363,106 @@ trait ContextHelpers {
                         }
                     }
                 };
-                let to_print = self.mem()._get_bytes(address, len).to_vec();
-                self.shared_state_mut()
-                    .net_loop
-                    .stdout(argument_list[5].0, to_print);
+                let buf = self.mem()._get_bytes(address, len).to_vec();
+                let net_loop = &mut self.shared_state_mut().net_loop;
+                // files the guest opens get fds from 3 up, so they never
+                // collide with stdout and stderr
+                match js::int_from_value(argument_list[0]) {
+                    1 => net_loop.stdout(argument_list[5].0, buf),
+                    2 => net_loop.stderr(argument_list[5].0, buf),
+                    fd => net_loop.fs_write(
+                        argument_list[5].0,
+                        fd as usize,
+                        buf,
+                        js::seek_from_value(argument_list[4]),
+                    ),
+                }
                 Some(argument_list[3])
             }
+            ("fetch", "global") => {
+                // url, options
+                let opt = argument_list[1].0;
+                let mut request = FetchRequest {
+                    method: "GET".to_string(),
+                    url: self.js().get_string(argument_list[0].0)?.to_string(),
+                    ..FetchRequest::default()
+                };
+                if let Some(method) = self.reflect_get(opt, "method") {
+                    request.method = self.js().get_string(method.0)?.to_string();
+                }
+                if let Some(headers) = self.reflect_get(opt, "headers") {
+                    request.headers = self.js().header_pairs(headers.0);
+                }
+                if let Some(body) = self.reflect_get(opt, "body") {
+                    // the typed array may be released as soon as fetch
+                    // returns
+                    if let Some(js::Value::Memory { address, len }) = self.js().slab_get(body.0) {
+                        let (address, len) = (*address as usize, *len as usize);
+                        request.body = self.mem().mem_slice(address, address + len).to_vec();
+                    }
+                }
+                let promise = self.js_mut().add_promise().unwrap();
+                let abort = self.shared_state_mut().net_loop.fetch(promise, request);
+                if let Some(signal) = self.reflect_get(opt, "signal") {
+                    self.js_mut()
+                        .add_object_value(signal.0, "fetch", (abort as i64, false))
+                        .unwrap();
+                }
+                Some((promise, true))
+            }
+            ("abort", "AbortController") => {
+                let fetch = self
+                    .reflect_get(this_argument, "signal")
+                    .and_then(|signal| self.reflect_get(signal.0, "fetch"));
+                if let Some((abort, _)) = fetch {
+                    self.shared_state_mut().net_loop.abort_fetch(abort as usize);
+                }
+                Some((0, true))
+            }
+            ("append", "Headers") => {
+                // name, value
+                let (name, value) = {
+                    let js = self.js();
+                    (
+                        js.get_string(argument_list[0].0)?.to_string(),
+                        js.get_string(argument_list[1].0)?.to_string(),
+                    )
+                };
+                self.js_mut()
+                    .append_header(this_argument, name, value)
+                    .unwrap();
+                Some((0, true))
+            }
+            ("entries", "Headers") => Some((
+                self.js_mut().add_headers_iterator(this_argument).unwrap(),
+                true,
+            )),
+            ("next", "HeadersIterator") => Some((
+                self.js_mut().headers_iterator_next(this_argument).unwrap(),
+                true,
+            )),
+            ("getReader", "ReadableStream") => {
+                Some((self.js_mut().add_reader(this_argument).unwrap(), true))
+            }
+            ("read", "reader") => {
+                let body = self.reflect_get(this_argument, "id")?.0 as usize;
+                let promise = self.js_mut().add_promise().unwrap();
+                self.shared_state_mut().net_loop.fetch_read(promise, body);
+                Some((promise, true))
+            }
+            ("cancel", "reader") => {
+                let body = self.reflect_get(this_argument, "id")?.0 as usize;
+                self.shared_state_mut().net_loop.fetch_cancel(body);
+                Some((0, true))
+            }
+            ("then", "Promise") => {
+                // the wrapped funcs to call, by their Go ids
+                let on_fulfilled = self.reflect_get(argument_list[0].0, "id")?.0;
+                let on_rejected = self.reflect_get(argument_list[1].0, "id")?.0;
+                self.shared_state_mut()
+                    .then_promise(this_argument, on_fulfilled, on_rejected);
+                Some((0, true))
+            }
             _ => {
                 panic!(
                     "No reflect_apply match on {:?} {:?}",
@@ -300,6 +471,36 @@ trait ContextHelpers {
             }
         }
     }
+    /// Uint8Arrays are views of Go's memory. set copies the value a reader
+    /// resolved to into one.
+    fn typed_array_call(
+        &mut self,
+        target: i64,
+        method: &'static str,
+        argument_list: Vec<(i64, bool)>,
+    ) -> Option<(i64, bool)> {
+        let (address, len) = match self.js().slab_get(target)? {
+            js::Value::Memory { address, len } => (*address as usize, *len as usize),
+            _ => return None,
+        };
+        match method {
+            "set" => {
+                let bytes = self.reflect_get(argument_list[0].0, "bytes")?.0;
+                let ss = self.shared_state_mut();
+                match ss.js.slab_get(bytes)? {
+                    js::Value::Bytes(b) => {
+                        let n = b.len().min(len);
+                        ss.mem
+                            .mut_mem_slice(address, address + n)
+                            .copy_from_slice(&b[..n]);
+                    }
+                    _ => return None,
+                }
+                Some((0, true))
+            }
+            _ => panic!("No typed array method {:?}", method),
+        }
+    }
     fn reflect_construct(
         &mut self,
         target: i64,
@@ -309,14 +510,6 @@ trait ContextHelpers {
     }
     fn get_static_string(&self, sp: i32) -> &'static str {
         let key = str::from_utf8(self.mem().get_bytes(sp)).unwrap();
-        if key == "AbortController" {
-            panic!([
-                "\"AbortController\" requested. This likely means ",
-                "the default js/wasm roundtripper is being used. Wasabi",
-                " doesn't support this, use the wasabi networking libary."
-            ]
-            .join(""))
-        }
         match self.js().static_strings.get(&key) {
             Some(v) => v,
             None => {
@@ -405,9 +598,18 @@ extern "C" fn go_js_value_invoke(_sp: i32) {
 extern "C" fn go_js_value_call(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
     let v = fc.shared_state().load_value(sp + 8);
-    let m = fc.reflect_get(v.0, &fc.get_static_string(sp + 16)).unwrap();
+    let method = fc.get_static_string(sp + 16);
     let args = fc.shared_state().load_slice_of_values(sp + 32);
-    let result = fc.reflect_apply(m.0, v.0, args).unwrap();
+    let is_typed_array = match fc.js().slab_get(v.0) {
+        Some(js::Value::Memory { .. }) => true,
+        _ => false,
+    };
+    let result = if is_typed_array {
+        fc.typed_array_call(v.0, method, args).unwrap()
+    } else {
+        let m = fc.reflect_get(v.0, method).unwrap();
+        fc.reflect_apply(m.0, v.0, args).unwrap()
+    };
     // TODO: catch error and error
     fc.shared_state_mut().store_value(sp + 56, result);
     fc.mem_mut().set_bool(sp + 64, true);
@@ -431,30 +633,28 @@ extern "C" fn go_js_value_length(vmctx: *mut VMContext, sp: i32) {
 
 extern "C" fn go_js_value_prepare_string(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
-    let reference = fc.mem().get_i32(sp + 8);
-
-    let len = match fc.shared_state().js.slab_get(i64::from(reference)).unwrap() {
-        js::Value::String(ref b) => b.len(),
-        _ => panic!("load_string needs string"),
+    let value = fc.shared_state().load_value(sp + 8);
+    let len = match fc.js().value_string(value) {
+        Some(s) => s.len(),
+        None => panic!("can't convert {:?} to a string", value),
     };
-    // this is a little different from our prepare_bytes because we
-    // don't pass back the reference
-    fc.mem_mut().set_i64(sp + 16, i64::from(reference));
+    // this is a little different from our prepare_bytes because we don't
+    // pass back a new reference. numbers are converted again on load
+    let raw = fc.mem().get_i64(sp + 8);
+    fc.mem_mut().set_i64(sp + 16, raw);
     fc.mem_mut().set_i64(sp + 16 + 8, len as i64);
 }
 
 extern "C" fn go_js_value_load_string(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
-    let reference = fc.mem().get_i32(sp + 8);
+    let value = fc.shared_state().load_value(sp + 8);
     let addr = fc.mem().get_i32(sp + 16);
     let ln = fc.mem().get_i32(sp + 24);
-    let ss = fc.shared_state_mut();
-    let thing = ss.js.slab_get(i64::from(reference)).unwrap();
-    let b = match thing {
-        js::Value::String(ref b) => b,
-        _ => panic!("load_string needs string"),
+    let b = match fc.js().value_string(value) {
+        Some(s) => s,
+        None => panic!("load_string needs string"),
     };
-    ss.mem
+    fc.mem_mut()
         .mut_mem_slice(addr as usize, (addr + ln) as usize)
         .clone_from_slice(&b.as_bytes());
 }
@@ -570,6 +770,172 @@ extern "C" fn go_dial_tcp(vmctx: *mut VMContext, sp: i32) {
     }
 }
 
+extern "C" fn go_dial_tls(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let addr = fc.mem().get_string(sp + 8).to_owned();
+    let server_name = fc.mem().get_string(sp + 24).to_owned();
+    match &addr
+        .parse()
+        .map_err(|e: net::AddrParseError| -> Error { e.into() })
+    {
+        Ok(addr) => {
+            let id = fc
+                .shared_state_mut()
+                .net_loop
+                .tls_connect(addr, &server_name);
+            fc.shared_state_mut().set_usize_result(sp + 40, id);
+        }
+        Err(err) => {
+            fc.shared_state_mut().set_error(sp + 40, err);
+        }
+    }
+}
+
+extern "C" fn go_listen_tls(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let addr = fc.mem().get_string(sp + 8).to_owned();
+    match &addr
+        .parse()
+        .map_err(|e: net::AddrParseError| -> Error { e.into() })
+    {
+        Ok(addr) => {
+            let id = fc.shared_state_mut().net_loop.tls_listen(addr);
+            fc.shared_state_mut().set_usize_result(sp + 24, id);
+        }
+        Err(err) => {
+            fc.shared_state_mut().set_error(sp + 24, err);
+        }
+    }
+}
+
+extern "C" fn go_listen_udp(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let addr = fc.mem().get_string(sp + 8).to_owned();
+    match &addr
+        .parse()
+        .map_err(|e: net::AddrParseError| -> Error { e.into() })
+    {
+        Ok(addr) => {
+            let id = fc.shared_state_mut().net_loop.udp_bind(addr);
+            fc.shared_state_mut().set_usize_result(sp + 24, id);
+        }
+        Err(err) => {
+            fc.shared_state_mut().set_error(sp + 24, err);
+        }
+    }
+}
+
+extern "C" fn go_dial_udp(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let addr = fc.mem().get_string(sp + 8).to_owned();
+    match &addr
+        .parse()
+        .map_err(|e: net::AddrParseError| -> Error { e.into() })
+    {
+        Ok(addr) => {
+            let id = fc.shared_state_mut().net_loop.udp_connect(addr);
+            fc.shared_state_mut().set_usize_result(sp + 24, id);
+        }
+        Err(err) => {
+            fc.shared_state_mut().set_error(sp + 24, err);
+        }
+    }
+}
+
+extern "C" fn go_send_to(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let id = fc.mem().get_i32(sp + 8);
+    let start = fc.mem().get_i64(sp + 16) as usize;
+    let end = fc.mem().get_i64(sp + 24) as usize + start;
+    let addr_start = fc.mem().get_i64(sp + 40) as usize;
+    let addr_end = fc.mem().get_i64(sp + 48) as usize + addr_start;
+    let sent = {
+        let shared_state = fc.shared_state_mut();
+        match bytes_to_addr(shared_state.mem.mem_slice(addr_start, addr_end)) {
+            Ok(addr) => shared_state.net_loop.udp_send_to(
+                id as usize,
+                shared_state.mem.mem_slice(start, end),
+                &addr,
+            ),
+            Err(err) => Err(err),
+        }
+    };
+    fc.shared_state_mut()
+        .set_u64_result(sp + 64, sent.map(|n| n as u64));
+}
+
+extern "C" fn go_recv_from(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let id = fc.mem().get_i32(sp + 8);
+    let start = fc.mem().get_i64(sp + 16) as usize;
+    let end = fc.mem().get_i64(sp + 24) as usize + start;
+    let addr_start = fc.mem().get_i64(sp + 40) as usize;
+    let addr_end = fc.mem().get_i64(sp + 48) as usize + addr_start;
+    let received = {
+        let shared_state = fc.shared_state_mut();
+        shared_state
+            .net_loop
+            .udp_recv_from(id as usize, shared_state.mem.mut_mem_slice(start, end))
+            .and_then(|(n, addr)| {
+                addr_to_bytes(addr, shared_state.mem.mut_mem_slice(addr_start, addr_end))?;
+                Ok(n as u64)
+            })
+    };
+    fc.shared_state_mut().set_u64_result(sp + 64, received);
+}
+
+extern "C" fn go_listen_unix(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let path = fc.mem().get_string(sp + 8).to_owned();
+    let id = fc.shared_state_mut().net_loop.unix_listen(&path);
+    fc.shared_state_mut().set_usize_result(sp + 24, id);
+}
+
+extern "C" fn go_accept_unix(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let token = fc.mem().get_i32(sp + 8);
+    let id = fc.shared_state_mut().net_loop.unix_accept(token as usize);
+    fc.shared_state_mut().set_usize_result(sp + 16, id);
+}
+
+extern "C" fn go_dial_wait(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let wait = fc.shared_state().net_loop.dial_wait();
+    let nanos = wait.as_secs() * 1_000_000_000 + u64::from(wait.subsec_nanos());
+    fc.mem_mut().set_i64(sp + 8, nanos as i64);
+}
+
+extern "C" fn go_dial_unix(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let path = fc.mem().get_string(sp + 8).to_owned();
+    let id = fc.shared_state_mut().net_loop.unix_connect(&path);
+    fc.shared_state_mut().set_usize_result(sp + 24, id);
+}
+
+extern "C" fn go_listen_virtual(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let name = fc.mem().get_string(sp + 8).to_owned();
+    let id = fc.shared_state_mut().net_loop.virtual_listen(&name);
+    fc.shared_state_mut().set_usize_result(sp + 24, id);
+}
+
+extern "C" fn go_accept_virtual(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let token = fc.mem().get_i32(sp + 8);
+    let id = fc
+        .shared_state_mut()
+        .net_loop
+        .virtual_accept(token as usize);
+    fc.shared_state_mut().set_usize_result(sp + 16, id);
+}
+
+extern "C" fn go_dial_virtual(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let name = fc.mem().get_string(sp + 8).to_owned();
+    let id = fc.shared_state_mut().net_loop.virtual_connect(&name);
+    fc.shared_state_mut().set_usize_result(sp + 24, id);
+}
+
 extern "C" fn go_shutdown_tcp_conn(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
     let id = fc.mem().get_i32(sp + 8);
@@ -591,18 +957,103 @@ extern "C" fn go_shutdown_tcp_conn(vmctx: *mut VMContext, sp: i32) {
     }
 }
 
+extern "C" fn go_set_sock_opt(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let id = fc.mem().get_i32(sp + 8);
+    let code = fc.mem().get_i32(sp + 8 + 4);
+    let value = fc.mem().get_i64(sp + 16);
+    let result = SockOpt::from_code(code, value)
+        .map_err(|err| err.into())
+        .and_then(|opt| fc.shared_state().net_loop.set_sockopt(id as usize, opt));
+    if let Err(err) = result {
+        fc.shared_state_mut().set_error(sp + 24, &err);
+        fc.mem_mut().set_bool(sp + 24 + 4, false)
+    } else {
+        fc.mem_mut().set_bool(sp + 24 + 4, true)
+    }
+}
+
+extern "C" fn go_proxy_protocol(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let id = fc.mem().get_i32(sp + 8);
+    let result = fc
+        .shared_state_mut()
+        .net_loop
+        .set_proxy_protocol(id as usize, true);
+    if let Err(err) = result {
+        fc.shared_state_mut().set_error(sp + 16, &err);
+        fc.mem_mut().set_bool(sp + 16 + 4, false)
+    } else {
+        fc.mem_mut().set_bool(sp + 16 + 4, true)
+    }
+}
+
+extern "C" fn go_set_deadline(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let id = fc.mem().get_i32(sp + 8);
+    // 1 read
+    // 2 write
+    let kind = match fc.mem().get_i32(sp + 8 + 4) {
+        1 => Deadline::Read,
+        2 => Deadline::Write,
+        _ => {
+            let err = Error::from(io::Error::from_raw_os_error(libc::EINVAL));
+            fc.shared_state_mut().set_error(sp + 24, &err);
+            fc.mem_mut().set_bool(sp + 24 + 4, false);
+            return;
+        }
+    };
+    // unix nanoseconds, zero clears the deadline
+    let nanos = fc.mem().get_i64(sp + 16);
+    let at = if nanos == 0 {
+        None
+    } else {
+        let deadline = UNIX_EPOCH + Duration::from_nanos(nanos.max(0) as u64);
+        match deadline.duration_since(SystemTime::now()) {
+            Ok(until) => Some(Instant::now() + until),
+            Err(_) => Some(Instant::now()),
+        }
+    };
+    let result = fc
+        .shared_state_mut()
+        .net_loop
+        .set_deadline(id as usize, kind, at);
+    if let Err(err) = result {
+        fc.shared_state_mut().set_error(sp + 24, &err);
+        fc.mem_mut().set_bool(sp + 24 + 4, false)
+    } else {
+        fc.mem_mut().set_bool(sp + 24 + 4, true)
+    }
+}
+
 extern "C" fn go_write_tcp_conn(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
     let id = fc.mem().get_i32(sp + 8);
-    let addr = fc.mem().get_i32(sp + 16);
-    let ln = fc.mem().get_i32(sp + 24);
-    let written = fc.shared_state().net_loop.write_stream(
-        id as usize,
-        fc.shared_state()
-            .mem
-            .mem_slice(addr as usize, (addr + ln) as usize),
-    );
-    fc.shared_state_mut().set_usize_result(sp + 40, written);
+    let addr = fc.mem().get_i64(sp + 16) as usize;
+    let ln = fc.mem().get_i64(sp + 24) as usize;
+    let written = fc
+        .shared_state()
+        .net_loop
+        .write_stream(
+            id as usize,
+            fc.shared_state().mem.mem_slice(addr, addr + ln),
+        )
+        .map(|n| n as u64);
+    fc.shared_state_mut().set_u64_result(sp + 40, written);
+}
+
+extern "C" fn go_send_file(vmctx: *mut VMContext, sp: i32) {
+    let mut fc = FuncContext::new(vmctx);
+    let id = fc.mem().get_i32(sp + 8);
+    let fd = fc.mem().get_i32(sp + 8 + 4);
+    let offset = fc.mem().get_i64(sp + 16) as u64;
+    let ln = fc.mem().get_i64(sp + 24) as usize;
+    let sent = fc
+        .shared_state_mut()
+        .net_loop
+        .send_file(id as usize, fd as usize, offset, ln)
+        .map(|n| n as u64);
+    fc.shared_state_mut().set_u64_result(sp + 32, sent);
 }
 
 extern "C" fn go_net_get_error(vmctx: *mut VMContext, sp: i32) {
@@ -627,17 +1078,17 @@ extern "C" fn go_net_get_error(vmctx: *mut VMContext, sp: i32) {
 extern "C" fn go_read_tcp_conn(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
     let id = fc.mem().get_i32(sp + 8);
-    let start = fc.mem().get_i32(sp + 16);
-    let end = fc.mem().get_i32(sp + 24) + start;
+    let start = fc.mem().get_i64(sp + 16) as usize;
+    let end = fc.mem().get_i64(sp + 24) as usize + start;
 
     let read = {
         let shared_state = fc.shared_state_mut();
-        shared_state.net_loop.read_stream(
-            id as usize,
-            shared_state.mem.mut_mem_slice(start as usize, end as usize),
-        )
+        shared_state
+            .net_loop
+            .read_stream(id as usize, shared_state.mem.mut_mem_slice(start, end))
+            .map(|n| n as u64)
     };
-    fc.shared_state_mut().set_usize_result(sp + 40, read);
+    fc.shared_state_mut().set_u64_result(sp + 40, read);
 }
 
 extern "C" fn go_close_listener_or_conn(vmctx: *mut VMContext, sp: i32) {
@@ -653,30 +1104,6 @@ extern "C" fn go_close_listener_or_conn(vmctx: *mut VMContext, sp: i32) {
     }
 }
 
-extern "C" fn go_lookup_ip_addr(vmctx: *mut VMContext, sp: i32) {
-    let mut fc = FuncContext::new(vmctx);
-    let addr = fc.mem().get_string(sp + 8).to_owned();
-    // TODO handle error
-    match resolve_host(&addr) {
-        Ok(ips) => {
-            fc.mem_mut().set_bool(sp + 24 + 4, true);
-            let mut byte_ips: Vec<Vec<u8>> = Vec::new();
-            for ip in ips.iter() {
-                match ip {
-                    IpAddr::V4(ip4) => byte_ips.push(ip4.octets().to_vec()),
-                    IpAddr::V6(_) => {} //no IPV6 support
-                }
-            }
-            fc.shared_state_mut()
-                .set_byte_array_array(sp + 24, byte_ips);
-        }
-        Err(err) => {
-            fc.shared_state_mut().set_error(sp + 24, &err.into());
-            fc.mem_mut().set_bool(sp + 24 + 4, false);
-        }
-    }
-}
-
 extern "C" fn go_local_addr(vmctx: *mut VMContext, sp: i32) {
     let mut fc = FuncContext::new(vmctx);
     let id = fc.mem().get_i32(sp + 8);
@@ -705,13 +1132,7 @@ extern "C" fn go_remote_addr(vmctx: *mut VMContext, sp: i32) {
     .unwrap();
 }
 
-fn resolve_host(host: &str) -> Result<Vec<IpAddr>, std::io::Error> {
-    (host, 0)
-        .to_socket_addrs()
-        .map(|iter| iter.map(|socket_address| socket_address.ip()).collect())
-}
-
-pub fn instantiate_go() -> Result<InstanceHandle, InstantiationError> {
+pub fn instantiate_go(shared_state: SharedState) -> Result<InstanceHandle, InstantiationError> {
     let mut module = Module::new();
     let mut finished_functions: PrimaryMap<DefinedFuncIndex, *const VMFunctionBody> =
         PrimaryMap::new();
@@ -722,15 +1143,31 @@ pub fn instantiate_go() -> Result<InstanceHandle, InstantiationError> {
     let functions = [
         ("debug", go_debug as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.acceptTcp", go_accept_tcp as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.acceptUnix", go_accept_unix as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.acceptVirtual", go_accept_virtual as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.closeConn", go_close_listener_or_conn as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.closeListener", go_close_listener_or_conn as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.dialTcp", go_dial_tcp as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.dialTls", go_dial_tls as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.dialUDP", go_dial_udp as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.dialUnix", go_dial_unix as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.dialVirtual", go_dial_virtual as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.dialWait", go_dial_wait as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.getError", go_net_get_error as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.listenTCP", go_listen_tcp as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.listenTls", go_listen_tls as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.listenUDP", go_listen_udp as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.listenUnix", go_listen_unix as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.listenVirtual", go_listen_virtual as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.localAddr", go_local_addr as *const VMFunctionBody),
-        ("github.com/maxmcd/wasabi/internal/net.lookupIP", go_lookup_ip_addr as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.proxyProtocol", go_proxy_protocol as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.readConn", go_read_tcp_conn as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.recvFrom", go_recv_from as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.remoteAddr", go_remote_addr as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.sendFile", go_send_file as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.sendTo", go_send_to as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.setDeadline", go_set_deadline as *const VMFunctionBody),
+        ("github.com/maxmcd/wasabi/internal/net.setSockOpt", go_set_sock_opt as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.shutdownConn", go_shutdown_tcp_conn as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/net.writeConn", go_write_tcp_conn as *const VMFunctionBody),
         ("github.com/maxmcd/wasabi/internal/wasm.loadBytes", go_load_bytes as *const VMFunctionBody),
@@ -804,7 +1241,7 @@ pub fn instantiate_go() -> Result<InstanceHandle, InstantiationError> {
         &data_initializers,
         signatures.into_boxed_slice(),
         None,
-        Box::new(SharedState::new()),
+        Box::new(shared_state),
     )
 }
 
@@ -844,11 +1281,20 @@ fn load_args_from_definition(args: Vec<String>, definition: *mut VMMemoryDefinit
     load_args_from_mem(args, &mut mem)
 }
 
-pub fn run(args: Vec<String>, compiler: Compiler, data: Vec<u8>) -> Result<(), String> {
+pub fn run(
+    args: Vec<String>,
+    config: Config,
+    compiler: Compiler,
+    data: Vec<u8>,
+) -> Result<(), String> {
     let c = Box::new(compiler);
     let mut context = Context::new(c);
     let instantiate_timer = SystemTime::now();
-    let instance = instantiate_go().expect("Instantiate go");
+    let mut shared_state = SharedState::new();
+    config
+        .apply(&mut shared_state.net_loop)
+        .map_err(|e| e.to_string())?;
+    let instance = instantiate_go(shared_state).expect("Instantiate go");
     context.name_instance("go".to_string(), instance);
     println!(
         "Go module instantiation time: {:?}",
@@ -916,6 +1362,16 @@ pub fn run(args: Vec<String>, compiler: Compiler, data: Vec<u8>) -> Result<(), S
         }
     }
     println!("Invocation time: {:?}", invoke_timer.elapsed().unwrap());
+    let instance = context.get_instance(&"go").unwrap();
+    let shared_state = instance
+        .host_state()
+        .downcast_mut::<SharedState>()
+        .expect("host state is not a SharedState");
+    // a run whose audit log is missing lines fails
+    shared_state
+        .net_loop
+        .check_audit_log()
+        .map_err(|e| e.to_string())?;
 
     Ok(())
 }
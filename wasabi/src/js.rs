@@ -3,6 +3,7 @@ use failure::{err_msg, Error};
 use slab::Slab;
 use std::collections::HashMap;
 use std::i32;
+use std::io::SeekFrom;
 use std::os::unix::fs::MetadataExt;
 
 #[derive(Debug)]
@@ -34,6 +35,11 @@ pub struct Js {
     pub static_strings: HashMap<&'static str, &'static str>,
     pub error_not_found: i64,
     pub error_exists: i64,
+    pub error_again: i64,
+    pub error_io: i64,
+    pub error_timed_out: i64,
+    // refs below this are created with the runtime and are never removed
+    static_refs: i64,
     pub true_value: i64,
     pub false_value: i64,
     pub null: i64,
@@ -77,6 +83,16 @@ pub fn int_from_value(val: (i64, bool)) -> i64 {
     }
 }
 
+/// Converts the position argument of fs.read and fs.write. A null position
+/// means the file's current offset is used.
+pub fn seek_from_value(val: (i64, bool)) -> SeekFrom {
+    if val == (2, true) {
+        SeekFrom::Current(0)
+    } else {
+        SeekFrom::Start(int_from_value(val) as u64)
+    }
+}
+
 impl Js {
     pub fn new() -> Result<Self, Error> {
         let mut js = Self {
@@ -84,6 +100,10 @@ impl Js {
             static_strings: HashMap::new(),
             error_not_found: 2,
             error_exists: 2,
+            error_again: 2,
+            error_io: 2,
+            error_timed_out: 2,
+            static_refs: 0,
             true_value: 2,
             false_value: 2,
             null: 2,
@@ -120,6 +140,8 @@ impl Js {
         js.add_object(fs, "read")?;
         js.add_object(fs, "mkdir")?;
         js.add_object(fs, "fsync")?;
+        js.add_object(fs, "close")?;
+        js.add_object(fs, "flock")?;
 
         js.add_object(fs, "isDirectory")?;
 
@@ -162,22 +184,59 @@ impl Js {
 
         let wsbi = js.add_object(global, "wasabi")?;
         js.add_object(wsbi, "lookup_ip")?;
+        js.add_object(wsbi, "lookup")?;
+        js.add_object(wsbi, "watch")?;
+        js.add_object(wsbi, "unwatch")?;
 
         let date = js.add_object(global, "Date")?;
         // this would be a function on a new Date() but we'll just make it a
         // function on the global object to avoid allocating an item
         js.add_object(date, "getTimezoneOffset")?;
 
+        // enough of fetch for net/http's js/wasm transport
+        // https://github.com/golang/go/blob/release-branch.go1.12/src/net/http/roundtrip_js.go
+        js.add_object(global, "fetch")?;
+        js.add_object(global, "Headers")?;
+        js.add_object(global, "AbortController")?;
+        for key in &[
+            "method",
+            "credentials",
+            "mode",
+            "signal",
+            "headers",
+            "body",
+            "append",
+            "entries",
+            "next",
+            "done",
+            "value",
+            "status",
+            "getReader",
+            "read",
+            "cancel",
+            "byteLength",
+            "set",
+            "then",
+            "abort",
+            "message",
+        ] {
+            js.static_strings.insert(*key, *key);
+        }
+
         // https://github.com/golang/go/blob/master/src/syscall/tables_nacljs.go#L367
         let enoent = js.add_io_error("ENOENT")?;
         js.error_not_found = enoent;
         let eexist = js.add_io_error("EEXIST")?;
         js.error_exists = eexist;
+        js.error_again = js.add_io_error("EAGAIN")?;
+        js.error_io = js.add_io_error("EIO")?;
+        js.error_timed_out = js.add_io_error("ETIMEDOUT")?;
 
         js.global = global;
 
         js.static_strings.insert("is_directory", "is_directory");
 
+        js.static_refs = js.slab.len() as i64;
         Ok(js)
     }
     fn add_io_error(&mut self, name: &'static str) -> Result<i64, Error> {
@@ -193,7 +252,7 @@ impl Js {
         self.slab.insert(v) as i64
     }
     pub fn slab_remove(&mut self, r: i64) {
-        if r < self.error_exists {
+        if r < self.static_refs {
             return;
         }
         let ru = r as usize;
@@ -264,6 +323,31 @@ impl Js {
         }
         Err(err_msg("ref doesn't exist"))
     }
+    pub fn get_string(&self, r: i64) -> Option<&str> {
+        if let Value::String(s) = self.slab_get(r)? {
+            return Some(s);
+        }
+        None
+    }
+    /// The value as js's String() would convert it, for the values that Go
+    /// asks for as strings
+    pub fn value_string(&self, val: (i64, bool)) -> Option<String> {
+        if !val.1 {
+            return Some(val.0.to_string());
+        }
+        match self.slab_get(val.0)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Int(i) => Some(i.to_string()),
+            Value::Object {
+                name: "TypeError",
+                values,
+            } => Some(format!(
+                "TypeError: {}",
+                self.get_string(values.get("message")?.0)?
+            )),
+            _ => None,
+        }
+    }
     pub fn value_length(&self, target: i64) -> Option<(i64)> {
         if let Value::Array(rs) = self.slab_get(target)? {
             return Some(rs.len() as i64);
@@ -303,6 +387,23 @@ impl Js {
                 true,
             )),
             "Date" => Some((target, true)),
+            "Object" => Some((
+                self.slab_add(Value::Object {
+                    name: "Object",
+                    values: HashMap::new(),
+                }),
+                true,
+            )),
+            "Headers" => Some((self.add_headers(Vec::new()).unwrap(), true)),
+            "AbortController" => {
+                let ac = self.slab_add(Value::Object {
+                    name: "AbortController",
+                    values: HashMap::new(),
+                });
+                self.add_object(ac, "signal").unwrap();
+                self.add_object(ac, "abort").unwrap();
+                Some((ac, true))
+            }
             "net_listener" => {
                 let nl = self.slab_add(Value::Object {
                     name: "net_listener",
@@ -328,6 +429,184 @@ impl Js {
         };
         Err(err_msg("reflect_set target or property_key doesn't exist"))
     }
+    /// A Headers object, the pairs are kept in its "list"
+    pub fn add_headers(&mut self, pairs: Vec<(String, String)>) -> Result<i64, Error> {
+        let headers = self.slab_add(Value::Object {
+            name: "Headers",
+            values: HashMap::new(),
+        });
+        self.add_object(headers, "append")?;
+        self.add_object(headers, "entries")?;
+        self.add_array(headers, "list", Vec::new())?;
+        for (name, value) in pairs {
+            self.append_header(headers, name, value)?;
+        }
+        Ok(headers)
+    }
+    pub fn append_header(
+        &mut self,
+        headers: i64,
+        name: String,
+        value: String,
+    ) -> Result<(), Error> {
+        let name = self.slab_add(Value::String(name));
+        let value = self.slab_add(Value::String(value));
+        let pair = self.slab_add(Value::Array(vec![(name, true), (value, true)]));
+        let list = self
+            .reflect_get(headers, "list")
+            .ok_or_else(|| err_msg("headers have no list"))?;
+        match self.slab_get_mut(list.0) {
+            Some(Value::Array(pairs)) => {
+                pairs.push((pair, true));
+                Ok(())
+            }
+            _ => Err(err_msg("headers list is not an array")),
+        }
+    }
+    pub fn header_pairs(&self, headers: i64) -> Vec<(String, String)> {
+        let pairs = match self
+            .reflect_get(headers, "list")
+            .and_then(|list| self.slab_get(list.0))
+        {
+            Some(Value::Array(pairs)) => pairs,
+            _ => return Vec::new(),
+        };
+        pairs
+            .iter()
+            .filter_map(|pair| match self.slab_get(pair.0) {
+                Some(Value::Array(pair)) if pair.len() == 2 => Some((
+                    self.get_string(pair[0].0)?.to_string(),
+                    self.get_string(pair[1].0)?.to_string(),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+    /// An iterator over copies of the pairs, so it can hand them out without
+    /// sharing refs with the Headers
+    pub fn add_headers_iterator(&mut self, headers: i64) -> Result<i64, Error> {
+        let mut pairs = Vec::new();
+        for (name, value) in self.header_pairs(headers) {
+            let name = self.slab_add(Value::String(name));
+            let value = self.slab_add(Value::String(value));
+            pairs.push((
+                self.slab_add(Value::Array(vec![(name, true), (value, true)])),
+                true,
+            ));
+        }
+        let it = self.slab_add(Value::Object {
+            name: "HeadersIterator",
+            values: HashMap::new(),
+        });
+        self.add_object(it, "next")?;
+        self.add_array(it, "list", pairs)?;
+        Ok(it)
+    }
+    /// The next {done, value} of a headers iterator
+    pub fn headers_iterator_next(&mut self, it: i64) -> Result<i64, Error> {
+        let list = self
+            .reflect_get(it, "list")
+            .ok_or_else(|| err_msg("iterator has no list"))?;
+        let pair = match self.slab_get_mut(list.0) {
+            Some(Value::Array(pairs)) if !pairs.is_empty() => Some(pairs.remove(0)),
+            _ => None,
+        };
+        let result = self.slab_add(Value::Object {
+            name: "IteratorResult",
+            values: HashMap::new(),
+        });
+        match pair {
+            Some(pair) => {
+                let done = self.false_value;
+                self.add_object_value(result, "done", (done, true))?;
+                self.add_object_value(result, "value", pair)?;
+            }
+            None => {
+                let done = self.true_value;
+                self.add_object_value(result, "done", (done, true))?;
+            }
+        }
+        Ok(result)
+    }
+    /// A promise that is settled by SharedState::settle_promise
+    pub fn add_promise(&mut self) -> Result<i64, Error> {
+        let promise = self.slab_add(Value::Object {
+            name: "Promise",
+            values: HashMap::new(),
+        });
+        self.add_object(promise, "then")?;
+        Ok(promise)
+    }
+    /// The Response a fetch resolves to. Its body is read through a reader
+    /// holding the IOLoop's body id.
+    pub fn add_fetch_response(
+        &mut self,
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: usize,
+    ) -> Result<i64, Error> {
+        let response = self.slab_add(Value::Object {
+            name: "Response",
+            values: HashMap::new(),
+        });
+        self.add_object_value(response, "status", (i64::from(status), false))?;
+        let headers = self.add_headers(headers)?;
+        self.add_object_value(response, "headers", (headers, true))?;
+        let stream = self.slab_add(Value::Object {
+            name: "ReadableStream",
+            values: HashMap::new(),
+        });
+        self.add_object(stream, "getReader")?;
+        self.add_object_value(stream, "id", (body as i64, false))?;
+        self.add_object_value(response, "body", (stream, true))?;
+        Ok(response)
+    }
+    pub fn add_reader(&mut self, stream: i64) -> Result<i64, Error> {
+        let body = self
+            .reflect_get(stream, "id")
+            .ok_or_else(|| err_msg("stream has no body"))?;
+        let reader = self.slab_add(Value::Object {
+            name: "reader",
+            values: HashMap::new(),
+        });
+        self.add_object(reader, "read")?;
+        self.add_object(reader, "cancel")?;
+        self.add_object_value(reader, "id", body)?;
+        Ok(reader)
+    }
+    /// The {done, value} a reader's read resolves to. The value only needs
+    /// its byteLength and to be the source of a Uint8Array's set.
+    pub fn add_read_result(&mut self, chunk: Option<Vec<u8>>) -> Result<i64, Error> {
+        let result = self.slab_add(Value::Object {
+            name: "ReadResult",
+            values: HashMap::new(),
+        });
+        match chunk {
+            Some(chunk) => {
+                let done = self.false_value;
+                self.add_object_value(result, "done", (done, true))?;
+                let value = self.add_object(result, "value")?;
+                self.add_object_value(value, "byteLength", (chunk.len() as i64, false))?;
+                let bytes = self.slab_add(Value::Bytes(chunk));
+                self.add_object_value(value, "bytes", (bytes, true))?;
+            }
+            None => {
+                let done = self.true_value;
+                self.add_object_value(result, "done", (done, true))?;
+            }
+        }
+        Ok(result)
+    }
+    /// What fetch and reads reject with
+    pub fn add_type_error(&mut self, msg: String) -> Result<i64, Error> {
+        let err = self.slab_add(Value::Object {
+            name: "TypeError",
+            values: HashMap::new(),
+        });
+        let msg = self.slab_add(Value::String(msg));
+        self.add_object_value(err, "message", (msg, true))?;
+        Ok(err)
+    }
     pub fn add_metadata(&mut self, md: std::fs::Metadata) -> Result<i64, Error> {
         let is_dir = if md.is_dir() {
             self.true_value
@@ -374,6 +653,16 @@ mod tests {
         assert_eq!((-2147483639, true), load_value(&store_value((big, true))));
     }
 
+    #[test]
+    fn seek_from_large_position() {
+        assert_eq!(seek_from_value((2, true)), SeekFrom::Current(0));
+        assert_eq!(seek_from_value((1, true)), SeekFrom::Start(0));
+        // offsets past 4GB survive the trip through a js number
+        let position = 5 * 1024 * 1024 * 1024;
+        let value = load_value(&store_value((position, false)));
+        assert_eq!(seek_from_value(value), SeekFrom::Start(position as u64));
+    }
+
     #[test]
     fn slab_get() {
         let j = Js::new().unwrap();
@@ -397,5 +686,37 @@ mod tests {
         assert_eq!(2, j.reflect_get(7, "_pendingEvent").unwrap().0);
         // println!("{:?}", MAX / 2);
     }
+    #[test]
+    fn value_string() {
+        let mut j = Js::new().unwrap();
+        assert_eq!(j.value_string((200, false)).unwrap(), "200");
+        assert_eq!(j.value_string((1, true)).unwrap(), "0");
+        let err = j.add_type_error("failed".to_string()).unwrap();
+        assert_eq!(j.value_string((err, true)).unwrap(), "TypeError: failed");
+        assert!(j.value_string((j.global, true)).is_none());
+    }
+    #[test]
+    fn headers_iterator() {
+        let mut j = Js::new().unwrap();
+        let headers = j
+            .add_headers(vec![("a".to_string(), "1".to_string())])
+            .unwrap();
+        j.append_header(headers, "b".to_string(), "2".to_string())
+            .unwrap();
+        let it = j.add_headers_iterator(headers).unwrap();
+        for (name, value) in &[("a", "1"), ("b", "2")] {
+            let next = j.headers_iterator_next(it).unwrap();
+            assert_eq!(j.reflect_get(next, "done").unwrap().0, j.false_value);
+            let pair = j.reflect_get(next, "value").unwrap().0;
+            let name_ref = j.reflect_get_index(pair, 0).unwrap().0;
+            let value_ref = j.reflect_get_index(pair, 1).unwrap().0;
+            assert_eq!(j.get_string(name_ref).unwrap(), *name);
+            assert_eq!(j.get_string(value_ref).unwrap(), *value);
+        }
+        let next = j.headers_iterator_next(it).unwrap();
+        assert_eq!(j.reflect_get(next, "done").unwrap().0, j.true_value);
+        // the iterator handed out copies
+        assert_eq!(j.header_pairs(headers).len(), 2);
+    }
 
 }
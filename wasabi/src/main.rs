@@ -32,6 +32,7 @@ extern crate cranelift_native;
 extern crate cranelift_wasm;
 extern crate failure;
 extern crate file_per_thread_logger;
+extern crate libc;
 extern crate rand;
 extern crate slab;
 extern crate target_lexicon;
@@ -42,13 +43,16 @@ extern crate wasmtime_jit;
 extern crate wasmtime_runtime;
 
 mod bytes;
+mod config;
 mod go;
 mod js;
 mod mem;
 mod shared_state;
 mod timeout_heap;
+mod upgrade;
 mod util;
 
+use config::Config;
 use cranelift_codegen::settings;
 use cranelift_codegen::settings::Configurable;
 use std::env::args;
@@ -86,7 +90,13 @@ fn main() {
 
     let isa = isa_builder.finish(settings::Flags::new(flag_builder));
     let compiler = Compiler::new(isa);
-    let args: Vec<String> = args().collect();
+    let (config, args) = match Config::from_args(args().collect()) {
+        Ok(v) => v,
+        Err(message) => {
+            println!("{}", message);
+            exit(1);
+        }
+    };
     if args.len() < 2 {
         println!("Runtime expects a wasm binary or wat file as the first argument");
         exit(1);
@@ -94,7 +104,7 @@ fn main() {
     let filename = args[1].clone();
 
     let path = Path::new(&filename);
-    match handle_module(args, compiler, path) {
+    match handle_module(args, config, compiler, path) {
         Ok(()) => {}
         Err(message) => {
             let name = path.as_os_str().to_string_lossy();
@@ -104,12 +114,17 @@ fn main() {
     }
 }
 
-fn handle_module(args: Vec<String>, compiler: Compiler, path: &Path) -> Result<(), String> {
+fn handle_module(
+    args: Vec<String>,
+    config: Config,
+    compiler: Compiler,
+    path: &Path,
+) -> Result<(), String> {
     let mut data =
         read_to_end(path.to_path_buf()).map_err(|err| String::from(err.description()))?;
     // if data is using wat-format, first convert data to wasm
     if !data.starts_with(&[b'\0', b'a', b's', b'm']) {
         data = wabt::wat2wasm(data).map_err(|err| String::from(err.description()))?;
     }
-    go::run(args, compiler, data)
+    go::run(args, config, compiler, data)
 }
@@ -51,10 +51,8 @@ impl SharedState {
         }
         self.store_value_bytes(byte_references)
     }
-    pub fn set_byte_array_array(&mut self, addr: i32, values: Vec<Vec<u8>>) {
-        let reference = self._set_byte_array_array(values);
-        self.mem.set_i32(addr, reference);
-    }
+    /// Stores a (int32, bool) result. Only for values like network ids that
+    /// are known to fit in 32 bits, use set_u64_result for sizes and offsets.
     pub fn set_usize_result(&mut self, addr: i32, result: Result<usize, Error>) {
         match result {
             Ok(value) => {
@@ -67,6 +65,21 @@ impl SharedState {
             }
         }
     }
+    /// Stores a (int64, bool) result. On error the int64 holds the reference
+    /// to the error string.
+    pub fn set_u64_result(&mut self, addr: i32, result: Result<u64, Error>) {
+        match result {
+            Ok(value) => {
+                self.mem.set_i64(addr, value as i64);
+                self.mem.set_bool(addr + 8, true);
+            }
+            Err(err) => {
+                let reference = self.store_value_bytes(err.to_string().into_bytes());
+                self.mem.set_i64(addr, i64::from(reference));
+                self.mem.set_bool(addr + 8, false);
+            }
+        }
+    }
     pub fn set_error(&mut self, addr: i32, err: &Error) {
         self.store_string(addr, err.to_string())
     }
@@ -117,7 +130,7 @@ impl SharedState {
             Some(events)
         }
     }
-    fn add_pending_event(&mut self, id: i64, args: Vec<(i64, bool)>) {
+    pub fn add_pending_event(&mut self, id: i64, args: Vec<(i64, bool)>) {
         // TODO: return result
         let pe = self.js.slab_add(js::Value::Object {
             name: "pending_event",
@@ -129,6 +142,57 @@ impl SharedState {
         self.js.add_array(pe, "args", args).unwrap();
         self.call_queue.push_back(pe);
     }
+    /// Registers the ids of the Go funcs to call once promise settles, or
+    /// calls one right away if it already has
+    pub fn then_promise(&mut self, promise: i64, on_fulfilled: i64, on_rejected: i64) {
+        if let Some(value) = self.js.reflect_get(promise, "fulfilled") {
+            self.add_pending_event(on_fulfilled, vec![value]);
+        } else if let Some(reason) = self.js.reflect_get(promise, "rejected") {
+            self.add_pending_event(on_rejected, vec![reason]);
+        } else {
+            let js = &mut self.js;
+            js.add_object_value(promise, "onFulfilled", (on_fulfilled, false))
+                .unwrap();
+            js.add_object_value(promise, "onRejected", (on_rejected, false))
+                .unwrap();
+        }
+    }
+    fn settle_promise(&mut self, promise: i64, result: Result<i64, i64>) {
+        let (callback, state, value) = match result {
+            Ok(value) => ("onFulfilled", "fulfilled", value),
+            Err(reason) => ("onRejected", "rejected", reason),
+        };
+        match self.js.reflect_get(promise, callback) {
+            Some((id, _)) => self.add_pending_event(id, vec![(value, true)]),
+            None => self
+                .js
+                .add_object_value(promise, state, (value, true))
+                .unwrap(),
+        }
+    }
+    fn fetch_response(&mut self, fetch: wasabi_io::FetchResponse) {
+        let (promise, result) = match fetch {
+            wasabi_io::FetchResponse::Head {
+                id,
+                status,
+                headers,
+                body,
+            } => {
+                let response = self.js.add_fetch_response(status, headers, body);
+                (id, Ok(response.unwrap()))
+            }
+            wasabi_io::FetchResponse::Chunk { id, chunk } => {
+                (id, Ok(self.js.add_read_result(chunk).unwrap()))
+            }
+            wasabi_io::FetchResponse::Failed { id, msg } => {
+                (id, Err(self.js.add_type_error(msg).unwrap()))
+            }
+            // Go only aborts once it has stopped waiting, and its funcs have
+            // been released
+            wasabi_io::FetchResponse::Aborted { .. } => return,
+        };
+        self.settle_promise(promise, result);
+    }
     pub fn process_event_loop(&mut self) -> Result<bool, Error> {
         // Stop execution if we've exited
         if self.exited {
@@ -139,6 +203,11 @@ impl SharedState {
             if let Some(events) = self.recv_net_events() {
                 let mut network_cb_args = Vec::new();
                 for event in events {
+                    // these ids are promises, not callbacks
+                    if let wasabi_io::Response::Fetch(fetch) = event {
+                        self.fetch_response(fetch);
+                        continue;
+                    }
                     if event.id().is_some() {
                         self.js
                             .add_object_value(event.id().unwrap(), "result", (2, true))
@@ -146,6 +215,17 @@ impl SharedState {
                         self.call_queue.push_back(event.id().unwrap());
                     }
                     match event {
+                        wasabi_io::Response::Event(ref event)
+                            if self.net_loop.is_watch_event(event) =>
+                        {
+                            for change in self.net_loop.watch_events()? {
+                                let path = self.js.slab_add(js::Value::String(change.path));
+                                self.add_pending_event(
+                                    change.callback,
+                                    vec![(change.kind as i64, false), (path, true)],
+                                );
+                            }
+                        }
                         wasabi_io::Response::Event(event) => {
                             let ints = wasabi_io::event_to_ints(&event);
                             network_cb_args.push((ints.0, false));
@@ -159,12 +239,12 @@ impl SharedState {
                                 .add_array(id, "args", vec![(2, true), (len as i64, false)])
                                 .unwrap();
                         }
-                        wasabi_io::Response::Ips { id, ips } => {
+                        wasabi_io::Response::Ips { id, ips, .. } => {
                             let mut byte_ips: Vec<Vec<u8>> = Vec::new();
                             for ip in ips.iter() {
                                 match ip {
                                     IpAddr::V4(ip4) => byte_ips.push(ip4.octets().to_vec()),
-                                    IpAddr::V6(_) => {} //no IPV6 support
+                                    IpAddr::V6(ip6) => byte_ips.push(ip6.octets().to_vec()),
                                 }
                             }
                             let reference = self._set_byte_array_array(byte_ips);
@@ -176,15 +256,71 @@ impl SharedState {
                                 )
                                 .unwrap();
                         }
+                        wasabi_io::Response::Names { id, names } => {
+                            let names = names.into_iter().map(String::into_bytes).collect();
+                            let reference = self._set_byte_array_array(names);
+                            self.js
+                                .add_array(
+                                    id,
+                                    "args",
+                                    vec![(2, true), (i64::from(reference), false)],
+                                )
+                                .unwrap();
+                        }
+                        wasabi_io::Response::Mx { id, mx } => {
+                            // preference as big endian followed by the host
+                            let records = mx
+                                .into_iter()
+                                .map(|r| {
+                                    let mut b = r.pref.to_be_bytes().to_vec();
+                                    b.extend(r.host.into_bytes());
+                                    b
+                                })
+                                .collect();
+                            let reference = self._set_byte_array_array(records);
+                            self.js
+                                .add_array(
+                                    id,
+                                    "args",
+                                    vec![(2, true), (i64::from(reference), false)],
+                                )
+                                .unwrap();
+                        }
+                        wasabi_io::Response::Srv { id, srv } => {
+                            // priority, weight and port as big endian
+                            // followed by the target
+                            let records = srv
+                                .into_iter()
+                                .map(|r| {
+                                    let mut b = Vec::new();
+                                    b.extend_from_slice(&r.priority.to_be_bytes());
+                                    b.extend_from_slice(&r.weight.to_be_bytes());
+                                    b.extend_from_slice(&r.port.to_be_bytes());
+                                    b.extend(r.target.into_bytes());
+                                    b
+                                })
+                                .collect();
+                            let reference = self._set_byte_array_array(records);
+                            self.js
+                                .add_array(
+                                    id,
+                                    "args",
+                                    vec![(2, true), (i64::from(reference), false)],
+                                )
+                                .unwrap();
+                        }
                         wasabi_io::Response::Read {
                             buf,
                             id,
                             len,
                             address,
                         } => {
+                            // copied now rather than by the read itself, as
+                            // the memory may have grown and moved since
                             self.mem
                                 .mut_mem_slice(address, address + len)
-                                .clone_from_slice(&buf[..len]);
+                                .copy_from_slice(&buf[..len]);
+                            self.net_loop.recycle(buf);
                             self.js
                                 .add_array(
                                     id,
@@ -199,6 +335,11 @@ impl SharedState {
                                 .add_array(id, "args", vec![(2, true), (fstat, true)])
                                 .unwrap();
                         }
+                        wasabi_io::Response::Watch { id, watch } => {
+                            self.js
+                                .add_array(id, "args", vec![(2, true), ((watch as i64), false)])
+                                .unwrap();
+                        }
                         wasabi_io::Response::FileRef { id, fd } => {
                             self.js
                                 .add_array(id, "args", vec![(2, true), ((fd as i64), false)])
@@ -213,8 +354,21 @@ impl SharedState {
                                 let enoent = self.js.error_not_found;
                                 self.js.add_array(id, "args", vec![(enoent, true)]).unwrap();
                             }
+                            std::io::ErrorKind::WouldBlock => {
+                                let eagain = self.js.error_again;
+                                self.js.add_array(id, "args", vec![(eagain, true)]).unwrap();
+                            }
+                            std::io::ErrorKind::TimedOut => {
+                                let etimedout = self.js.error_timed_out;
+                                self.js
+                                    .add_array(id, "args", vec![(etimedout, true)])
+                                    .unwrap();
+                            }
                             _ => {
-                                println!("unhandled error type {:?}", kind);
+                                // the callback still has to be called or the
+                                // goroutine waiting on it will never wake up
+                                let eio = self.js.error_io;
+                                self.js.add_array(id, "args", vec![(eio, true)]).unwrap();
                             }
                         },
                         _ => {
@@ -293,6 +447,27 @@ mod tests {
         assert_eq!(should_break, true);
     }
 
+    #[test]
+    fn promise_callbacks() {
+        let mut ss = SharedState::new();
+
+        // settled after then
+        let promise = ss.js.add_promise().unwrap();
+        ss.then_promise(promise, 10, 11);
+        ss.settle_promise(promise, Ok(2));
+        // settled before then
+        let promise = ss.js.add_promise().unwrap();
+        ss.settle_promise(promise, Err(2));
+        ss.then_promise(promise, 12, 13);
+
+        let ids: Vec<i64> = ss
+            .call_queue
+            .iter()
+            .map(|pe| ss.js.reflect_get(*pe, "id").unwrap().0)
+            .collect();
+        assert_eq!(ids, vec![10, 13]);
+    }
+
     // #[test]
     // fn test_event_loop_timeouts() {
     //     let mut ss = SharedState::new();
@@ -0,0 +1,92 @@
+//! Binary upgrades
+//!
+//! On SIGUSR2 wasabi starts the wasabi binary again with the same arguments
+//! and passes it the program's listeners with `--listen-fd`. The new process
+//! hands each one to its program when it listens on the same address, so
+//! connections are accepted by both until the old process is stopped.
+
+use libc;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use wasabi_io::ListenerSet;
+
+// the write end of the pipe that wakes the upgrade thread
+static SIGNALED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    let fd = SIGNALED.load(Ordering::Relaxed) as RawFd;
+    // write is safe in a signal handler, and if the pipe is full an upgrade
+    // is already pending
+    unsafe { libc::write(fd, b"u".as_ptr() as *const libc::c_void, 1) };
+}
+
+/// Upgrade whenever the process gets SIGUSR2
+pub fn on_sigusr2(listeners: ListenerSet) -> Result<(), io::Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) };
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNALED.store(fds[1] as usize, Ordering::Relaxed);
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGUSR2, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    thread::Builder::new()
+        .name("wasabi-upgrade".to_string())
+        .spawn(move || {
+            let mut buf = [0; 1];
+            while let Ok(1) = signals.read(&mut buf) {
+                match upgrade(&listeners) {
+                    Ok(child) => {
+                        eprintln!("wasabi: started process {} to take over", child.id());
+                        reap(child);
+                    }
+                    Err(err) => eprintln!("wasabi: upgrade failed: {}", err),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+// waits for the child on a thread of its own, so that it doesn't linger as a
+// zombie if it exits while this process still runs
+fn reap(mut child: Child) {
+    let spawned = thread::Builder::new()
+        .name("wasabi-reap".to_string())
+        .spawn(move || match child.wait() {
+            Ok(status) => eprintln!("wasabi: process {} exited: {}", child.id(), status),
+            Err(err) => eprintln!("wasabi: waiting for process {}: {}", child.id(), err),
+        });
+    if let Err(err) = spawned {
+        eprintln!("wasabi: can't wait for the new process: {}", err);
+    }
+}
+
+fn upgrade(listeners: &ListenerSet) -> Result<Child, io::Error> {
+    // closed in this process when they're dropped at the end
+    let listeners = listeners.duplicate()?;
+    let mut command = Command::new(env::current_exe()?);
+    let mut args = env::args().skip(1).peekable();
+    // the listeners this process was started with are replaced by the ones
+    // the program has open now
+    while args.peek().map_or(false, |arg| arg.starts_with("--")) {
+        let arg = args.next().unwrap();
+        if !arg.starts_with("--listen-fd=") {
+            command.arg(arg);
+        }
+    }
+    for (name, listener) in &listeners {
+        let fd = listener.as_raw_fd();
+        // the new process inherits the fd
+        unsafe { libc::fcntl(fd, libc::F_SETFD, 0) };
+        command.arg(format!("--listen-fd={}={}", name, fd));
+    }
+    command.args(args).spawn()
+}